@@ -1,18 +1,48 @@
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use std::path::PathBuf;
 
+/// How to print command output
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum OutputFormat {
+    /// Table meant for humans
+    Text,
+    /// JSON meant for scripts
+    Json,
+}
+
+/// What to export a review as
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ExportFormat {
+    /// Email reply, ready for `sendmail -t`
+    Email,
+}
+
 #[derive(Subcommand, Debug)]
 pub(crate) enum Command {
     /// Get a pull request and begin a review
     Get {
         /// Ignore unsubmitted review checks
-        #[clap(short, long)]
+        #[clap(short, long, conflicts_with = "refresh")]
         force: bool,
-        /// Pull request to review (eg. `danobi/prr/24`)
+        /// Carry unsubmitted comments over to the refreshed review
+        #[clap(short, long)]
+        refresh: bool,
+        /// Pull request to review (eg. `danobi/prr/24`, `group/project/!24` or `gerrit:project~24`)
         pr: String,
         /// Open review file in $EDITOR after download
         #[clap(long)]
         open: bool,
+        /// Only review what changed since the last submitted review
+        #[clap(long)]
+        since_last_review: bool,
+        /// Review each commit of the pull request separately
+        #[clap(long, conflicts_with = "since_last_review")]
+        per_commit: bool,
+        /// Read a patch series from an mbox (or a directory of patches) instead of a forge
+        ///
+        /// `pr` then only names the review.
+        #[clap(long, value_name = "PATH", conflicts_with_all = ["since_last_review", "per_commit"])]
+        mbox: Option<PathBuf>,
     },
     /// Open an existing review in $EDITOR
     Edit {
@@ -25,16 +55,136 @@ pub(crate) enum Command {
         pr: String,
         #[clap(short, long)]
         debug: bool,
+        /// Leave the review pending, so it can be finished later
+        #[clap(long, conflicts_with = "finalize")]
+        pending: bool,
+        /// Submit a review that was left pending
+        #[clap(long)]
+        finalize: bool,
+        /// Post only what a failed submission did not
+        #[clap(long, conflicts_with_all = ["pending", "finalize"])]
+        resume: bool,
+    },
+    /// Post changes made to a review after it was submitted
+    ///
+    /// Edited comments are updated and removed comments are deleted.
+    Amend {
+        /// Pull request to amend the review of (eg. `danobi/prr/24`)
+        pr: String,
+        #[clap(short, long)]
+        debug: bool,
     },
     /// Apply a pull request to the working directory
     ///
     /// This can be useful for building/testing PRs
     Apply { pr: String },
+    /// Apply the changes suggested in a pull request's review threads
+    ///
+    /// Meant for the author of the pull request. Suggestions are applied to the
+    /// working directory, which must have the head of the pull request
+    /// available. Suggestions that no longer apply cleanly are reported.
+    ApplySuggestions { pr: String },
+    /// Export a review for sending it elsewhere
+    Export {
+        /// Pull request to export (eg. `danobi/prr/24`)
+        pr: String,
+        /// Format to print the review in
+        #[clap(short, long, value_enum, required_unless_present = "email")]
+        format: Option<ExportFormat>,
+        /// Print the review as an email reply, the same as `--format email`
+        #[clap(long, conflicts_with = "format")]
+        email: bool,
+    },
+    /// Import a review that was sent as an email reply
+    ///
+    /// The quoted text of the reply is matched against the review, so the
+    /// result can be submitted like any other review.
+    ImportEmail {
+        /// Pull request the reply reviews (eg. `danobi/prr/24`)
+        pr: String,
+        /// The reply, as a single email (eg. a `.eml` file)
+        email: PathBuf,
+        /// Overwrite unsubmitted changes to the review
+        #[clap(short, long)]
+        force: bool,
+    },
+    /// Import static analysis results as comments on a review
+    ///
+    /// Results are attached to the lines they are on in the new version of
+    /// each file. Check them with `prr edit` and keep the ones you agree with.
+    ImportSarif {
+        /// Pull request to add the results to (eg. `danobi/prr/24`)
+        pr: String,
+        /// The results, as a SARIF log
+        sarif: PathBuf,
+        /// List results that are not on lines in the diff in file-level
+        /// comments, instead of dropping them
+        #[clap(short, long)]
+        keep_outside: bool,
+    },
+    /// Add comments from a script to a review
+    ///
+    /// Reads a JSON list of comments from stdin, each with a `file`, `line`
+    /// (left out for file-level comments), `side` (`left` or `right`, defaults
+    /// to `right`), optional `start_line` and `body`. They are added to the
+    /// review file under the lines they are on, to be edited and submitted as
+    /// usual.
+    Compose {
+        /// Pull request to add the comments to (eg. `danobi/prr/24`)
+        pr: String,
+    },
     /// Print a status summary of all known reviews
     Status {
         /// Hide column titles from output
         #[clap(short, long)]
         no_titles: bool,
+        /// Fetch each pull request to show its state, author and title, and
+        /// whether it was updated since it was downloaded
+        #[clap(short, long)]
+        remote: bool,
+        /// Output format
+        #[clap(short, long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+    /// Parse a review file and check it for errors
+    Parse {
+        /// Pull request to parse the review file of (eg. `danobi/prr/24`)
+        pr: String,
+        /// Print the parsed comments as JSON
+        #[clap(long)]
+        json: bool,
+    },
+    /// Run a language server for review files, speaking LSP over stdio
+    ///
+    /// Editors get the errors `prr submit` would fail on as you type, the
+    /// line each quoted line of the diff maps to on hover and code actions
+    /// to approve or reject.
+    Lsp,
+    /// Download pull requests awaiting your review, all at once
+    ///
+    /// Review files you have not started on are refreshed if the pull request
+    /// changed. Review files with changes are left alone.
+    Sync {
+        /// Pull requests to download (eg. `danobi/prr/24`). Defaults to the
+        /// pull requests listed by `prr inbox`
+        prs: Vec<String>,
+    },
+    /// List open pull requests awaiting your review
+    ///
+    /// These are the pull requests you are a requested reviewer of, unless
+    /// `inbox_query` is configured.
+    Inbox {
+        /// Hide column titles from output
+        #[clap(short, long)]
+        no_titles: bool,
+    },
+    /// List the rounds of review on a pull request
+    ///
+    /// Each `prr get` after a review was submitted starts a new round. Earlier
+    /// rounds are kept next to the review file as `<pr>.<round>.prr`.
+    History {
+        /// Pull request to list the rounds of (eg. `danobi/prr/24`)
+        pr: String,
     },
     /// Remove a review
     Remove {
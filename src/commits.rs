@@ -0,0 +1,348 @@
+use std::fmt::Write as fmt_write;
+
+use crate::parser::{
+    diff_locations, parse_commit_start, CommitComment, FileComment, InlineComment, LineLocation,
+};
+
+/// A single commit of a pull request
+#[derive(Clone)]
+pub struct Commit {
+    /// Full commit hash
+    pub sha: String,
+    /// Author in `Name <email>` form
+    pub author: String,
+    /// Author date
+    pub date: String,
+    /// Full commit message
+    pub message: String,
+    /// Diff of the commit against its parent
+    pub diff: String,
+}
+
+/// A comment that has to be posted on a commit rather than the pull request
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CommitPost {
+    /// Commit to post the comment on
+    pub commit: String,
+    /// The user-supplied review comment
+    pub body: String,
+    /// File the comment is in, if any
+    pub path: Option<String>,
+    /// Line in the commit diff the comment is on, if any. This is the number
+    /// of lines below the first hunk start of the file, as GitHub expects.
+    pub position: Option<u64>,
+}
+
+/// Renders the commits of a pull request like `git log -p` does
+///
+/// Each commit gets its own section, starting with `commit <sha>` and its
+/// message, followed by its diff.
+pub fn render_commits(commits: &[Commit]) -> String {
+    let mut out = String::new();
+    for commit in commits {
+        writeln!(out, "commit {}", commit.sha).expect("Failed to write to string");
+        writeln!(out, "Author: {}", commit.author).expect("Failed to write to string");
+        writeln!(out, "Date:   {}", commit.date).expect("Failed to write to string");
+        out += "\n";
+        for line in commit.message.trim_end().lines() {
+            if line.is_empty() {
+                out += "\n";
+            } else {
+                writeln!(out, "    {line}").expect("Failed to write to string");
+            }
+        }
+        out += "\n";
+        out += &commit.diff;
+        if !out.ends_with('\n') {
+            out += "\n";
+        }
+    }
+
+    out
+}
+
+/// Returns the diff of commit `sha` in `original` (rendered by `render_commits()`)
+fn commit_diff(original: &str, sha: &str) -> String {
+    let mut in_commit = false;
+    let mut in_diff = false;
+    let mut diff = String::new();
+    for line in original.lines() {
+        if let Some(commit) = parse_commit_start(line) {
+            in_commit = commit == sha;
+            in_diff = false;
+            continue;
+        }
+
+        in_diff |= in_commit && line.starts_with("diff --git ");
+        if in_diff {
+            diff += line;
+            diff += "\n";
+        }
+    }
+
+    diff
+}
+
+/// Returns the text of the line at `file` and `line` in `diff`, including the
+/// leading `+`, `-` or ` `
+fn line_text<'a>(diff: &'a str, file: &str, line: &LineLocation) -> Option<&'a str> {
+    diff.lines()
+        .zip(diff_locations(diff))
+        .find(|(_, loc)| matches!(loc, Some((f, l)) if f == file && l == line))
+        .map(|(text, _)| text)
+}
+
+/// Returns where the line at `file` and `line` in `commit_diff` is in `pr_diff`
+///
+/// Lines are matched by content, ignoring whether the line is context or an
+/// addition: a line added in one commit is context in the next. The match must
+/// be unique, otherwise the line cannot be found on the pull request.
+fn pr_location(
+    commit_diff: &str,
+    pr_diff: &str,
+    file: &str,
+    line: &LineLocation,
+) -> Option<LineLocation> {
+    let text = line_text(commit_diff, file, line)?;
+    let content = text.get(1..).unwrap_or("");
+
+    let mut candidates = pr_diff
+        .lines()
+        .zip(diff_locations(pr_diff))
+        .filter_map(|(pr_text, loc)| match loc {
+            Some((f, l)) if f == file => Some((pr_text, l)),
+            _ => None,
+        })
+        .filter(|(pr_text, l)| {
+            let same_side = matches!(
+                (line, l),
+                (LineLocation::Left(_), LineLocation::Left(_))
+                    | (LineLocation::Right(_), LineLocation::Right(_))
+            );
+            same_side && pr_text.get(1..).unwrap_or("") == content
+        })
+        .map(|(_, l)| l);
+
+    match (candidates.next(), candidates.next()) {
+        (Some(l), None) => Some(l),
+        _ => None,
+    }
+}
+
+/// Returns the position of the line at `file` and `line` in `commit_diff`
+///
+/// See `CommitPost::position` for the semantics.
+fn diff_position(commit_diff: &str, file: &str, line: &LineLocation) -> Option<u64> {
+    let mut position: Option<u64> = None;
+    for (text, loc) in commit_diff.lines().zip(diff_locations(commit_diff)) {
+        if text.starts_with("diff --git ") {
+            position = None;
+        } else if text.starts_with("@@ ") {
+            // The line right below the first hunk start is position 1
+            position = Some(position.map_or(0, |p| p + 1));
+        } else if let Some(p) = position.as_mut() {
+            *p += 1;
+        }
+
+        if matches!(loc, Some((f, l)) if f == file && &l == line) {
+            return position;
+        }
+    }
+
+    None
+}
+
+/// Moves comments from a per-commit review onto the pull request where possible
+///
+/// `original` is the per-commit review text and `pr_diff` the full pull request
+/// diff. Comments in `inline_comments` and `file_comments` are rewritten in
+/// place to point at the pull request. Comments that do not map onto the pull
+/// request, as well as all of `commit_comments`, are returned to be posted on
+/// their commits instead.
+pub fn map_comments(
+    original: &str,
+    pr_diff: &str,
+    inline_comments: &mut Vec<InlineComment>,
+    file_comments: &mut Vec<FileComment>,
+    commit_comments: Vec<CommitComment>,
+) -> Vec<CommitPost> {
+    let mut posts: Vec<CommitPost> = commit_comments
+        .into_iter()
+        .map(|c| CommitPost {
+            commit: c.commit,
+            body: c.comment,
+            path: None,
+            position: None,
+        })
+        .collect();
+
+    let mut mapped = Vec::with_capacity(inline_comments.len());
+    for mut c in inline_comments.drain(..) {
+        let commit = match c.commit.take() {
+            Some(commit) => commit,
+            None => {
+                mapped.push(c);
+                continue;
+            }
+        };
+
+        let diff = commit_diff(original, &commit);
+        let line = pr_location(&diff, pr_diff, &c.file, &c.line);
+        let start_line = c
+            .start_line
+            .as_ref()
+            .map(|start| pr_location(&diff, pr_diff, &c.file, start));
+        match (line, start_line) {
+            (Some(line), None) => mapped.push(InlineComment { line, ..c }),
+            (Some(line), Some(Some(start))) => mapped.push(InlineComment {
+                line,
+                start_line: Some(start),
+                ..c
+            }),
+            _ => posts.push(CommitPost {
+                position: diff_position(&diff, &c.file, &c.line),
+                commit,
+                body: c.comment,
+                path: Some(c.file),
+            }),
+        }
+    }
+    *inline_comments = mapped;
+
+    let mut mapped = Vec::with_capacity(file_comments.len());
+    for mut c in file_comments.drain(..) {
+        let commit = match c.commit.take() {
+            Some(commit) => commit,
+            None => {
+                mapped.push(c);
+                continue;
+            }
+        };
+
+        let in_pr = pr_diff
+            .lines()
+            .filter_map(parse_diff_file)
+            .any(|f| f == c.file);
+        if in_pr {
+            mapped.push(c);
+        } else {
+            posts.push(CommitPost {
+                commit,
+                body: c.comment,
+                path: Some(c.file),
+                position: None,
+            });
+        }
+    }
+    *file_comments = mapped;
+
+    posts
+}
+
+/// Returns the new filename if `line` is a diff header
+pub fn parse_diff_file(line: &str) -> Option<&str> {
+    line.strip_prefix("diff --git ")
+        .and_then(|l| l.rsplit_once(" b/"))
+        .map(|(_, f)| f.trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq as assert_eq_pretty;
+
+    fn commits() -> Vec<Commit> {
+        vec![
+            Commit {
+                sha: "1111111111111111111111111111111111111111".to_string(),
+                author: "Sun Tzu <sun@example.com>".to_string(),
+                date: "2024-01-01T00:00:00Z".to_string(),
+                message: "ch1: Expand on the second point\n\nIt was too terse.\n".to_string(),
+                diff: include_str!("../testdata/commits/first").to_string(),
+            },
+            Commit {
+                sha: "2222222222222222222222222222222222222222".to_string(),
+                author: "Sun Tzu <sun@example.com>".to_string(),
+                date: "2024-01-02T00:00:00Z".to_string(),
+                message: "ch1: Add a fourth point".to_string(),
+                diff: include_str!("../testdata/commits/second").to_string(),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_render_commits() {
+        let gold = include_str!("../testdata/commits/gold");
+        assert_eq_pretty!(render_commits(&commits()), gold);
+    }
+
+    #[test]
+    fn test_map_comments() {
+        let original = render_commits(&commits());
+        let pr_diff = include_str!("../testdata/commits/pr_diff");
+        let first = "1111111111111111111111111111111111111111";
+        let second = "2222222222222222222222222222222222222222";
+        let inline = |line, commit: &str| InlineComment {
+            file: "ch1.txt".to_string(),
+            line,
+            start_line: None,
+            comment: "comment".to_string(),
+            commit: Some(commit.to_string()),
+        };
+
+        let mut inline_comments = vec![
+            // Added in the first commit and still in the PR
+            inline(LineLocation::Right(3), first),
+            // Context in the second commit, but added in the PR
+            inline(LineLocation::Right(3), second),
+            // Removed again by the second commit, so not in the PR
+            inline(LineLocation::Right(4), first),
+        ];
+        let mut file_comments = vec![FileComment {
+            file: "ch1.txt".to_string(),
+            comment: "file comment".to_string(),
+            commit: Some(second.to_string()),
+        }];
+        let commit_comments = vec![CommitComment {
+            commit: first.to_string(),
+            comment: "Typo in the subject".to_string(),
+        }];
+
+        let posts = map_comments(
+            &original,
+            pr_diff,
+            &mut inline_comments,
+            &mut file_comments,
+            commit_comments,
+        );
+
+        assert_eq!(
+            inline_comments
+                .iter()
+                .map(|c| (&c.line, &c.commit))
+                .collect::<Vec<_>>(),
+            vec![
+                (&LineLocation::Right(3), &None),
+                (&LineLocation::Right(3), &None)
+            ]
+        );
+        assert_eq!(file_comments[0].commit, None);
+        assert_eq_pretty!(
+            posts,
+            vec![
+                CommitPost {
+                    commit: first.to_string(),
+                    body: "Typo in the subject".to_string(),
+                    path: None,
+                    position: None,
+                },
+                CommitPost {
+                    commit: first.to_string(),
+                    body: "comment".to_string(),
+                    path: Some("ch1.txt".to_string()),
+                    position: Some(5),
+                },
+            ]
+        );
+    }
+}
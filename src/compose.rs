@@ -0,0 +1,289 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter, Result as fmt_result};
+
+use anyhow::{bail, Context, Result};
+use serde_derive::Deserialize;
+
+use crate::parser::{diff_locations, is_thread_line, Comment, LineLocation, ReviewParser};
+
+/// Side of the diff a composed comment is on
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Side {
+    /// The old version of the file
+    Left,
+    /// The new version of the file
+    #[default]
+    Right,
+}
+
+/// A comment to add to a review file, as read by `prr compose`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ComposedComment {
+    /// File the comment is on. The new name if the file was moved
+    pub file: String,
+    /// Line the comment is on, in the version of the file given by `side`.
+    /// `None` for a file-level comment
+    pub line: Option<u64>,
+    #[serde(default)]
+    pub side: Side,
+    /// For a spanned comment, the first line of the span. On the same side as `line`
+    pub start_line: Option<u64>,
+    pub body: String,
+}
+
+impl ComposedComment {
+    fn location(&self, line: u64) -> LineLocation {
+        match self.side {
+            Side::Left => LineLocation::Left(line),
+            Side::Right => LineLocation::Right(line),
+        }
+    }
+
+    /// The first line of the span, if the comment spans more than one line
+    fn span_start(&self) -> Option<LineLocation> {
+        self.start_line
+            .filter(|start| self.line.is_some_and(|line| line != *start))
+            .map(|start| self.location(start))
+    }
+}
+
+impl Display for ComposedComment {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt_result {
+        let side = match self.side {
+            Side::Left => "left",
+            Side::Right => "right",
+        };
+        match (self.start_line, self.line) {
+            (_, None) => write!(f, "{}", self.file),
+            (Some(start), Some(line)) if start != line => {
+                write!(f, "{}:{}-{} ({side})", self.file, start, line)
+            }
+            (_, Some(line)) => write!(f, "{}:{} ({side})", self.file, line),
+        }
+    }
+}
+
+/// Whether `line` of a review file quotes the original text, as opposed to
+/// being a comment or part of a review thread
+fn is_quoted(line: &str) -> bool {
+    line.starts_with("> ") || line == ">"
+}
+
+/// Adds `comments` to `contents`, the snip-resolved contents of a review file
+///
+/// Each comment goes right under the line it is on, which is the reverse of
+/// what `ReviewParser` does. File-level comments go under the `diff --git`
+/// header of their file. A comment on a line that already has one is
+/// appended to it as a new paragraph. Fails if any comment cannot be placed,
+/// eg. because its line is not part of the diff. `per_commit` is whether the
+/// review is split into commits.
+pub fn insert_comments(
+    contents: &str,
+    comments: &[ComposedComment],
+    per_commit: bool,
+) -> Result<String> {
+    let lines: Vec<&str> = contents.lines().collect();
+    // Index into `lines` of each line of original text
+    let quoted: Vec<usize> = (0..lines.len()).filter(|&i| is_quoted(lines[i])).collect();
+    let text: Vec<&str> = quoted
+        .iter()
+        .map(|&i| lines[i].strip_prefix("> ").unwrap_or(""))
+        .collect();
+    let locations = diff_locations(&(text.join("\n") + "\n"));
+    let find_header = |comment: &ComposedComment| {
+        let header = locations
+            .iter()
+            .position(|l| l.as_ref().is_some_and(|(file, _)| *file == comment.file))
+            .and_then(|first| {
+                (0..first)
+                    .rev()
+                    .find(|&i| text[i].starts_with("diff --git "))
+            });
+        match header {
+            Some(idx) => Ok(quoted[idx]),
+            None => bail!("{} is not in the diff", comment),
+        }
+    };
+    let find = |comment: &ComposedComment, location: LineLocation| {
+        let idx = locations
+            .iter()
+            .position(|l| l.as_ref() == Some(&(comment.file.clone(), location.clone())));
+        match idx {
+            Some(idx) => Ok(quoted[idx]),
+            None => bail!("{} is not in the diff", comment),
+        }
+    };
+
+    // Both are keyed by the index of the line to insert before
+    let mut inserted: HashMap<usize, Vec<String>> = HashMap::new();
+    let mut span_starts: HashMap<usize, usize> = HashMap::new();
+    // Lines that already have a comment under them
+    let mut commented: HashSet<usize> = HashSet::new();
+    for comment in comments {
+        let body = comment.body.trim();
+        if body.is_empty() {
+            bail!("Comment on {} is empty", comment);
+        }
+
+        let line = match comment.line {
+            Some(line) => find(comment, comment.location(line))?,
+            None => find_header(comment)?,
+        };
+        if let Some(start) = comment.span_start() {
+            let start = find(comment, start)?;
+            if start > line {
+                bail!("Span of comment on {} ends before it starts", comment);
+            }
+            *span_starts.entry(start).or_default() += 1;
+        }
+
+        // Existing comments come right after the line, before any review threads.
+        // The same goes for file-level comments and the header.
+        let run_end = (line + 1..lines.len())
+            .find(|&i| is_quoted(lines[i]) || is_thread_line(lines[i]))
+            .unwrap_or(lines.len());
+        let existing = (line + 1..run_end)
+            .rev()
+            .find(|&i| !lines[i].trim().is_empty());
+        let at = existing.map(|i| i + 1).unwrap_or(line + 1);
+        let insertion = inserted.entry(at).or_default();
+        if existing.is_some() || !commented.insert(line) {
+            insertion.push(String::new());
+        }
+        insertion.extend(body.lines().map(|l| l.trim_end().to_string()));
+    }
+
+    let mut composed = String::with_capacity(contents.len());
+    for idx in 0..=lines.len() {
+        for line in inserted.get(&idx).into_iter().flatten() {
+            composed += line;
+            composed += "\n";
+        }
+        for _ in 0..span_starts.get(&idx).copied().unwrap_or(0) {
+            composed += "\n";
+        }
+        if let Some(line) = lines.get(idx) {
+            composed += line;
+            composed += "\n";
+        }
+    }
+
+    check_placed(&composed, comments, per_commit)?;
+
+    Ok(composed)
+}
+
+/// Checks that the parser finds each of `comments` in `contents`
+///
+/// Comments can end up merged with their neighbours, eg. when a span starts
+/// right under another comment.
+fn check_placed(contents: &str, comments: &[ComposedComment], per_commit: bool) -> Result<()> {
+    let mut parser = ReviewParser::new(per_commit);
+    let mut parsed = Vec::new();
+    for line in contents.lines() {
+        if let Some(c) = parser
+            .parse_line(line)
+            .context("Failed to place comments")?
+        {
+            parsed.push(c);
+        }
+    }
+    if let Some(c) = parser.finish().context("Failed to place comments")? {
+        parsed.push(c);
+    }
+
+    for comment in comments {
+        let body: Vec<&str> = comment.body.trim().lines().map(str::trim_end).collect();
+        let body = body.join("\n");
+        let placed = parsed.iter().any(|c| match (c, comment.line) {
+            (Comment::Inline(c), Some(line)) => {
+                c.file == comment.file
+                    && c.line == comment.location(line)
+                    // Comments appended to a spanned comment end up spanned as well
+                    && (comment.span_start().is_none() || c.start_line == comment.span_start())
+                    && c.comment.contains(&body)
+            }
+            (Comment::File(c), None) => c.file == comment.file && c.comment.contains(&body),
+            _ => false,
+        });
+        if !placed {
+            bail!("Failed to place comment on {}", comment);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn comment(line: u64, side: Side, start_line: Option<u64>, body: &str) -> ComposedComment {
+        ComposedComment {
+            file: "ch1.txt".to_string(),
+            line: Some(line),
+            side,
+            start_line,
+            body: body.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_insert_comments() {
+        let contents = include_str!("../testdata/review/threads/gold");
+        let comments: Vec<ComposedComment> = serde_json::from_str(
+            r#"[
+                {"file": "ch1.txt", "line": 3, "side": "left", "body": "Keep this\n"},
+                {"file": "ch1.txt", "line": 2, "start_line": 1, "body": "Span"},
+                {"file": "ch1.txt", "line": 4, "body": "Second\n\nparagraph"},
+                {"file": "ch1.txt", "line": 4, "body": "Third"},
+                {"file": "ch1.txt", "body": "Whole file"}
+            ]"#,
+        )
+        .unwrap();
+
+        let composed = insert_comments(contents, &comments, false).unwrap();
+        let expected = contents
+            .replace("ch1.txt b/ch1.txt\n", "ch1.txt b/ch1.txt\nWhole file\n")
+            .replace("> @@ -1,4 +1,4 @@\n", "> @@ -1,4 +1,4 @@\n\n")
+            .replace(
+                "> -2. It is a matter of life and death.\n",
+                "Span\n> -2. It is a matter of life and death.\nKeep this\n",
+            )
+            + "Second\n\nparagraph\n\nThird\n";
+        assert_eq!(composed, expected);
+    }
+
+    #[test]
+    fn test_insert_comments_fails() {
+        let contents = include_str!("../testdata/review/threads/gold");
+        // Not in the diff
+        assert!(insert_comments(contents, &[comment(9, Side::Right, None, "x")], false).is_err());
+        assert!(insert_comments(contents, &[comment(4, Side::Left, None, "x")], false).is_err());
+        // Backwards span
+        assert!(
+            insert_comments(contents, &[comment(1, Side::Right, Some(3), "x")], false).is_err()
+        );
+        assert!(insert_comments(contents, &[comment(1, Side::Right, None, " \n")], false).is_err());
+        let other_file = ComposedComment {
+            file: "ch2.txt".to_string(),
+            line: None,
+            side: Side::Right,
+            start_line: None,
+            body: "x".to_string(),
+        };
+        assert!(insert_comments(contents, &[other_file], false).is_err());
+        // Spans cannot contain other comments or review threads
+        let nested = [
+            comment(3, Side::Left, None, "x"),
+            comment(3, Side::Right, Some(1), "y"),
+        ];
+        assert!(insert_comments(contents, &nested, false).is_err());
+        assert!(
+            insert_comments(contents, &[comment(4, Side::Right, Some(3), "x")], false).is_err()
+        );
+    }
+}
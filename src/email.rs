@@ -0,0 +1,491 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as fmt_write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+
+use crate::commits::parse_diff_file;
+use crate::mbox::PatchEmail;
+use crate::parser::{diff_locations, parse_commit_start, ReviewAction};
+use crate::review::{resolve_quotes, LineType, ReviewComments, SNIP_VARIANTS};
+
+/// Number of quoted lines kept above each comment in a reply. This is in
+/// addition to the commented line(s).
+const REPLY_CONTEXT: usize = 3;
+
+/// What stands in for quoted lines trimmed from a reply. Same as a snip in a
+/// review file.
+const SNIP: &str = "[...]";
+
+/// Snips people write in emails, on top of the ones prr understands in review files
+const EMAIL_SNIP_VARIANTS: &[&str] = &["[snip]", "<snip>", "[ ... ]", "..."];
+
+/// Trailers with which a reviewer approves a patch
+const APPROVAL_TRAILERS: &[&str] = &["Reviewed-by:", "Acked-by:"];
+
+/// Headers of a reply that do not depend on what is replied to
+pub struct Envelope {
+    /// Sender in `Name <email>` form, if known
+    pub from: Option<String>,
+    /// Date in RFC 5322 format
+    pub date: String,
+    /// Message ID, including the angle brackets
+    pub message_id: String,
+}
+
+/// Formats `time` as an RFC 5322 date in UTC
+pub fn rfc5322_date(time: SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs();
+    let days = secs / 86400;
+    let rem = secs % 86400;
+
+    // Civil date from days since the epoch, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} +0000",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// Returns a new message ID for an email from `from`
+pub fn new_message_id(from: Option<&str>, time: SystemTime) -> String {
+    let domain = from
+        .and_then(|f| f.rsplit_once('@'))
+        .map(|(_, d)| d.trim_end_matches('>'))
+        .filter(|d| !d.is_empty())
+        .unwrap_or("localhost");
+    let nanos = time
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_nanos();
+
+    format!("<prr.{}.{}@{}>", nanos, std::process::id(), domain)
+}
+
+/// Returns the body of a reply: `original` quoted, with the comments inserted
+/// under the lines they are attached to
+///
+/// Only lines close to a comment are quoted, along with the commit, file and
+/// hunk headers they are in. Everything else is trimmed to a snip.
+pub fn quote_review(original: &str, comments: &ReviewComments) -> String {
+    let lines: Vec<&str> = original.lines().collect();
+    let locations = diff_locations(original);
+
+    // Commit each line is in, if the review is per-commit
+    let mut commit = None;
+    let commits: Vec<Option<String>> = lines
+        .iter()
+        .map(|l| {
+            if let Some(sha) = parse_commit_start(l) {
+                commit = Some(sha);
+            }
+            commit.clone()
+        })
+        .collect();
+
+    // Comments to insert after each line
+    let mut insertions: HashMap<usize, Vec<&str>> = HashMap::new();
+    // Lines the comments are attached to, not counting context
+    let mut anchors: Vec<(usize, usize)> = Vec::new();
+
+    for c in &comments.inline_comments {
+        let find = |line| {
+            (0..lines.len()).find(|&i| {
+                commits[i] == c.commit
+                    && matches!(&locations[i], Some((f, l)) if *f == c.file && l == line)
+            })
+        };
+        if let Some(end) = find(&c.line) {
+            let start = c.start_line.as_ref().and_then(find).unwrap_or(end);
+            insertions.entry(end).or_default().push(&c.comment);
+            anchors.push((start, end));
+        }
+    }
+
+    for c in &comments.file_comments {
+        let header = (0..lines.len())
+            .find(|&i| commits[i] == c.commit && parse_diff_file(lines[i]) == Some(&c.file));
+        if let Some(i) = header {
+            insertions.entry(i).or_default().push(&c.comment);
+            anchors.push((i, i));
+        }
+    }
+
+    for c in &comments.commit_comments {
+        // Right below the commit message, ie. above the first file
+        let first_file = (0..lines.len()).find(|&i| {
+            commits[i].as_ref() == Some(&c.commit) && lines[i].starts_with("diff --git ")
+        });
+        let last = (0..lines.len())
+            .rev()
+            .find(|&i| commits[i].as_ref() == Some(&c.commit));
+        if let Some(i) = first_file.map(|i| i.saturating_sub(1)).or(last) {
+            insertions.entry(i).or_default().push(&c.comment);
+            anchors.push((i, i));
+        }
+    }
+
+    let mut keep: HashSet<usize> = HashSet::new();
+    for &(start, end) in &anchors {
+        keep.extend(start.saturating_sub(REPLY_CONTEXT)..=end);
+
+        // Headers of the commit, file and hunk the comment is in
+        let headers = (0..=start).rev().filter(|&i| commits[i] == commits[start]);
+        let mut want_hunk = true;
+        for i in headers {
+            let line = lines[i];
+            if want_hunk && line.starts_with("@@ ") {
+                keep.insert(i);
+                want_hunk = false;
+            } else if line.starts_with("diff --git ") {
+                keep.insert(i);
+                break;
+            }
+        }
+        if let Some(i) = (0..=start).find(|&i| parse_commit_start(lines[i]) == commits[start]) {
+            keep.insert(i);
+        }
+    }
+
+    let mut body = String::new();
+    let mut snipped = false;
+    for (i, line) in lines.iter().enumerate() {
+        if !keep.contains(&i) {
+            snipped = true;
+            continue;
+        }
+
+        if snipped {
+            writeln!(body, "{SNIP}").expect("Failed to write to string");
+            snipped = false;
+        }
+        if line.is_empty() {
+            body += ">\n";
+        } else {
+            writeln!(body, "> {line}").expect("Failed to write to string");
+        }
+
+        for comment in insertions.get(&i).into_iter().flatten() {
+            writeln!(body, "\n{}\n", comment.trim_end()).expect("Failed to write to string");
+        }
+    }
+    if snipped {
+        writeln!(body, "{SNIP}").expect("Failed to write to string");
+    }
+
+    body
+}
+
+/// Renders a review as a reply to the email `parent`, ready for `sendmail -t`
+///
+/// Without a `parent`, the reply is not threaded and its subject is based on
+/// `handle` instead. An approving review carries a `Reviewed-by` trailer.
+pub fn render_reply(
+    original: &str,
+    comments: &ReviewComments,
+    parent: Option<&PatchEmail>,
+    handle: &str,
+    envelope: &Envelope,
+) -> String {
+    let mut headers: Vec<(&str, String)> = Vec::new();
+    if let Some(from) = &envelope.from {
+        headers.push(("From", from.clone()));
+    }
+
+    let subject = match parent {
+        Some(p) => {
+            headers.push(("To", p.from.clone()));
+            // Everyone else on the thread, but not ourselves
+            let cc: Vec<&str> =
+                p.to.split(',')
+                    .chain(p.cc.split(','))
+                    .map(str::trim)
+                    .filter(|a| !a.is_empty() && Some(*a) != envelope.from.as_deref())
+                    .collect();
+            if !cc.is_empty() {
+                headers.push(("Cc", cc.join(", ")));
+            }
+            p.subject.clone()
+        }
+        None => handle.to_string(),
+    };
+    let subject = match subject.get(..3) {
+        Some(re) if re.eq_ignore_ascii_case("re:") => subject,
+        _ => format!("Re: {subject}"),
+    };
+    headers.push(("Subject", subject));
+    headers.push(("Date", envelope.date.clone()));
+    headers.push(("Message-Id", envelope.message_id.clone()));
+
+    if let Some(id) = parent.and_then(|p| p.message_id.as_ref()) {
+        let mut references = parent.unwrap().references.clone();
+        references.push(id.clone());
+        headers.push(("In-Reply-To", id.clone()));
+        headers.push(("References", references.join(" ")));
+    }
+
+    headers.push(("MIME-Version", "1.0".to_string()));
+    headers.push(("Content-Type", "text/plain; charset=UTF-8".to_string()));
+    headers.push(("Content-Transfer-Encoding", "8bit".to_string()));
+
+    let mut email = String::new();
+    for (name, value) in headers {
+        writeln!(email, "{name}: {value}").expect("Failed to write to string");
+    }
+    email += "\n";
+
+    if !comments.review_comment.is_empty() {
+        writeln!(email, "{}\n", comments.review_comment.trim_end())
+            .expect("Failed to write to string");
+    }
+    email += &quote_review(original, comments);
+
+    if comments.review_action == ReviewAction::Approve {
+        if let Some(from) = &envelope.from {
+            if !email.ends_with("\n\n") {
+                email += "\n";
+            }
+            writeln!(email, "Reviewed-by: {from}").expect("Failed to write to string");
+        }
+    }
+
+    email
+}
+
+/// Collapses all runs of whitespace in `s` into single spaces
+fn squash_whitespace(s: &str) -> String {
+    s.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Matches the quoted lines at the start of `pattern` against `line` of the
+/// original text, the way mail clients quote
+///
+/// Both `>` and `> ` are accepted as quote markers and trailing whitespace is
+/// ignored. A line that was wrapped over several quoted lines also matches.
+fn match_email_quote(pattern: &[LineType], line: &str) -> Option<usize> {
+    let LineType::Quoted(first) = pattern.first()? else {
+        return None;
+    };
+    let line = line.trim_end();
+    let first = first.trim_end();
+    if first == line || first.strip_prefix(' ') == Some(line) {
+        return Some(1);
+    }
+
+    // Wrapping eats the whitespace it breaks at, and clients may turn tabs
+    // into spaces, so compare with whitespace collapsed
+    let target = squash_whitespace(line);
+    let mut joined = String::new();
+    for (idx, quoted) in pattern.iter().enumerate() {
+        let LineType::Quoted(quoted) = quoted else {
+            break;
+        };
+        let piece = squash_whitespace(quoted);
+        if idx > 0 {
+            if piece.is_empty() {
+                break;
+            }
+            joined.push(' ');
+        }
+        joined += &piece;
+
+        if joined == target {
+            return Some(idx + 1);
+        }
+        if joined.is_empty() || !target.starts_with(&joined) {
+            break;
+        }
+    }
+
+    None
+}
+
+/// Turns the body of an emailed reply to `original` into review file contents
+///
+/// Whatever the reply trimmed from the quoted text is quoted again, so the
+/// result is a complete review file. Text around the quotes becomes comments
+/// in the same place. The signature and the "... wrote:" line above the quote
+/// are dropped. A `Reviewed-by` or `Acked-by` trailer approves the review.
+/// The review comment starts with who the reply is `from`.
+pub fn import_reply(body: &str, original: &str, from: &str) -> Result<String> {
+    let mut lines: Vec<&str> = body
+        .lines()
+        .take_while(|l| *l != "-- " && *l != "--")
+        .skip_while(|l| l.trim().is_empty())
+        .collect();
+
+    // Drop the attribution line above the first quote
+    if let Some(first_quote) = lines.iter().position(|l| l.starts_with('>')) {
+        let attribution = lines[..first_quote]
+            .iter()
+            .rposition(|l| !l.trim().is_empty())
+            .filter(|&i| lines[i].trim_end().ends_with("wrote:"));
+        if let Some(i) = attribution {
+            lines.remove(i);
+        }
+    }
+
+    let is_trailer = |l: &str| APPROVAL_TRAILERS.iter().any(|t| l.starts_with(t));
+    let approve = lines.iter().any(|l| is_trailer(l));
+    lines.retain(|l| !is_trailer(l));
+
+    // People trim quotes without saying so. So every run of quoted lines may
+    // come after a snip.
+    let mut pattern = vec![LineType::Snip];
+    for line in lines {
+        let item = if let Some(quoted) = line.strip_prefix('>') {
+            if matches!(pattern.last(), Some(LineType::Comment(_))) {
+                pattern.push(LineType::Snip);
+            }
+            LineType::Quoted(quoted)
+        } else if SNIP_VARIANTS
+            .iter()
+            .chain(EMAIL_SNIP_VARIANTS)
+            .any(|v| *v == line.trim())
+        {
+            LineType::Snip
+        } else {
+            LineType::Comment(line)
+        };
+
+        if !matches!(
+            (pattern.last(), &item),
+            (Some(LineType::Snip), LineType::Snip)
+        ) {
+            pattern.push(item);
+        }
+    }
+    if !matches!(pattern.last(), Some(LineType::Snip)) {
+        pattern.push(LineType::Snip);
+    }
+
+    let text: Vec<&str> = original.lines().collect();
+    let resolved = resolve_quotes(&pattern, &text, &match_email_quote)
+        .ok_or_else(|| anyhow!("Failed to match the quoted text of the reply to the review"))?;
+
+    let mut contents = String::new();
+    if approve {
+        contents += "@prr approve\n\n";
+    }
+    if !from.is_empty() {
+        writeln!(contents, "Review by {from}, imported from email.\n")
+            .expect("Failed to write to string");
+    }
+    for line in resolved {
+        writeln!(contents, "{line}").expect("Failed to write to string");
+    }
+
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commits::render_commits;
+    use crate::mbox::{parse_email, parse_series};
+    use crate::parser::{CommitComment, FileComment, InlineComment, LineLocation};
+    use pretty_assertions::assert_eq as assert_eq_pretty;
+    use std::time::Duration;
+
+    #[test]
+    fn test_import_reply() {
+        let mbox = include_str!("../testdata/mbox/series.mbox").to_string();
+        let original = render_commits(&parse_series(&[mbox]).unwrap().commits);
+        let eml = include_str!("../testdata/email/quirks.eml");
+        let (email, body) = parse_email(eml).unwrap();
+
+        let imported = import_reply(&body, &original, &email.from).unwrap();
+        assert_eq_pretty!(imported, include_str!("../testdata/email/quirks.prr"));
+    }
+
+    #[test]
+    fn test_import_reply_mismatch() {
+        let original =
+            "diff --git a/a.txt b/a.txt\n--- a/a.txt\n+++ b/a.txt\n@@ -1 +1 @@\n-a\n+b\n";
+        let body = "> +c\n\nWhat?\n";
+        assert!(import_reply(body, original, "").is_err());
+    }
+
+    #[test]
+    fn test_rfc5322_date() {
+        let date = |secs| rfc5322_date(UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(date(0), "Thu, 01 Jan 1970 00:00:00 +0000");
+        assert_eq!(date(1704067200), "Mon, 01 Jan 2024 00:00:00 +0000");
+        assert_eq!(date(1709208000 + 3661), "Thu, 29 Feb 2024 13:01:01 +0000");
+    }
+
+    #[test]
+    fn test_render_reply() {
+        let mbox = include_str!("../testdata/mbox/series.mbox").to_string();
+        let parsed = parse_series(&[mbox]).unwrap();
+        let original = render_commits(&parsed.commits);
+        let first = "1111111111111111111111111111111111111111";
+        let second = "2222222222222222222222222222222222222222";
+
+        let comments = ReviewComments {
+            review_action: ReviewAction::Approve,
+            review_comment: "Nice series.".to_string(),
+            inline_comments: vec![InlineComment {
+                file: "ch1.txt".to_string(),
+                line: LineLocation::Right(4),
+                start_line: Some(LineLocation::Right(3)),
+                comment: "Why add this only to remove it again?".to_string(),
+                commit: Some(first.to_string()),
+            }],
+            file_comments: vec![FileComment {
+                file: "ch1.txt".to_string(),
+                comment: "Maybe split this file up.".to_string(),
+                commit: Some(second.to_string()),
+            }],
+            thread_replies: vec![],
+            commit_comments: vec![CommitComment {
+                commit: second.to_string(),
+                comment: "Say why in the message.".to_string(),
+            }],
+            pending: false,
+        };
+        let envelope = Envelope {
+            from: Some("Wu Qi <wu@example.com>".to_string()),
+            date: "Wed, 03 Jan 2024 00:00:00 +0000".to_string(),
+            message_id: "<prr.1@example.com>".to_string(),
+        };
+
+        let reply = render_reply(
+            &original,
+            &comments,
+            parsed.series.cover.as_ref(),
+            "local/series/1",
+            &envelope,
+        );
+        assert_eq_pretty!(reply, include_str!("../testdata/email/reply"));
+
+        // Without a series to reply to, there is no thread to keep
+        let reply = render_reply(&original, &comments, None, "local/series/1", &envelope);
+        assert!(reply.contains("Subject: Re: local/series/1\n"));
+        assert!(!reply.contains("In-Reply-To"));
+    }
+}
@@ -0,0 +1,472 @@
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+
+use super::rest::{response_id, Rest};
+use super::{Forge, PullRequest, PullRequestState, ReviewSubmission};
+use crate::commits::{Commit, CommitPost};
+use crate::parser::{FileComment, InlineComment, LineLocation, ReviewAction, ThreadReply};
+use crate::review::{ReviewThread, ThreadComment};
+
+/// Number of items to request per page from paginated endpoints. This is the
+/// default maximum of both Forgejo and Gitea.
+const PER_PAGE: usize = 50;
+
+#[derive(Debug, Deserialize)]
+struct FjHead {
+    sha: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct FjPullRequest {
+    title: String,
+    body: Option<String>,
+    user: FjUser,
+    /// `open` or `closed`, whether merged or not
+    state: String,
+    #[serde(default)]
+    merged: bool,
+    head: FjHead,
+}
+
+#[derive(Debug, Deserialize)]
+struct FjReview {
+    id: u64,
+    #[serde(default)]
+    comments_count: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct FjUser {
+    login: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct FjReviewComment {
+    id: u64,
+    body: String,
+    user: FjUser,
+    resolver: Option<FjUser>,
+    path: String,
+    /// Line on the new side, 0 if on the old side
+    #[serde(default)]
+    position: u64,
+    /// Line on the old side, 0 if on the new side
+    #[serde(default)]
+    original_position: u64,
+    created_at: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct FjCommitAuthor {
+    name: String,
+    email: String,
+    date: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct FjCommitDetails {
+    message: String,
+    author: FjCommitAuthor,
+}
+
+#[derive(Debug, Deserialize)]
+struct FjCommit {
+    sha: String,
+    commit: FjCommitDetails,
+}
+
+impl FjReviewComment {
+    fn line(&self) -> Option<LineLocation> {
+        match (self.position, self.original_position) {
+            (0, 0) => None,
+            (0, old) => Some(LineLocation::Left(old)),
+            (new, _) => Some(LineLocation::Right(new)),
+        }
+    }
+}
+
+/// Returns the review state Forgejo expects for `action`
+fn review_event(action: &ReviewAction) -> &'static str {
+    match action {
+        ReviewAction::Approve => "APPROVED",
+        ReviewAction::RequestChanges => "REQUEST_CHANGES",
+        ReviewAction::Comment => "COMMENT",
+    }
+}
+
+/// Returns a review comment as Forgejo expects it
+///
+/// Forgejo comments are attached to a single line, so spans are attached to
+/// their last line.
+fn review_comment(comment: &InlineComment) -> Value {
+    let (old, new) = match comment.line {
+        LineLocation::Left(l) => (l, 0),
+        LineLocation::Right(l) => (0, l),
+    };
+
+    json!({
+        "path": comment.file,
+        "body": comment.comment,
+        "old_position": old,
+        "new_position": new,
+    })
+}
+
+/// Groups review comments into threads
+///
+/// Forgejo has no notion of threads in its API. Comments on the same line are
+/// shown as one conversation, though, so that is what a thread is here. The
+/// thread is identified by its first comment.
+fn group_threads(comments: Vec<FjReviewComment>) -> Vec<ReviewThread> {
+    let mut threads: Vec<ReviewThread> = Vec::new();
+    for c in comments {
+        let line = match c.line() {
+            Some(l) => l,
+            None => continue,
+        };
+        let comment = ThreadComment {
+            author: c.user.login,
+            created_at: c.created_at,
+            body: c.body,
+        };
+
+        match threads
+            .iter_mut()
+            .find(|t| t.file == c.path && t.line == line)
+        {
+            Some(thread) => thread.comments.push(comment),
+            None => threads.push(ReviewThread {
+                id: c.id.to_string(),
+                file: c.path,
+                line,
+                start_line: None,
+                resolved: c.resolver.is_some(),
+                comments: vec![comment],
+            }),
+        }
+    }
+
+    threads
+}
+
+/// Forgejo (or Gitea) pull request forge
+pub struct Forgejo {
+    rest: Rest,
+    /// Base URL of the REST API, eg. `https://codeberg.org/api/v1`
+    api: String,
+}
+
+impl Forgejo {
+    /// Creates a client for the Forgejo instance at `url` authenticating with `token`
+    pub fn new(url: &str, token: String) -> Result<Forgejo> {
+        Ok(Forgejo {
+            rest: Rest::new("Authorization", format!("token {token}"))?,
+            api: format!("{}/api/v1", url.trim_end_matches('/')),
+        })
+    }
+
+    fn repo_url(&self, owner: &str, repo: &str) -> String {
+        format!("{}/repos/{}/{}", self.api, owner, repo)
+    }
+
+    fn pr_url(&self, owner: &str, repo: &str, pr_num: u64) -> String {
+        format!("{}/pulls/{}", self.repo_url(owner, repo), pr_num)
+    }
+
+    /// Posts a review and returns its ID
+    async fn post_review(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        body: &Value,
+    ) -> Result<Option<String>> {
+        let url = format!("{}/reviews", self.pr_url(owner, repo, pr_num));
+        let resp = self.rest.post(&url, body).await?;
+
+        Ok(response_id(&resp))
+    }
+}
+
+#[async_trait]
+impl Forge for Forgejo {
+    async fn get_diff(&self, owner: &str, repo: &str, pr_num: u64) -> Result<String> {
+        let url = format!("{}.diff", self.pr_url(owner, repo, pr_num));
+        self.rest.get_text(&url).await
+    }
+
+    async fn get_pr(&self, owner: &str, repo: &str, pr_num: u64) -> Result<PullRequest> {
+        let pr: FjPullRequest = self.rest.get(&self.pr_url(owner, repo, pr_num)).await?;
+
+        let state = if pr.merged {
+            PullRequestState::Merged
+        } else if pr.state == "closed" {
+            PullRequestState::Closed
+        } else {
+            PullRequestState::Open
+        };
+
+        Ok(PullRequest {
+            head_sha: pr.head.sha,
+            description: pr.body.unwrap_or_default(),
+            title: pr.title,
+            author: pr.user.login,
+            state,
+        })
+    }
+
+    async fn get_review_threads(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+    ) -> Result<Vec<ReviewThread>> {
+        let reviews_url = format!("{}/reviews", self.pr_url(owner, repo, pr_num));
+        let reviews: Vec<FjReview> = self.rest.get_pages(&reviews_url, "limit", PER_PAGE).await?;
+
+        let mut comments = Vec::new();
+        for review in reviews.iter().filter(|r| r.comments_count > 0) {
+            let url = format!("{}/{}/comments", reviews_url, review.id);
+            let review_comments: Vec<FjReviewComment> = self
+                .rest
+                .get(&url)
+                .await
+                .with_context(|| format!("Failed to fetch comments of review {}", review.id))?;
+            comments.extend(review_comments);
+        }
+        comments.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+
+        Ok(group_threads(comments))
+    }
+
+    async fn get_commits(&self, owner: &str, repo: &str, pr_num: u64) -> Result<Vec<Commit>> {
+        let url = format!("{}/commits", self.pr_url(owner, repo, pr_num));
+        let mut fj_commits: Vec<FjCommit> = self.rest.get_pages(&url, "limit", PER_PAGE).await?;
+        // Forgejo lists the newest commit first
+        fj_commits.reverse();
+
+        let mut commits = Vec::with_capacity(fj_commits.len());
+        for c in fj_commits {
+            let url = format!("{}/git/commits/{}.diff", self.repo_url(owner, repo), c.sha);
+            let diff = self
+                .rest
+                .get_text(&url)
+                .await
+                .with_context(|| format!("Failed to fetch diff for commit {}", c.sha))?;
+            commits.push(Commit {
+                author: format!("{} <{}>", c.commit.author.name, c.commit.author.email),
+                date: c.commit.author.date,
+                message: c.commit.message,
+                sha: c.sha,
+                diff,
+            });
+        }
+
+        Ok(commits)
+    }
+
+    async fn submit_review(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        review: &ReviewSubmission<'_>,
+    ) -> Result<Option<String>> {
+        let mut body = json!({
+            "body": review.body,
+            "event": review_event(review.action),
+            "comments": review.comments.iter().map(review_comment).collect::<Vec<_>>(),
+        });
+        if let Some(id) = review.commit_id {
+            body["commit_id"] = json!(id);
+        }
+
+        self.post_review(owner, repo, pr_num, &body).await
+    }
+
+    async fn submit_file_comment(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        _commit_id: &str,
+        comment: &FileComment,
+    ) -> Result<Option<String>> {
+        // Forgejo cannot attach comments to whole files, so leave a regular
+        // comment on the pull request naming the file instead
+        let url = format!("{}/issues/{}/comments", self.repo_url(owner, repo), pr_num);
+        let body = format!("`{}`:\n\n{}", comment.file, comment.comment);
+        let resp = self.rest.post(&url, &json!({ "body": body })).await?;
+
+        Ok(response_id(&resp))
+    }
+
+    async fn submit_thread_reply(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        reply: &ThreadReply,
+    ) -> Result<Option<String>> {
+        if reply.resolve {
+            eprintln!(
+                "Warning: Forgejo cannot resolve conversations through its API, \
+                 please resolve thread {} in the web interface",
+                reply.thread
+            );
+        }
+        if reply.comment.is_empty() {
+            return Ok(None);
+        }
+
+        // Replying means commenting on the same line again
+        let threads = self.get_review_threads(owner, repo, pr_num).await?;
+        let thread = threads
+            .iter()
+            .find(|t| t.id == reply.thread)
+            .ok_or_else(|| anyhow!("Thread {} not found", reply.thread))?;
+        let comment = InlineComment {
+            file: thread.file.clone(),
+            line: thread.line.clone(),
+            start_line: None,
+            comment: reply.comment.clone(),
+            commit: None,
+        };
+        let body = json!({
+            "body": "",
+            "event": review_event(&ReviewAction::Comment),
+            "comments": [review_comment(&comment)],
+        });
+
+        self.post_review(owner, repo, pr_num, &body).await
+    }
+
+    async fn submit_commit_comment(
+        &self,
+        _owner: &str,
+        _repo: &str,
+        _pr_num: u64,
+        post: &CommitPost,
+    ) -> Result<Option<String>> {
+        bail!(
+            "Forgejo does not support commenting on commit {}",
+            post.commit
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::{Matcher, Server};
+
+    const PR: &str = "/api/v1/repos/owner/repo/pulls/3";
+
+    #[tokio::test]
+    async fn test_get_diff() {
+        let mut server = Server::new_async().await;
+        let diff = include_str!("../../testdata/review/threads/diff");
+        let mock = server
+            .mock("GET", format!("{PR}.diff").as_str())
+            .match_header("Authorization", "token secret")
+            .with_body(diff)
+            .create();
+        let forgejo = Forgejo::new(&server.url(), "secret".to_string()).unwrap();
+
+        assert_eq!(forgejo.get_diff("owner", "repo", 3).await.unwrap(), diff);
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_get_review_threads() {
+        let mut server = Server::new_async().await;
+        let reviews = server
+            .mock("GET", format!("{PR}/reviews").as_str())
+            .match_query(Matcher::Any)
+            .with_body(include_str!("../../testdata/forgejo/reviews.json"))
+            .create();
+        let comments = server
+            .mock("GET", format!("{PR}/reviews/11/comments").as_str())
+            .with_body(include_str!("../../testdata/forgejo/review_comments.json"))
+            .create();
+        let forgejo = Forgejo::new(&server.url(), "secret".to_string()).unwrap();
+
+        let threads = forgejo
+            .get_review_threads("owner", "repo", 3)
+            .await
+            .unwrap();
+        // Review 12 has no comments, so it is not fetched
+        reviews.assert();
+        comments.assert();
+
+        assert_eq!(threads.len(), 2);
+        assert_eq!(threads[0].id, "101");
+        assert_eq!(threads[0].file, "ch1.txt");
+        assert_eq!(threads[0].line, LineLocation::Right(3));
+        assert!(threads[0].resolved);
+        assert_eq!(threads[0].comments.len(), 2);
+        assert_eq!(threads[0].comments[1].author, "bob");
+        assert_eq!(threads[1].id, "102");
+        assert_eq!(threads[1].line, LineLocation::Left(2));
+        assert!(!threads[1].resolved);
+    }
+
+    #[tokio::test]
+    async fn test_submit_review() {
+        let mut server = Server::new_async().await;
+        let mock = server
+            .mock("POST", format!("{PR}/reviews").as_str())
+            .match_body(Matcher::Json(json!({
+                "body": "Needs work",
+                "event": "REQUEST_CHANGES",
+                "commit_id": "abc",
+                "comments": [
+                    {
+                        "path": "ch1.txt",
+                        "body": "Span",
+                        "old_position": 0,
+                        "new_position": 4,
+                    },
+                    {
+                        "path": "ch1.txt",
+                        "body": "Removed",
+                        "old_position": 2,
+                        "new_position": 0,
+                    },
+                ],
+            })))
+            .with_body("{}")
+            .create();
+        let forgejo = Forgejo::new(&server.url(), "secret".to_string()).unwrap();
+
+        let comments = vec![
+            InlineComment {
+                file: "ch1.txt".to_string(),
+                line: LineLocation::Right(4),
+                start_line: Some(LineLocation::Right(3)),
+                comment: "Span".to_string(),
+                commit: None,
+            },
+            InlineComment {
+                file: "ch1.txt".to_string(),
+                line: LineLocation::Left(2),
+                start_line: None,
+                comment: "Removed".to_string(),
+                commit: None,
+            },
+        ];
+        let review = ReviewSubmission {
+            action: &ReviewAction::RequestChanges,
+            body: "Needs work",
+            comments: &comments,
+            commit_id: Some("abc"),
+        };
+        forgejo
+            .submit_review("owner", "repo", 3, &review)
+            .await
+            .unwrap();
+        mock.assert();
+    }
+}
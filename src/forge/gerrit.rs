@@ -0,0 +1,656 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use reqwest::Method;
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+
+use super::rest::Rest;
+use super::{Forge, PullRequest, PullRequestState, ReviewSubmission};
+use crate::commits::{Commit, CommitPost};
+use crate::parser::{
+    diff_locations, FileComment, InlineComment, LineLocation, ReviewAction, ThreadReply,
+};
+use crate::review::{ReviewThread, ThreadComment};
+
+/// Owner all Gerrit changes are filed under. Gerrit projects have no owner, so
+/// this keeps their review files apart from other forges' in the workdir.
+pub const GERRIT_OWNER: &str = "gerrit";
+
+/// Gerrit prefixes JSON responses with this to prevent XSSI
+const XSSI_PREFIX: &str = ")]}'";
+
+/// Path Gerrit uses for comments on the commit message
+const COMMIT_MSG: &str = "/COMMIT_MSG";
+
+/// Label `@prr approve` and `@prr reject` vote on
+const CODE_REVIEW: &str = "Code-Review";
+
+/// Returns the owner and repo a Gerrit `project` is filed under
+///
+/// Any directories of the project become part of the owner, eg.
+/// `platform/build` is `gerrit/platform` and `build`.
+pub fn gerrit_owner_repo(project: &str) -> (String, String) {
+    match project.rsplit_once('/') {
+        Some((dirs, name)) => (format!("{GERRIT_OWNER}/{dirs}"), name.to_string()),
+        None => (GERRIT_OWNER.to_string(), project.to_string()),
+    }
+}
+
+/// Returns the Gerrit project filed under `owner` and `repo`. Inverse of
+/// `gerrit_owner_repo()`.
+pub fn gerrit_project(owner: &str, repo: &str) -> String {
+    match owner
+        .strip_prefix(GERRIT_OWNER)
+        .and_then(|o| o.strip_prefix('/'))
+    {
+        Some(dirs) => format!("{dirs}/{repo}"),
+        None => repo.to_string(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct GrAccount {
+    name: Option<String>,
+    username: Option<String>,
+    email: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GrGitPerson {
+    name: String,
+    email: String,
+    date: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct GrCommit {
+    author: GrGitPerson,
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct GrRevision {
+    #[serde(rename = "_number")]
+    number: u64,
+    commit: Option<GrCommit>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GrChange {
+    subject: String,
+    /// One of `NEW`, `MERGED` or `ABANDONED`
+    status: String,
+    owner: GrAccount,
+    current_revision: String,
+    revisions: HashMap<String, GrRevision>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GrComment {
+    id: String,
+    patch_set: Option<u64>,
+    /// `PARENT` if on the old side. Absent if on the new side.
+    side: Option<String>,
+    line: Option<u64>,
+    in_reply_to: Option<String>,
+    message: String,
+    updated: String,
+    author: Option<GrAccount>,
+    #[serde(default)]
+    unresolved: bool,
+}
+
+impl GrAccount {
+    fn login(&self) -> String {
+        self.username
+            .clone()
+            .or_else(|| self.name.clone())
+            .or_else(|| self.email.clone())
+            .unwrap_or_else(|| "unknown".to_string())
+    }
+}
+
+impl GrChange {
+    fn current(&self) -> Result<&GrRevision> {
+        self.revisions
+            .get(&self.current_revision)
+            .ok_or_else(|| anyhow!("Change is missing its current revision"))
+    }
+}
+
+/// Strips the XSSI prefix off a Gerrit JSON response
+fn strip_xssi(text: &str) -> &str {
+    text.strip_prefix(XSSI_PREFIX).unwrap_or(text)
+}
+
+/// Extracts the diff from a patch as returned by Gerrit
+///
+/// The patch is base64 encoded `git format-patch` output, the diff being
+/// everything from the first file header on.
+fn patch_diff(patch: &str) -> Result<String> {
+    let patch: String = patch.split_whitespace().collect();
+    let decoded = BASE64.decode(patch).context("Failed to decode patch")?;
+    let text = String::from_utf8(decoded).context("Patch is not valid UTF-8")?;
+
+    let start = if text.starts_with("diff --git ") {
+        Some(0)
+    } else {
+        text.find("\ndiff --git ").map(|idx| idx + 1)
+    };
+    match start {
+        Some(idx) => Ok(text[idx..].to_string()),
+        None => Ok(String::new()),
+    }
+}
+
+/// Returns the location of a comment as Gerrit expects it
+fn comment_location(line: &LineLocation) -> (u64, Option<&'static str>) {
+    match line {
+        LineLocation::Left(l) => (*l, Some("PARENT")),
+        LineLocation::Right(l) => (*l, None),
+    }
+}
+
+/// Returns an inline comment as Gerrit expects it
+///
+/// Spans become ranges of whole lines. Gerrit wants the range to end after the
+/// last character of the end line, so its length is looked up in `diff`.
+fn inline_comment(diff: &str, comment: &InlineComment) -> Value {
+    let (line, side) = comment_location(&comment.line);
+    let mut input = json!({
+        "line": line,
+        "message": comment.comment,
+    });
+    if let Some(side) = side {
+        input["side"] = json!(side);
+    }
+
+    let start = comment
+        .start_line
+        .as_ref()
+        .map(comment_location)
+        .filter(|(_, start_side)| *start_side == side);
+    if let Some((start, _)) = start {
+        let end_len = diff
+            .lines()
+            .zip(diff_locations(diff))
+            .find(
+                |(_, loc)| matches!(loc, Some((f, l)) if *f == comment.file && *l == comment.line),
+            )
+            .map_or(0, |(text, _)| text.chars().count().saturating_sub(1));
+        input["range"] = json!({
+            "start_line": start,
+            "start_character": 0,
+            "end_line": line,
+            "end_character": end_len,
+        });
+    }
+
+    input
+}
+
+/// Groups the comments on a revision into threads
+///
+/// Replies point at the comment they reply to, not the root of the thread. A
+/// thread is resolved if its last comment says so.
+fn group_threads(comments: BTreeMap<String, Vec<GrComment>>, patch_set: u64) -> Vec<ReviewThread> {
+    let mut all: Vec<(String, GrComment)> = comments
+        .into_iter()
+        .flat_map(|(file, cs)| cs.into_iter().map(move |c| (file.clone(), c)))
+        .filter(|(_, c)| c.patch_set == Some(patch_set))
+        .collect();
+    all.sort_by(|a, b| a.1.updated.cmp(&b.1.updated));
+
+    let mut threads: Vec<ReviewThread> = Vec::new();
+    // Maps comment IDs to the index of their thread
+    let mut thread_of: HashMap<String, usize> = HashMap::new();
+    for (file, c) in all {
+        let comment = ThreadComment {
+            author: c
+                .author
+                .as_ref()
+                .map_or("unknown".to_string(), GrAccount::login),
+            created_at: c.updated,
+            body: c.message,
+        };
+
+        let parent = c.in_reply_to.as_ref().and_then(|p| thread_of.get(p));
+        if let Some(&idx) = parent {
+            threads[idx].comments.push(comment);
+            threads[idx].resolved = !c.unresolved;
+            thread_of.insert(c.id, idx);
+            continue;
+        }
+
+        // Only comments on lines of the diff can be shown in a review file
+        let line = match (c.line, c.side.as_deref()) {
+            (Some(l), Some("PARENT")) => LineLocation::Left(l),
+            (Some(l), _) => LineLocation::Right(l),
+            (None, _) => continue,
+        };
+        if file == COMMIT_MSG {
+            continue;
+        }
+
+        thread_of.insert(c.id.clone(), threads.len());
+        threads.push(ReviewThread {
+            id: c.id,
+            file,
+            line,
+            start_line: None,
+            resolved: !c.unresolved,
+            comments: vec![comment],
+        });
+    }
+
+    threads
+}
+
+/// Gerrit change forge
+///
+/// Changes are identified by their project and number. The project is stored
+/// in `owner` and `repo`, see `gerrit_owner_repo()`.
+pub struct Gerrit {
+    rest: Rest,
+    /// Base URL of the authenticated REST API, eg. `https://review.example.com/a`
+    api: String,
+}
+
+impl Gerrit {
+    /// Creates a client for the Gerrit instance at `url` authenticating with
+    /// `username` and HTTP `password`
+    pub fn new(url: &str, username: &str, password: &str) -> Result<Gerrit> {
+        let credentials = BASE64.encode(format!("{username}:{password}"));
+        Ok(Gerrit {
+            rest: Rest::new("Authorization", format!("Basic {credentials}"))?,
+            api: format!("{}/a", url.trim_end_matches('/')),
+        })
+    }
+
+    fn change_url(&self, owner: &str, repo: &str, change: u64) -> String {
+        let project = gerrit_project(owner, repo).replace('/', "%2F");
+        format!("{}/changes/{}~{}", self.api, project, change)
+    }
+
+    fn revision_url(&self, owner: &str, repo: &str, change: u64, revision: &str) -> String {
+        format!(
+            "{}/revisions/{}",
+            self.change_url(owner, repo, change),
+            revision
+        )
+    }
+
+    async fn get_json<T: serde::de::DeserializeOwned>(&self, url: &str) -> Result<T> {
+        let text = self.rest.get_text(url).await?;
+        serde_json::from_str(strip_xssi(&text)).context("Failed to parse response")
+    }
+
+    async fn get_change(&self, owner: &str, repo: &str, change: u64) -> Result<GrChange> {
+        let url = format!(
+            "{}?o=CURRENT_REVISION&o=CURRENT_COMMIT&o=DETAILED_ACCOUNTS",
+            self.change_url(owner, repo, change)
+        );
+        self.get_json(&url).await
+    }
+
+    /// Posts a review on `revision`. Gerrit accepts commit hashes as well as
+    /// `current` for the latest patchset.
+    ///
+    /// Gerrit does not say what it created, so there is never an ID to return.
+    async fn post_review(
+        &self,
+        owner: &str,
+        repo: &str,
+        change: u64,
+        revision: &str,
+        input: &Value,
+    ) -> Result<Option<String>> {
+        let url = format!(
+            "{}/review",
+            self.revision_url(owner, repo, change, revision)
+        );
+        self.rest
+            .send(self.rest.request(Method::POST, &url).json(input))
+            .await?;
+
+        Ok(None)
+    }
+}
+
+#[async_trait]
+impl Forge for Gerrit {
+    async fn get_diff(&self, owner: &str, repo: &str, pr_num: u64) -> Result<String> {
+        let url = format!(
+            "{}/patch",
+            self.revision_url(owner, repo, pr_num, "current")
+        );
+        let patch = self.rest.get_text(&url).await?;
+
+        patch_diff(&patch)
+    }
+
+    async fn get_pr(&self, owner: &str, repo: &str, pr_num: u64) -> Result<PullRequest> {
+        let change = self.get_change(owner, repo, pr_num).await?;
+        let description = change
+            .current()?
+            .commit
+            .as_ref()
+            .map(|c| c.message.clone())
+            .unwrap_or_default();
+
+        let state = match change.status.as_str() {
+            "MERGED" => PullRequestState::Merged,
+            "ABANDONED" => PullRequestState::Closed,
+            _ => PullRequestState::Open,
+        };
+
+        Ok(PullRequest {
+            head_sha: change.current_revision,
+            description,
+            title: change.subject,
+            author: change.owner.login(),
+            state,
+        })
+    }
+
+    async fn get_review_threads(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+    ) -> Result<Vec<ReviewThread>> {
+        let change = self.get_change(owner, repo, pr_num).await?;
+        let url = format!("{}/comments", self.change_url(owner, repo, pr_num));
+        let comments: BTreeMap<String, Vec<GrComment>> = self.get_json(&url).await?;
+
+        Ok(group_threads(comments, change.current()?.number))
+    }
+
+    async fn get_commits(&self, owner: &str, repo: &str, pr_num: u64) -> Result<Vec<Commit>> {
+        // A change is a single commit
+        let change = self.get_change(owner, repo, pr_num).await?;
+        let commit = change
+            .current()?
+            .commit
+            .as_ref()
+            .ok_or_else(|| anyhow!("Change is missing its commit"))?;
+
+        Ok(vec![Commit {
+            sha: change.current_revision.clone(),
+            author: format!("{} <{}>", commit.author.name, commit.author.email),
+            date: commit.author.date.clone(),
+            message: commit.message.clone(),
+            diff: self.get_diff(owner, repo, pr_num).await?,
+        }])
+    }
+
+    async fn submit_review(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        review: &ReviewSubmission<'_>,
+    ) -> Result<Option<String>> {
+        let mut input = json!({});
+        if !review.body.is_empty() {
+            input["message"] = json!(review.body);
+        }
+
+        match review.action {
+            ReviewAction::Approve => input["labels"] = json!({ CODE_REVIEW: 1 }),
+            ReviewAction::RequestChanges => input["labels"] = json!({ CODE_REVIEW: -1 }),
+            ReviewAction::Comment => (),
+        }
+
+        if !review.comments.is_empty() {
+            let diff = self.get_diff(owner, repo, pr_num).await?;
+            let mut comments: BTreeMap<&str, Vec<Value>> = BTreeMap::new();
+            for c in review.comments {
+                comments
+                    .entry(&c.file)
+                    .or_default()
+                    .push(inline_comment(&diff, c));
+            }
+            input["comments"] = json!(comments);
+        }
+
+        let revision = review.commit_id.unwrap_or("current");
+        self.post_review(owner, repo, pr_num, revision, &input)
+            .await
+    }
+
+    async fn submit_file_comment(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        commit_id: &str,
+        comment: &FileComment,
+    ) -> Result<Option<String>> {
+        let input = json!({
+            "comments": { &comment.file: [{ "message": comment.comment }] },
+        });
+
+        self.post_review(owner, repo, pr_num, commit_id, &input)
+            .await
+    }
+
+    async fn submit_thread_reply(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        reply: &ThreadReply,
+    ) -> Result<Option<String>> {
+        let threads = self.get_review_threads(owner, repo, pr_num).await?;
+        let thread = threads
+            .iter()
+            .find(|t| t.id == reply.thread)
+            .ok_or_else(|| anyhow!("Thread {} not found", reply.thread))?;
+
+        // Gerrit marks threads resolved with a reply, "Done" being the customary one
+        let message = if reply.comment.is_empty() {
+            "Done"
+        } else {
+            &reply.comment
+        };
+        let (line, side) = comment_location(&thread.line);
+        let mut input = json!({
+            "in_reply_to": reply.thread,
+            "line": line,
+            "message": message,
+            "unresolved": !reply.resolve,
+        });
+        if let Some(side) = side {
+            input["side"] = json!(side);
+        }
+
+        let review = json!({ "comments": { &thread.file: [input] } });
+        self.post_review(owner, repo, pr_num, "current", &review)
+            .await
+    }
+
+    async fn submit_commit_comment(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        post: &CommitPost,
+    ) -> Result<Option<String>> {
+        // Gerrit has no line positions for commits, so the comment goes on the
+        // whole file, or the commit message if there is no file
+        let path = post.path.as_deref().unwrap_or(COMMIT_MSG);
+        let input = json!({
+            "comments": { path: [{ "message": post.body }] },
+        });
+
+        self.post_review(owner, repo, pr_num, &post.commit, &input)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::{Matcher, Server};
+    use pretty_assertions::assert_eq as assert_eq_pretty;
+
+    const CHANGE: &str = "/a/changes/platform%2Fbuild~12345";
+
+    fn change_mock(server: &mut mockito::ServerGuard) -> mockito::Mock {
+        server
+            .mock("GET", CHANGE)
+            .match_query(Matcher::Any)
+            .with_body(include_str!("../../testdata/gerrit/change.json"))
+            .create()
+    }
+
+    #[test]
+    fn test_owner_repo() {
+        let (owner, repo) = gerrit_owner_repo("platform/build");
+        assert_eq!(
+            (owner.as_str(), repo.as_str()),
+            ("gerrit/platform", "build")
+        );
+        assert_eq!(gerrit_project(&owner, &repo), "platform/build");
+
+        let (owner, repo) = gerrit_owner_repo("build");
+        assert_eq!((owner.as_str(), repo.as_str()), ("gerrit", "build"));
+        assert_eq!(gerrit_project(&owner, &repo), "build");
+    }
+
+    #[tokio::test]
+    async fn test_get_diff() {
+        let mut server = Server::new_async().await;
+        let diff = include_str!("../../testdata/review/threads/diff");
+        let patch = format!(
+            "From abc Mon Sep 17 00:00:00 2001\nFrom: Sun Tzu <sun@example.com>\n\
+             Subject: [PATCH] ch1: Expand\n\n---\n\n{diff}"
+        );
+        let mock = server
+            .mock("GET", format!("{CHANGE}/revisions/current/patch").as_str())
+            .match_header("Authorization", "Basic dXNlcjpwYXNz")
+            .with_body(BASE64.encode(patch))
+            .create();
+        let gerrit = Gerrit::new(&server.url(), "user", "pass").unwrap();
+
+        let got = gerrit.get_diff("gerrit/platform", "build", 12345).await;
+        assert_eq_pretty!(got.unwrap(), diff);
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_get_pr() {
+        let mut server = Server::new_async().await;
+        let change = change_mock(&mut server);
+        let gerrit = Gerrit::new(&server.url(), "user", "pass").unwrap();
+
+        let pr = gerrit.get_pr("gerrit/platform", "build", 12345).await;
+        change.assert();
+        let pr = pr.unwrap();
+        assert_eq!(pr.head_sha, "4803c71e6b1833ca72b8b26ef2ecd5adc8a38031");
+        assert_eq!(pr.title, "ch1: Expand on the second point");
+        assert_eq!(pr.author, "suntzu");
+        assert_eq!(pr.state, PullRequestState::Open);
+    }
+
+    #[tokio::test]
+    async fn test_get_review_threads() {
+        let mut server = Server::new_async().await;
+        let change = change_mock(&mut server);
+        let comments = server
+            .mock("GET", format!("{CHANGE}/comments").as_str())
+            .with_body(include_str!("../../testdata/gerrit/comments.json"))
+            .create();
+        let gerrit = Gerrit::new(&server.url(), "user", "pass").unwrap();
+
+        let threads = gerrit
+            .get_review_threads("gerrit/platform", "build", 12345)
+            .await
+            .unwrap();
+        change.assert();
+        comments.assert();
+
+        // Comments on older patchsets and the commit message are skipped
+        assert_eq!(threads.len(), 2);
+        assert_eq!(threads[0].id, "c1");
+        assert_eq!(threads[0].file, "ch1.txt");
+        assert_eq!(threads[0].line, LineLocation::Right(3));
+        assert!(threads[0].resolved);
+        assert_eq!(threads[0].comments.len(), 3);
+        assert_eq!(threads[0].comments[2].author, "alice");
+        assert_eq!(threads[1].id, "c4");
+        assert_eq!(threads[1].line, LineLocation::Left(3));
+        assert!(!threads[1].resolved);
+    }
+
+    #[tokio::test]
+    async fn test_submit_review() {
+        let mut server = Server::new_async().await;
+        let diff = server
+            .mock("GET", format!("{CHANGE}/revisions/current/patch").as_str())
+            .with_body(BASE64.encode(include_str!("../../testdata/review/threads/diff")))
+            .create();
+        let review = server
+            .mock("POST", format!("{CHANGE}/revisions/abc/review").as_str())
+            .match_body(Matcher::Json(json!({
+                "message": "Needs work",
+                "labels": { "Code-Review": -1 },
+                "comments": {
+                    "ch1.txt": [
+                        {
+                            "line": 4,
+                            "message": "Span",
+                            "range": {
+                                "start_line": 3,
+                                "start_character": 0,
+                                "end_line": 4,
+                                "end_character": 73,
+                            },
+                        },
+                        {
+                            "line": 3,
+                            "side": "PARENT",
+                            "message": "Removed",
+                        },
+                    ],
+                },
+            })))
+            .with_body(format!("{XSSI_PREFIX}\n{{}}"))
+            .create();
+        let gerrit = Gerrit::new(&server.url(), "user", "pass").unwrap();
+
+        let comments = vec![
+            InlineComment {
+                file: "ch1.txt".to_string(),
+                line: LineLocation::Right(4),
+                start_line: Some(LineLocation::Right(3)),
+                comment: "Span".to_string(),
+                commit: None,
+            },
+            InlineComment {
+                file: "ch1.txt".to_string(),
+                line: LineLocation::Left(3),
+                start_line: None,
+                comment: "Removed".to_string(),
+                commit: None,
+            },
+        ];
+        let submission = ReviewSubmission {
+            action: &ReviewAction::RequestChanges,
+            body: "Needs work",
+            comments: &comments,
+            commit_id: Some("abc"),
+        };
+        gerrit
+            .submit_review("gerrit/platform", "build", 12345, &submission)
+            .await
+            .unwrap();
+        diff.assert();
+        review.assert();
+    }
+}
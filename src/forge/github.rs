@@ -0,0 +1,712 @@
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use http::header::{HeaderMap, HeaderValue, ACCEPT};
+use http::{Method, StatusCode, Uri};
+use octocrab::models::IssueState;
+use octocrab::Octocrab;
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+
+use super::{
+    Forge, PostedComment, PullRequest, PullRequestState, PullRequestSummary, ReviewSubmission,
+};
+use crate::commits::{Commit, CommitPost};
+use crate::parser::{FileComment, LineLocation, ReviewAction, ThreadReply};
+use crate::review::{ReviewThread, ThreadComment};
+
+/// GraphQL query for the review threads on a PR. REST has no notion of threads
+/// nor of their resolution state, so we go through GraphQL instead.
+const REVIEW_THREADS_QUERY: &str = r#"
+query($owner: String!, $repo: String!, $number: Int!, $cursor: String) {
+  repository(owner: $owner, name: $repo) {
+    pullRequest(number: $number) {
+      reviewThreads(first: 100, after: $cursor) {
+        pageInfo {
+          hasNextPage
+          endCursor
+        }
+        nodes {
+          id
+          isResolved
+          path
+          line
+          diffSide
+          startLine
+          startDiffSide
+          comments(first: 100) {
+            nodes {
+              author {
+                login
+              }
+              body
+              createdAt
+            }
+          }
+        }
+      }
+    }
+  }
+}
+"#;
+
+/// GraphQL mutation to reply to a review thread
+const ADD_THREAD_REPLY_MUTATION: &str = r#"
+mutation($thread: ID!, $body: String!) {
+  addPullRequestReviewThreadReply(input: {pullRequestReviewThreadId: $thread, body: $body}) {
+    comment {
+      id
+    }
+  }
+}
+"#;
+
+/// GraphQL mutation to resolve a review thread
+const RESOLVE_THREAD_MUTATION: &str = r#"
+mutation($thread: ID!) {
+  resolveReviewThread(input: {threadId: $thread}) {
+    thread {
+      id
+    }
+  }
+}
+"#;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GqlPageInfo {
+    has_next_page: bool,
+    end_cursor: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GqlAuthor {
+    login: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GqlThreadComment {
+    /// Deleted ("ghost") users have no author
+    author: Option<GqlAuthor>,
+    body: String,
+    created_at: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct GqlThreadComments {
+    nodes: Vec<GqlThreadComment>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GqlThread {
+    id: String,
+    is_resolved: bool,
+    path: String,
+    /// Outdated threads no longer have a line in the current diff
+    line: Option<u64>,
+    diff_side: String,
+    start_line: Option<u64>,
+    start_diff_side: Option<String>,
+    comments: GqlThreadComments,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GqlThreads {
+    page_info: GqlPageInfo,
+    nodes: Vec<GqlThread>,
+}
+
+impl GqlThread {
+    /// Converts into a `ReviewThread`, or `None` if the thread is outdated
+    fn into_review_thread(self) -> Option<ReviewThread> {
+        let location = |line, side: &str| match side {
+            "LEFT" => LineLocation::Left(line),
+            _ => LineLocation::Right(line),
+        };
+        let line = location(self.line?, &self.diff_side);
+        let start_line = self
+            .start_line
+            .map(|l| location(l, self.start_diff_side.as_deref().unwrap_or_default()));
+
+        Some(ReviewThread {
+            id: self.id,
+            file: self.path,
+            line,
+            start_line,
+            resolved: self.is_resolved,
+            comments: self
+                .comments
+                .nodes
+                .into_iter()
+                .map(|c| ThreadComment {
+                    author: c
+                        .author
+                        .map(|a| a.login)
+                        .unwrap_or_else(|| "ghost".to_string()),
+                    created_at: c.created_at,
+                    body: c.body,
+                })
+                .collect(),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct GhCommitAuthor {
+    name: String,
+    email: String,
+    date: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct GhCommitDetails {
+    author: GhCommitAuthor,
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct GhCommit {
+    sha: String,
+    commit: GhCommitDetails,
+}
+
+/// Anything GitHub creates, eg. a review or a comment
+#[derive(Debug, Deserialize)]
+struct GhCreated {
+    id: u64,
+}
+
+/// Returns the ID of what a POST created, if the response says
+fn created_id(response: &str) -> Option<String> {
+    serde_json::from_str::<GhCreated>(response)
+        .ok()
+        .map(|c| c.id.to_string())
+}
+
+/// An issue or PR in search results
+#[derive(Debug, Deserialize)]
+struct GhIssue {
+    number: u64,
+    title: String,
+    /// API URL of the repository, ending in `/repos/{owner}/{repo}`
+    repository_url: String,
+    /// Only set for PRs
+    pull_request: Option<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GhSearchResults {
+    items: Vec<GhIssue>,
+}
+
+/// A comment attached to a line (or file) of a PR
+#[derive(Debug, Deserialize)]
+struct GhReviewComment {
+    id: u64,
+    pull_request_review_id: Option<u64>,
+    path: String,
+    /// Line the comment was made on, which unlike `line` is kept when the
+    /// comment becomes outdated
+    original_line: Option<u64>,
+    original_start_line: Option<u64>,
+    side: Option<String>,
+    start_side: Option<String>,
+}
+
+/// Returns the side and line number GitHub uses for a line location
+fn line_and_side(line: &LineLocation) -> (u64, &'static str) {
+    match line {
+        LineLocation::Left(line) => (*line, "LEFT"),
+        LineLocation::Right(line) => (*line, "RIGHT"),
+    }
+}
+
+/// Returns the review event that submits a review with `action`
+fn review_event(action: &ReviewAction) -> &'static str {
+    match action {
+        ReviewAction::Approve => "APPROVE",
+        ReviewAction::RequestChanges => "REQUEST_CHANGES",
+        ReviewAction::Comment => "COMMENT",
+    }
+}
+
+/// Builds the JSON body of a `POST /pulls/{pr_num}/reviews` request
+fn review_body(review: &ReviewSubmission) -> Value {
+    let mut body = json!({
+        "body": review.body,
+        "event": review_event(review.action),
+        "comments": review
+            .comments
+            .iter()
+            .map(|c| {
+                let (line, side) = line_and_side(&c.line);
+                let mut json_comment = json!({
+                    "path": c.file,
+                    "line": line,
+                    "body": c.comment,
+                    "side": side,
+                });
+                if let Some(start_line) = &c.start_line {
+                    let (line, side) = line_and_side(start_line);
+                    json_comment["start_line"] = line.into();
+                    json_comment["start_side"] = side.into();
+                }
+
+                json_comment
+            })
+            .collect::<Vec<Value>>(),
+    });
+
+    if let Some(id) = review.commit_id {
+        body["commit_id"] = json!(id);
+    }
+
+    body
+}
+
+/// GitHub (or GitHub Enterprise) forge
+pub struct GitHub {
+    /// Instantiated github client
+    crab: Octocrab,
+}
+
+impl GitHub {
+    /// Creates a client for the GitHub API at `url` authenticating with `token`
+    pub fn new(token: String, url: String) -> Result<GitHub> {
+        let crab = Octocrab::builder()
+            .personal_token(token)
+            .base_uri(url)
+            .context("Failed to parse github base URL")?
+            .build()
+            .context("Failed to create GH client")?;
+
+        Ok(GitHub { crab })
+    }
+
+    /// Fetches the diff of a single commit against its parent
+    async fn get_commit_diff(&self, owner: &str, repo: &str, sha: &str) -> Result<String> {
+        let path = format!("/repos/{}/{}/commits/{}", owner, repo, sha);
+        let uri = Uri::builder()
+            .path_and_query(path)
+            .build()
+            .context("Invalid URI")?;
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            ACCEPT,
+            HeaderValue::from_static("application/vnd.github.v3.diff"),
+        );
+        let resp = self.crab._get_with_headers(uri, Some(headers)).await?;
+        let status = resp.status();
+        let text = self
+            .crab
+            .body_to_string(resp)
+            .await
+            .context("Failed to decode response")?;
+        if status != StatusCode::OK {
+            bail!("Error during GET: Status code: {}, Body: {}", status, text);
+        }
+
+        Ok(text)
+    }
+
+    /// POSTs `body` to `path` and returns the response body
+    ///
+    /// Fails unless the response status is `expected`.
+    async fn post(&self, path: String, body: &Value, expected: StatusCode) -> Result<String> {
+        self.send(Method::POST, path, Some(body), expected).await
+    }
+
+    /// POSTs `body` to `path` and returns the ID of what it created
+    ///
+    /// The ID is `None` if GitHub's response could not be read.
+    async fn post_created(
+        &self,
+        path: String,
+        body: &Value,
+        expected: StatusCode,
+    ) -> Result<Option<String>> {
+        match self.post(path, body, expected).await {
+            Ok(text) => Ok(created_id(&text)),
+            // GH is known to send unescaped control characters in JSON responses which
+            // serde will fail to parse (not that it should succeed)
+            Err(e) if matches!(e.downcast_ref(), Some(octocrab::Error::Json { .. })) => {
+                eprintln!("Warning: GH response had invalid JSON, so the ID it gave is unknown");
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Sends a `method` request with `body` to `path` and returns the response body
+    ///
+    /// Fails unless the response status is `expected`.
+    async fn send(
+        &self,
+        method: Method,
+        path: String,
+        body: Option<&Value>,
+        expected: StatusCode,
+    ) -> Result<String> {
+        let uri = Uri::builder()
+            .path_and_query(path)
+            .build()
+            .context("Invalid URI")?;
+        let resp = match method {
+            Method::POST => self.crab._post(uri, body).await?,
+            Method::PATCH => self.crab._patch(uri, body).await?,
+            Method::PUT => self.crab._put(uri, body).await?,
+            Method::DELETE => self.crab._delete(uri, body).await?,
+            _ => bail!("Unsupported method: {}", method),
+        };
+        let status = resp.status();
+        let text = self
+            .crab
+            .body_to_string(resp)
+            .await
+            .context("Failed to decode response")?;
+        if status != expected {
+            bail!(
+                "Error during {}: Status code: {}, Body: {}",
+                method,
+                status,
+                text
+            );
+        }
+
+        Ok(text)
+    }
+
+    /// Runs a GraphQL query or mutation and returns the `data` in the response
+    async fn graphql(&self, query: &str, variables: Value) -> Result<Value> {
+        let body = json!({
+            "query": query,
+            "variables": variables,
+        });
+        let mut resp: Value = self.crab.graphql(&body).await?;
+        if let Some(errors) = resp.get("errors") {
+            bail!("GraphQL request failed: {}", errors);
+        }
+
+        Ok(resp["data"].take())
+    }
+}
+
+#[async_trait]
+impl Forge for GitHub {
+    async fn get_diff(&self, owner: &str, repo: &str, pr_num: u64) -> Result<String> {
+        Ok(self.crab.pulls(owner, repo).get_diff(pr_num).await?)
+    }
+
+    async fn get_pr(&self, owner: &str, repo: &str, pr_num: u64) -> Result<PullRequest> {
+        let pr = self.crab.pulls(owner, repo).get(pr_num).await?;
+        let state = if pr.merged_at.is_some() {
+            PullRequestState::Merged
+        } else if pr.state == Some(IssueState::Closed) {
+            PullRequestState::Closed
+        } else {
+            PullRequestState::Open
+        };
+
+        Ok(PullRequest {
+            head_sha: pr.head.sha,
+            description: pr.body.unwrap_or_default(),
+            title: pr.title.unwrap_or_default(),
+            author: pr.user.map(|u| u.login).unwrap_or_default(),
+            state,
+        })
+    }
+
+    async fn get_review_threads(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+    ) -> Result<Vec<ReviewThread>> {
+        let mut threads = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let variables = json!({
+                "owner": owner,
+                "repo": repo,
+                "number": pr_num,
+                "cursor": cursor,
+            });
+            let data = self
+                .graphql(REVIEW_THREADS_QUERY, variables)
+                .await
+                .context("Failed to query review threads")?;
+
+            let page: GqlThreads =
+                serde_json::from_value(data["repository"]["pullRequest"]["reviewThreads"].clone())
+                    .context("Failed to parse review threads")?;
+            threads.extend(
+                page.nodes
+                    .into_iter()
+                    .filter_map(GqlThread::into_review_thread),
+            );
+
+            if !page.page_info.has_next_page {
+                break;
+            }
+            cursor = page.page_info.end_cursor;
+        }
+
+        Ok(threads)
+    }
+
+    async fn get_commits(&self, owner: &str, repo: &str, pr_num: u64) -> Result<Vec<Commit>> {
+        let mut gh_commits: Vec<GhCommit> = Vec::new();
+        for page in 1u32.. {
+            let route = format!("/repos/{}/{}/pulls/{}/commits", owner, repo, pr_num);
+            let params = json!({ "per_page": 100, "page": page });
+            let commits: Vec<GhCommit> = self.crab.get(route, Some(&params)).await?;
+            let done = commits.len() < 100;
+            gh_commits.extend(commits);
+            if done {
+                break;
+            }
+        }
+
+        let mut commits = Vec::with_capacity(gh_commits.len());
+        for c in gh_commits {
+            let diff = self
+                .get_commit_diff(owner, repo, &c.sha)
+                .await
+                .with_context(|| format!("Failed to fetch diff for commit {}", c.sha))?;
+            commits.push(Commit {
+                author: format!("{} <{}>", c.commit.author.name, c.commit.author.email),
+                date: c.commit.author.date,
+                message: c.commit.message,
+                sha: c.sha,
+                diff,
+            });
+        }
+
+        Ok(commits)
+    }
+
+    async fn search_prs(&self, query: &str) -> Result<Vec<PullRequestSummary>> {
+        let mut prs = Vec::new();
+        for page in 1u32.. {
+            let params = json!({ "q": query, "per_page": 100, "page": page });
+            let results: GhSearchResults = self
+                .crab
+                .get("/search/issues", Some(&params))
+                .await
+                .context("Failed to search pull requests")?;
+            let done = results.items.len() < 100;
+            for issue in results.items {
+                if issue.pull_request.is_none() {
+                    continue;
+                }
+                let mut url = issue.repository_url.rsplit('/');
+                let (Some(repo), Some(owner)) = (url.next(), url.next()) else {
+                    bail!("Unexpected repository URL: {}", issue.repository_url);
+                };
+                prs.push(PullRequestSummary {
+                    owner: owner.to_string(),
+                    repo: repo.to_string(),
+                    pr_num: issue.number,
+                    title: issue.title,
+                });
+            }
+            if done {
+                break;
+            }
+        }
+
+        Ok(prs)
+    }
+
+    async fn submit_review(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        review: &ReviewSubmission<'_>,
+    ) -> Result<Option<String>> {
+        let body = review_body(review);
+        let path = format!("repos/{}/{}/pulls/{}/reviews", owner, repo, pr_num);
+        self.post_created(path, &body, StatusCode::OK).await
+    }
+
+    async fn submit_pending_review(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        review: &ReviewSubmission<'_>,
+    ) -> Result<String> {
+        // Leaving out the event is what keeps a review pending
+        let mut body = review_body(review);
+        body.as_object_mut().unwrap().remove("event");
+
+        let path = format!("repos/{}/{}/pulls/{}/reviews", owner, repo, pr_num);
+        let text = self.post(path, &body, StatusCode::OK).await?;
+        created_id(&text).ok_or_else(|| anyhow!("Failed to parse created review"))
+    }
+
+    async fn finalize_review(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        id: &str,
+        action: &ReviewAction,
+        body: &str,
+    ) -> Result<()> {
+        let body = json!({
+            "body": body,
+            "event": review_event(action),
+        });
+        let path = format!(
+            "repos/{}/{}/pulls/{}/reviews/{}/events",
+            owner, repo, pr_num, id
+        );
+        self.post(path, &body, StatusCode::OK).await?;
+
+        Ok(())
+    }
+
+    async fn submit_file_comment(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        commit_id: &str,
+        comment: &FileComment,
+    ) -> Result<Option<String>> {
+        let body = json!({
+            "body": comment.comment,
+            "commit_id": commit_id,
+            "path": comment.file,
+            "subject_type": "file",
+        });
+        let path = format!("repos/{}/{}/pulls/{}/comments", owner, repo, pr_num);
+        self.post_created(path, &body, StatusCode::CREATED).await
+    }
+
+    async fn submit_thread_reply(
+        &self,
+        _owner: &str,
+        _repo: &str,
+        _pr_num: u64,
+        reply: &ThreadReply,
+    ) -> Result<Option<String>> {
+        let mut id = None;
+        if !reply.comment.is_empty() {
+            let variables = json!({
+                "thread": reply.thread,
+                "body": reply.comment,
+            });
+            let data = self.graphql(ADD_THREAD_REPLY_MUTATION, variables).await?;
+            id = data["addPullRequestReviewThreadReply"]["comment"]["id"]
+                .as_str()
+                .map(str::to_string);
+        }
+
+        if reply.resolve {
+            let variables = json!({ "thread": reply.thread });
+            self.graphql(RESOLVE_THREAD_MUTATION, variables).await?;
+        }
+
+        Ok(id)
+    }
+
+    async fn submit_commit_comment(
+        &self,
+        owner: &str,
+        repo: &str,
+        _pr_num: u64,
+        post: &CommitPost,
+    ) -> Result<Option<String>> {
+        let mut body = json!({ "body": post.body });
+        if let Some(path) = &post.path {
+            body["path"] = json!(path);
+        }
+        if let Some(position) = post.position {
+            body["position"] = json!(position);
+        }
+
+        let path = format!("repos/{}/{}/commits/{}/comments", owner, repo, post.commit);
+        self.post_created(path, &body, StatusCode::CREATED).await
+    }
+
+    async fn get_review_comments(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        id: &str,
+    ) -> Result<Vec<PostedComment>> {
+        let id: u64 = id.parse().context("Invalid review ID")?;
+        let mut posted = Vec::new();
+        for page in 1u32.. {
+            let route = format!("/repos/{}/{}/pulls/{}/comments", owner, repo, pr_num);
+            let params = json!({ "per_page": 100, "page": page });
+            let comments: Vec<GhReviewComment> = self.crab.get(route, Some(&params)).await?;
+            let done = comments.len() < 100;
+            for c in comments {
+                // File-level comments have no line
+                let Some(line) = c.original_line else {
+                    continue;
+                };
+                if c.pull_request_review_id != Some(id) {
+                    continue;
+                }
+                let location = |line, side: Option<&str>| match side {
+                    Some("LEFT") => LineLocation::Left(line),
+                    _ => LineLocation::Right(line),
+                };
+                posted.push(PostedComment {
+                    id: c.id.to_string(),
+                    file: c.path,
+                    line: location(line, c.side.as_deref()),
+                    start_line: c
+                        .original_start_line
+                        .map(|l| location(l, c.start_side.as_deref())),
+                });
+            }
+            if done {
+                break;
+            }
+        }
+
+        Ok(posted)
+    }
+
+    async fn edit_review(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        id: &str,
+        body: &str,
+    ) -> Result<()> {
+        let path = format!("repos/{}/{}/pulls/{}/reviews/{}", owner, repo, pr_num, id);
+        let body = json!({ "body": body });
+        self.send(Method::PUT, path, Some(&body), StatusCode::OK)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn edit_comment(&self, owner: &str, repo: &str, id: &str, body: &str) -> Result<()> {
+        let path = format!("repos/{}/{}/pulls/comments/{}", owner, repo, id);
+        let body = json!({ "body": body });
+        self.send(Method::PATCH, path, Some(&body), StatusCode::OK)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn delete_comment(&self, owner: &str, repo: &str, id: &str) -> Result<()> {
+        let path = format!("repos/{}/{}/pulls/comments/{}", owner, repo, id);
+        self.send(Method::DELETE, path, None, StatusCode::NO_CONTENT)
+            .await?;
+
+        Ok(())
+    }
+}
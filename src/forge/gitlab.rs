@@ -0,0 +1,751 @@
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use reqwest::Method;
+use serde::de::DeserializeOwned;
+use serde_derive::Deserialize;
+use serde_json::json;
+
+use super::rest::{response_id, Rest};
+use super::{Forge, PullRequest, PullRequestState, ReviewSubmission};
+use crate::commits::{Commit, CommitPost};
+use crate::parser::{diff_line_numbers, FileComment, LineLocation, ReviewAction, ThreadReply};
+use crate::review::{ReviewThread, ThreadComment};
+
+/// Number of items to request per page from paginated endpoints
+const PER_PAGE: usize = 100;
+
+/// Summary note to leave when requesting changes without a review comment
+const CHANGES_REQUESTED: &str = "Changes requested.";
+
+/// The commits a position on a merge request version is relative to
+#[derive(Debug)]
+struct GlDiffRefs {
+    base_sha: String,
+    head_sha: String,
+    start_sha: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct GlMergeRequest {
+    title: String,
+    description: Option<String>,
+    author: GlUser,
+    /// One of `opened`, `closed`, `locked` or `merged`
+    state: String,
+    sha: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct GlVersion {
+    id: u64,
+    head_commit_sha: String,
+    base_commit_sha: String,
+    start_commit_sha: String,
+    /// Only part of a single version, not of the list of versions
+    #[serde(default)]
+    diffs: Vec<GlDiff>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GlDiff {
+    old_path: String,
+    new_path: String,
+    a_mode: Option<String>,
+    b_mode: Option<String>,
+    diff: String,
+    #[serde(default)]
+    new_file: bool,
+    #[serde(default)]
+    renamed_file: bool,
+    #[serde(default)]
+    deleted_file: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct GlUser {
+    username: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct GlPosition {
+    new_path: String,
+    old_line: Option<u64>,
+    new_line: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GlNote {
+    body: String,
+    author: GlUser,
+    created_at: String,
+    position: Option<GlPosition>,
+    #[serde(default)]
+    resolved: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct GlDiscussion {
+    id: String,
+    notes: Vec<GlNote>,
+}
+
+/// A note that is only visible to its author until it is published
+#[derive(Debug, Deserialize)]
+struct GlDraftNote {
+    note: String,
+    position: Option<GlDraftPosition>,
+}
+
+/// Where a draft note is, as far as telling drafts apart goes
+#[derive(Debug, Default, Deserialize, PartialEq)]
+struct GlDraftPosition {
+    new_path: Option<String>,
+    old_line: Option<u64>,
+    new_line: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GlApprovals {
+    user_has_approved: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct GlCommit {
+    id: String,
+    author_name: String,
+    author_email: String,
+    authored_date: String,
+    message: String,
+}
+
+impl GlDiscussion {
+    /// Converts into a `ReviewThread`, or `None` if the discussion is not on a line
+    fn into_review_thread(self) -> Option<ReviewThread> {
+        let position = self.notes.first()?.position.as_ref()?;
+        let line = match (position.new_line, position.old_line) {
+            (Some(new), _) => LineLocation::Right(new),
+            (None, Some(old)) => LineLocation::Left(old),
+            (None, None) => return None,
+        };
+
+        Some(ReviewThread {
+            file: position.new_path.clone(),
+            line,
+            start_line: None,
+            resolved: self.notes[0].resolved,
+            id: self.id,
+            comments: self
+                .notes
+                .into_iter()
+                .map(|n| ThreadComment {
+                    author: n.author.username,
+                    created_at: n.created_at,
+                    body: n.body,
+                })
+                .collect(),
+        })
+    }
+}
+
+/// Renders the per-file diffs GitLab returns as a single git diff
+fn render_diff(diffs: &[GlDiff]) -> String {
+    let mut out = String::new();
+    for d in diffs {
+        out += &format!("diff --git a/{} b/{}\n", d.old_path, d.new_path);
+        if d.new_file {
+            out += &format!(
+                "new file mode {}\n",
+                d.b_mode.as_deref().unwrap_or("100644")
+            );
+        } else if d.deleted_file {
+            out += &format!(
+                "deleted file mode {}\n",
+                d.a_mode.as_deref().unwrap_or("100644")
+            );
+        } else if d.a_mode != d.b_mode {
+            if let (Some(a), Some(b)) = (&d.a_mode, &d.b_mode) {
+                out += &format!("old mode {a}\nnew mode {b}\n");
+            }
+        }
+        if d.renamed_file {
+            out += &format!("rename from {}\nrename to {}\n", d.old_path, d.new_path);
+        }
+
+        // Pure renames and mode changes have no hunks
+        if !d.diff.is_empty() {
+            let old = if d.new_file {
+                "/dev/null".to_string()
+            } else {
+                format!("a/{}", d.old_path)
+            };
+            let new = if d.deleted_file {
+                "/dev/null".to_string()
+            } else {
+                format!("b/{}", d.new_path)
+            };
+            out += &format!("--- {old}\n+++ {new}\n");
+            out += &d.diff;
+            if !out.ends_with('\n') {
+                out += "\n";
+            }
+        }
+    }
+
+    out
+}
+
+/// Returns the old path of `file` (which is a new path) in `diff`
+fn old_path(diff: &str, file: &str) -> String {
+    diff.lines()
+        .filter_map(|l| l.strip_prefix("diff --git a/"))
+        .filter_map(|l| l.rsplit_once(" b/"))
+        .find(|(_, new)| *new == file)
+        .map(|(old, _)| old.to_string())
+        .unwrap_or_else(|| file.to_string())
+}
+
+/// Returns the old and new line numbers GitLab expects for `line` in `file`
+///
+/// GitLab wants both for unchanged lines, whereas a `LineLocation` only has one.
+fn line_numbers(diff: &str, file: &str, line: &LineLocation) -> (Option<u64>, Option<u64>) {
+    let found = diff_line_numbers(diff)
+        .into_iter()
+        .flatten()
+        .find(|(f, old, new)| {
+            f == file
+                && match line {
+                    LineLocation::Left(l) => *old == Some(*l) && new.is_none(),
+                    LineLocation::Right(l) => *new == Some(*l),
+                }
+        });
+
+    match (found, line) {
+        (Some((_, old, new)), _) => (old, new),
+        (None, LineLocation::Left(l)) => (Some(*l), None),
+        (None, LineLocation::Right(l)) => (None, Some(*l)),
+    }
+}
+
+/// GitLab merge request forge
+pub struct GitLab {
+    rest: Rest,
+    /// Base URL of the REST API, eg. `https://gitlab.com/api/v4`
+    api: String,
+}
+
+impl GitLab {
+    /// Creates a client for the GitLab instance at `url` authenticating with `token`
+    pub fn new(url: &str, token: String) -> Result<GitLab> {
+        Ok(GitLab {
+            rest: Rest::new("PRIVATE-TOKEN", token)?,
+            api: format!("{}/api/v4", url.trim_end_matches('/')),
+        })
+    }
+
+    /// Returns the API URL of a project. Nested groups are part of `owner`.
+    fn project_url(&self, owner: &str, repo: &str) -> String {
+        let id = format!("{owner}/{repo}").replace('/', "%2F");
+        format!("{}/projects/{}", self.api, id)
+    }
+
+    fn mr_url(&self, owner: &str, repo: &str, pr_num: u64) -> String {
+        format!(
+            "{}/merge_requests/{}",
+            self.project_url(owner, repo),
+            pr_num
+        )
+    }
+
+    /// Fetches every page of a paginated endpoint
+    async fn get_all<T: DeserializeOwned>(&self, url: &str) -> Result<Vec<T>> {
+        self.rest.get_pages(url, "per_page", PER_PAGE).await
+    }
+
+    /// Returns the diff refs and the diff of the merge request version with
+    /// head `commit_id`
+    ///
+    /// Comments are positioned against the version that was reviewed, in terms
+    /// of that version's diff. If it cannot be found, the latest one is used.
+    async fn reviewed_version(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        commit_id: Option<&str>,
+    ) -> Result<(GlDiffRefs, String)> {
+        let mr_url = self.mr_url(owner, repo, pr_num);
+        let versions: Vec<GlVersion> = self
+            .get_all(&format!("{mr_url}/versions"))
+            .await
+            .context("Failed to fetch merge request versions")?;
+        // Versions are listed newest first
+        let reviewed = commit_id.and_then(|c| versions.iter().find(|v| v.head_commit_sha == c));
+        let Some(version) = reviewed.or(versions.first()) else {
+            bail!("Merge request has no versions");
+        };
+
+        let v: GlVersion = self
+            .rest
+            .get(&format!("{mr_url}/versions/{}", version.id))
+            .await
+            .context("Failed to fetch merge request version")?;
+        let refs = GlDiffRefs {
+            base_sha: v.base_commit_sha,
+            head_sha: v.head_commit_sha,
+            start_sha: v.start_commit_sha,
+        };
+
+        Ok((refs, render_diff(&v.diffs)))
+    }
+}
+
+#[async_trait]
+impl Forge for GitLab {
+    async fn get_diff(&self, owner: &str, repo: &str, pr_num: u64) -> Result<String> {
+        let url = format!("{}/diffs", self.mr_url(owner, repo, pr_num));
+        let diffs: Vec<GlDiff> = self.get_all(&url).await?;
+
+        Ok(render_diff(&diffs))
+    }
+
+    async fn get_pr(&self, owner: &str, repo: &str, pr_num: u64) -> Result<PullRequest> {
+        let mr: GlMergeRequest = self.rest.get(&self.mr_url(owner, repo, pr_num)).await?;
+
+        let state = match mr.state.as_str() {
+            "merged" => PullRequestState::Merged,
+            "closed" => PullRequestState::Closed,
+            _ => PullRequestState::Open,
+        };
+
+        Ok(PullRequest {
+            head_sha: mr.sha,
+            description: mr.description.unwrap_or_default(),
+            title: mr.title,
+            author: mr.author.username,
+            state,
+        })
+    }
+
+    async fn get_review_threads(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+    ) -> Result<Vec<ReviewThread>> {
+        let url = format!("{}/discussions", self.mr_url(owner, repo, pr_num));
+        let discussions: Vec<GlDiscussion> = self.get_all(&url).await?;
+
+        Ok(discussions
+            .into_iter()
+            .filter_map(GlDiscussion::into_review_thread)
+            .collect())
+    }
+
+    async fn get_commits(&self, owner: &str, repo: &str, pr_num: u64) -> Result<Vec<Commit>> {
+        let url = format!("{}/commits", self.mr_url(owner, repo, pr_num));
+        let mut gl_commits: Vec<GlCommit> = self.get_all(&url).await?;
+        // GitLab lists the newest commit first
+        gl_commits.reverse();
+
+        let mut commits = Vec::with_capacity(gl_commits.len());
+        for c in gl_commits {
+            let url = format!(
+                "{}/repository/commits/{}/diff",
+                self.project_url(owner, repo),
+                c.id
+            );
+            let diffs: Vec<GlDiff> = self
+                .get_all(&url)
+                .await
+                .with_context(|| format!("Failed to fetch diff for commit {}", c.id))?;
+            commits.push(Commit {
+                author: format!("{} <{}>", c.author_name, c.author_email),
+                date: c.authored_date,
+                message: c.message,
+                sha: c.id,
+                diff: render_diff(&diffs),
+            });
+        }
+
+        Ok(commits)
+    }
+
+    async fn submit_review(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        review: &ReviewSubmission<'_>,
+    ) -> Result<Option<String>> {
+        let mr_url = self.mr_url(owner, repo, pr_num);
+
+        // Everything is posted as draft notes and published in one go, so a
+        // submission that fails partway leaves nothing but drafts behind.
+        // Drafts left by an earlier attempt are not posted again.
+        let drafts: Vec<GlDraftNote> = self
+            .rest
+            .get(&format!("{mr_url}/draft_notes"))
+            .await
+            .context("Failed to fetch draft notes")?;
+        let mut notes = Vec::new();
+        if !review.comments.is_empty() {
+            let (refs, diff) = self
+                .reviewed_version(owner, repo, pr_num, review.commit_id)
+                .await?;
+            for c in review.comments {
+                let (old_line, new_line) = line_numbers(&diff, &c.file, &c.line);
+                let body = json!({
+                    "note": c.comment,
+                    "position": {
+                        "position_type": "text",
+                        "base_sha": refs.base_sha,
+                        "head_sha": refs.head_sha,
+                        "start_sha": refs.start_sha,
+                        "old_path": old_path(&diff, &c.file),
+                        "new_path": c.file,
+                        "old_line": old_line,
+                        "new_line": new_line,
+                    },
+                });
+                let position = GlDraftPosition {
+                    new_path: Some(c.file.clone()),
+                    old_line,
+                    new_line,
+                };
+                notes.push((body, position));
+            }
+        }
+
+        // The review comment stands in for the review. Published drafts start
+        // discussions, which stay unresolved until dealt with.
+        let summary = match review.action {
+            ReviewAction::RequestChanges if review.body.is_empty() => CHANGES_REQUESTED,
+            _ => review.body,
+        };
+        if !summary.is_empty() {
+            notes.push((json!({ "note": summary }), GlDraftPosition::default()));
+        }
+
+        for (body, position) in &notes {
+            let drafted = drafts.iter().any(|d| {
+                d.note == body["note"]
+                    && d.position.as_ref().unwrap_or(&Default::default()) == position
+            });
+            if drafted {
+                continue;
+            }
+            self.rest
+                .post(&format!("{mr_url}/draft_notes"), body)
+                .await
+                .with_context(|| match &position.new_path {
+                    Some(file) => format!("Failed to comment on {file}"),
+                    None => "Failed to post review comment".to_string(),
+                })?;
+        }
+
+        // Approve before publishing so that publishing is the last step
+        if *review.action == ReviewAction::Approve {
+            let approvals: GlApprovals = self
+                .rest
+                .get(&format!("{mr_url}/approvals"))
+                .await
+                .context("Failed to fetch approvals")?;
+            if !approvals.user_has_approved {
+                self.rest
+                    .post(&format!("{mr_url}/approve"), &json!({}))
+                    .await
+                    .context("Failed to approve")?;
+            }
+        }
+
+        if !notes.is_empty() {
+            self.rest
+                .post(&format!("{mr_url}/draft_notes/bulk_publish"), &json!({}))
+                .await
+                .context("Failed to publish draft notes")?;
+        }
+
+        // Published notes do not keep the ID of their draft
+        Ok(None)
+    }
+
+    async fn submit_file_comment(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        commit_id: &str,
+        comment: &FileComment,
+    ) -> Result<Option<String>> {
+        let (refs, diff) = self
+            .reviewed_version(owner, repo, pr_num, Some(commit_id))
+            .await?;
+        let body = json!({
+            "body": comment.comment,
+            "position": {
+                "position_type": "file",
+                "base_sha": refs.base_sha,
+                "head_sha": refs.head_sha,
+                "start_sha": refs.start_sha,
+                "old_path": old_path(&diff, &comment.file),
+                "new_path": comment.file,
+            },
+        });
+        let url = format!("{}/discussions", self.mr_url(owner, repo, pr_num));
+        let resp = self.rest.post(&url, &body).await?;
+
+        Ok(response_id(&resp))
+    }
+
+    async fn submit_thread_reply(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        reply: &ThreadReply,
+    ) -> Result<Option<String>> {
+        let url = format!(
+            "{}/discussions/{}",
+            self.mr_url(owner, repo, pr_num),
+            reply.thread
+        );
+        let mut id = None;
+        if !reply.comment.is_empty() {
+            let resp = self
+                .rest
+                .post(&format!("{url}/notes"), &json!({ "body": reply.comment }))
+                .await?;
+            id = response_id(&resp);
+        }
+
+        if reply.resolve {
+            self.rest
+                .send_json(Method::PUT, &url, &json!({ "resolved": true }))
+                .await?;
+        }
+
+        Ok(id)
+    }
+
+    async fn submit_commit_comment(
+        &self,
+        owner: &str,
+        repo: &str,
+        _pr_num: u64,
+        post: &CommitPost,
+    ) -> Result<Option<String>> {
+        let mut body = json!({ "note": post.body });
+        if let Some(path) = &post.path {
+            body["path"] = json!(path);
+        }
+        let url = format!(
+            "{}/repository/commits/{}/comments",
+            self.project_url(owner, repo),
+            post.commit
+        );
+        // Commit comments have no ID
+        self.rest.post(&url, &body).await?;
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::InlineComment;
+    use mockito::{Matcher, Server};
+    use pretty_assertions::assert_eq as assert_eq_pretty;
+
+    const MR: &str = "/api/v4/projects/group%2Fsub%2Fproject/merge_requests/7";
+
+    fn diffs_mock(server: &mut mockito::ServerGuard) -> mockito::Mock {
+        server
+            .mock("GET", format!("{MR}/diffs").as_str())
+            .match_query(Matcher::Any)
+            .match_header("PRIVATE-TOKEN", "secret")
+            .with_body(include_str!("../../testdata/gitlab/diffs.json"))
+            .create()
+    }
+
+    #[tokio::test]
+    async fn test_get_diff() {
+        let mut server = Server::new_async().await;
+        let mock = diffs_mock(&mut server);
+        let gitlab = GitLab::new(&server.url(), "secret".to_string()).unwrap();
+
+        let diff = gitlab.get_diff("group/sub", "project", 7).await.unwrap();
+        assert_eq_pretty!(diff, include_str!("../../testdata/gitlab/diff"));
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_get_review_threads() {
+        let mut server = Server::new_async().await;
+        let mock = server
+            .mock("GET", format!("{MR}/discussions").as_str())
+            .match_query(Matcher::Any)
+            .with_body(include_str!("../../testdata/gitlab/discussions.json"))
+            .create();
+        let gitlab = GitLab::new(&server.url(), "secret".to_string()).unwrap();
+
+        let threads = gitlab
+            .get_review_threads("group/sub", "project", 7)
+            .await
+            .unwrap();
+        mock.assert();
+
+        // The general (non-diff) discussion is skipped
+        assert_eq!(threads.len(), 1);
+        assert_eq!(threads[0].id, "6a9c1750b37d513a43987b574953fceb50b03ce7");
+        assert_eq!(threads[0].file, "ch1.txt");
+        assert_eq!(threads[0].line, LineLocation::Right(3));
+        assert!(!threads[0].resolved);
+        assert_eq!(threads[0].comments.len(), 2);
+        assert_eq!(threads[0].comments[1].author, "bob");
+    }
+
+    #[tokio::test]
+    async fn test_submit_review() {
+        let mut server = Server::new_async().await;
+        let versions = server
+            .mock("GET", format!("{MR}/versions").as_str())
+            .match_query(Matcher::Any)
+            .with_body(
+                r#"[
+                    {"id": 2, "head_commit_sha": "new", "base_commit_sha": "b2", "start_commit_sha": "s2"},
+                    {"id": 1, "head_commit_sha": "abc", "base_commit_sha": "base", "start_commit_sha": "start"}
+                ]"#,
+            )
+            .create();
+        // Positions are in terms of the reviewed version, not the latest one
+        let version = server
+            .mock("GET", format!("{MR}/versions/1").as_str())
+            .with_body(format!(
+                r#"{{"id": 1, "head_commit_sha": "abc", "base_commit_sha": "base", "start_commit_sha": "start", "diffs": {}}}"#,
+                include_str!("../../testdata/gitlab/diffs.json")
+            ))
+            .create();
+        let latest = server
+            .mock("GET", format!("{MR}/versions/2").as_str())
+            .expect(0)
+            .create();
+        // Left behind by an earlier attempt that failed
+        let drafts = server
+            .mock("GET", format!("{MR}/draft_notes").as_str())
+            .with_body(
+                r#"[{"id": 1, "note": "Context", "position": {"new_path": "ch1.txt", "old_line": 3, "new_line": 4}}]"#,
+            )
+            .create();
+        let context_line = server
+            .mock("POST", format!("{MR}/draft_notes").as_str())
+            .match_body(Matcher::PartialJson(json!({ "note": "Context" })))
+            .expect(0)
+            .create();
+        let removed_line = server
+            .mock("POST", format!("{MR}/draft_notes").as_str())
+            .match_body(Matcher::PartialJson(json!({
+                "note": "Removed",
+                "position": {
+                    "position_type": "text",
+                    "base_sha": "base",
+                    "head_sha": "abc",
+                    "start_sha": "start",
+                    "old_path": "old.txt",
+                    "new_path": "new.txt",
+                    "old_line": 1,
+                    "new_line": null,
+                },
+            })))
+            .with_status(201)
+            .with_body("{}")
+            .create();
+        let summary = server
+            .mock("POST", format!("{MR}/draft_notes").as_str())
+            .match_body(Matcher::Json(json!({ "note": "LGTM" })))
+            .with_status(201)
+            .with_body("{}")
+            .create();
+        let approvals = server
+            .mock("GET", format!("{MR}/approvals").as_str())
+            .with_body(r#"{"user_has_approved": false}"#)
+            .create();
+        let approve = server
+            .mock("POST", format!("{MR}/approve").as_str())
+            .with_status(201)
+            .with_body("{}")
+            .create();
+        let publish = server
+            .mock("POST", format!("{MR}/draft_notes/bulk_publish").as_str())
+            .with_status(204)
+            .create();
+        let gitlab = GitLab::new(&server.url(), "secret".to_string()).unwrap();
+
+        let comment = |file: &str, line, comment: &str| InlineComment {
+            file: file.to_string(),
+            line,
+            start_line: None,
+            comment: comment.to_string(),
+            commit: None,
+        };
+        let comments = vec![
+            comment("ch1.txt", LineLocation::Right(4), "Context"),
+            comment("new.txt", LineLocation::Left(1), "Removed"),
+        ];
+        let review = ReviewSubmission {
+            action: &ReviewAction::Approve,
+            body: "LGTM",
+            comments: &comments,
+            commit_id: Some("abc"),
+        };
+        gitlab
+            .submit_review("group/sub", "project", 7, &review)
+            .await
+            .unwrap();
+
+        versions.assert();
+        version.assert();
+        latest.assert();
+        drafts.assert();
+        context_line.assert();
+        removed_line.assert();
+        summary.assert();
+        approvals.assert();
+        approve.assert();
+        publish.assert();
+    }
+
+    #[tokio::test]
+    async fn test_request_changes() {
+        let mut server = Server::new_async().await;
+        let drafts = server
+            .mock("GET", format!("{MR}/draft_notes").as_str())
+            .with_body("[]")
+            .create();
+        let summary = server
+            .mock("POST", format!("{MR}/draft_notes").as_str())
+            .match_body(Matcher::Json(json!({ "note": CHANGES_REQUESTED })))
+            .with_status(201)
+            .with_body("{}")
+            .create();
+        let publish = server
+            .mock("POST", format!("{MR}/draft_notes/bulk_publish").as_str())
+            .with_status(204)
+            .create();
+        let gitlab = GitLab::new(&server.url(), "secret".to_string()).unwrap();
+
+        let review = ReviewSubmission {
+            action: &ReviewAction::RequestChanges,
+            body: "",
+            comments: &[],
+            commit_id: Some("abc"),
+        };
+        gitlab
+            .submit_review("group/sub", "project", 7, &review)
+            .await
+            .unwrap();
+        drafts.assert();
+        summary.assert();
+        publish.assert();
+    }
+}
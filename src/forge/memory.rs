@@ -0,0 +1,295 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+
+use super::{Forge, PostedComment, PullRequest, PullRequestSummary, ReviewSubmission};
+use crate::commits::{Commit, CommitPost};
+use crate::parser::{FileComment, InlineComment, ReviewAction, ThreadReply};
+use crate::review::ReviewThread;
+
+/// A pull request hosted on a `MemoryForge`
+#[derive(Clone)]
+pub struct MemoryPullRequest {
+    pub diff: String,
+    pub pr: PullRequest,
+    pub threads: Vec<ReviewThread>,
+    pub commits: Vec<Commit>,
+}
+
+/// A review submitted to a `MemoryForge`
+#[derive(Debug, PartialEq, Eq)]
+pub struct SubmittedReview {
+    pub id: String,
+    pub pr: (String, String, u64),
+    pub action: ReviewAction,
+    pub body: String,
+    pub comments: Vec<InlineComment>,
+    pub commit_id: Option<String>,
+}
+
+/// Everything submitted to a `MemoryForge`
+#[derive(Debug, Default)]
+pub struct Submitted {
+    pub reviews: Vec<SubmittedReview>,
+    /// Reviews that are not finalized yet, by ID
+    pub pending: Vec<(String, SubmittedReview)>,
+    pub file_comments: Vec<FileComment>,
+    pub thread_replies: Vec<ThreadReply>,
+    pub commit_comments: Vec<CommitPost>,
+    /// Comments edited after they were posted, as (ID, new text)
+    pub edited_comments: Vec<(String, String)>,
+    /// IDs of comments deleted after they were posted
+    pub deleted_comments: Vec<String>,
+}
+
+/// Forge that keeps everything in memory, for tests
+#[derive(Default)]
+pub struct MemoryForge {
+    prs: HashMap<(String, String, u64), MemoryPullRequest>,
+    submitted: Arc<Mutex<Submitted>>,
+    /// Files that file comments fail to be submitted on
+    failing_files: Arc<Mutex<HashSet<String>>>,
+    /// Search results, by query
+    searches: HashMap<String, Vec<PullRequestSummary>>,
+}
+
+impl MemoryForge {
+    /// Hosts `pr` as `owner/repo/pr_num`
+    pub fn add_pr(&mut self, owner: &str, repo: &str, pr_num: u64, pr: MemoryPullRequest) {
+        self.prs
+            .insert((owner.to_string(), repo.to_string(), pr_num), pr);
+    }
+
+    /// Makes searching for `query` return `prs`
+    pub fn add_search(&mut self, query: &str, prs: Vec<PullRequestSummary>) {
+        self.searches.insert(query.to_string(), prs);
+    }
+
+    /// Returns a handle to everything submitted to the forge. The handle stays
+    /// valid after the forge is handed off.
+    pub fn submitted(&self) -> Arc<Mutex<Submitted>> {
+        self.submitted.clone()
+    }
+
+    /// Returns a handle to the set of files that file comments fail to be
+    /// submitted on. The handle stays valid after the forge is handed off.
+    pub fn failing_files(&self) -> Arc<Mutex<HashSet<String>>> {
+        self.failing_files.clone()
+    }
+
+    fn pr(&self, owner: &str, repo: &str, pr_num: u64) -> Result<&MemoryPullRequest> {
+        self.prs
+            .get(&(owner.to_string(), repo.to_string(), pr_num))
+            .ok_or_else(|| anyhow!("No such pull request: {}/{}/{}", owner, repo, pr_num))
+    }
+}
+
+#[async_trait]
+impl Forge for MemoryForge {
+    async fn get_diff(&self, owner: &str, repo: &str, pr_num: u64) -> Result<String> {
+        Ok(self.pr(owner, repo, pr_num)?.diff.clone())
+    }
+
+    async fn get_pr(&self, owner: &str, repo: &str, pr_num: u64) -> Result<PullRequest> {
+        Ok(self.pr(owner, repo, pr_num)?.pr.clone())
+    }
+
+    async fn get_review_threads(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+    ) -> Result<Vec<ReviewThread>> {
+        Ok(self.pr(owner, repo, pr_num)?.threads.clone())
+    }
+
+    async fn get_commits(&self, owner: &str, repo: &str, pr_num: u64) -> Result<Vec<Commit>> {
+        Ok(self.pr(owner, repo, pr_num)?.commits.clone())
+    }
+
+    async fn search_prs(&self, query: &str) -> Result<Vec<PullRequestSummary>> {
+        Ok(self.searches.get(query).cloned().unwrap_or_default())
+    }
+
+    async fn submit_review(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        review: &ReviewSubmission<'_>,
+    ) -> Result<Option<String>> {
+        self.pr(owner, repo, pr_num)?;
+        let mut submitted = self.submitted.lock().unwrap();
+        let id = format!("review-{}", submitted.reviews.len() + 1);
+        submitted.reviews.push(SubmittedReview {
+            id: id.clone(),
+            pr: (owner.to_string(), repo.to_string(), pr_num),
+            action: review.action.clone(),
+            body: review.body.to_string(),
+            comments: review.comments.to_vec(),
+            commit_id: review.commit_id.map(|c| c.to_string()),
+        });
+
+        Ok(Some(id))
+    }
+
+    async fn submit_pending_review(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        review: &ReviewSubmission<'_>,
+    ) -> Result<String> {
+        self.pr(owner, repo, pr_num)?;
+        let mut submitted = self.submitted.lock().unwrap();
+        let id = format!("pending-{}", submitted.pending.len() + 1);
+        submitted.pending.push((
+            id.clone(),
+            SubmittedReview {
+                id: id.clone(),
+                pr: (owner.to_string(), repo.to_string(), pr_num),
+                action: review.action.clone(),
+                body: review.body.to_string(),
+                comments: review.comments.to_vec(),
+                commit_id: review.commit_id.map(|c| c.to_string()),
+            },
+        ));
+
+        Ok(id)
+    }
+
+    async fn finalize_review(
+        &self,
+        _owner: &str,
+        _repo: &str,
+        _pr_num: u64,
+        id: &str,
+        action: &ReviewAction,
+        body: &str,
+    ) -> Result<()> {
+        let mut submitted = self.submitted.lock().unwrap();
+        let idx = submitted
+            .pending
+            .iter()
+            .position(|(i, _)| i == id)
+            .ok_or_else(|| anyhow!("No such pending review: {}", id))?;
+        let (_, mut review) = submitted.pending.remove(idx);
+        review.action = action.clone();
+        review.body = body.to_string();
+        submitted.reviews.push(review);
+
+        Ok(())
+    }
+
+    async fn submit_file_comment(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        _commit_id: &str,
+        comment: &FileComment,
+    ) -> Result<Option<String>> {
+        self.pr(owner, repo, pr_num)?;
+        if self.failing_files.lock().unwrap().contains(&comment.file) {
+            return Err(anyhow!("Failed to comment on {}", comment.file));
+        }
+        let mut submitted = self.submitted.lock().unwrap();
+        submitted.file_comments.push(comment.clone());
+
+        Ok(Some(format!("file-{}", submitted.file_comments.len())))
+    }
+
+    async fn submit_thread_reply(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        reply: &ThreadReply,
+    ) -> Result<Option<String>> {
+        let pr = self.pr(owner, repo, pr_num)?;
+        if !pr.threads.iter().any(|t| t.id == reply.thread) {
+            return Err(anyhow!("No such thread: {}", reply.thread));
+        }
+        let mut submitted = self.submitted.lock().unwrap();
+        submitted.thread_replies.push(reply.clone());
+
+        Ok(Some(format!("reply-{}", submitted.thread_replies.len())))
+    }
+
+    async fn submit_commit_comment(
+        &self,
+        _owner: &str,
+        _repo: &str,
+        _pr_num: u64,
+        post: &CommitPost,
+    ) -> Result<Option<String>> {
+        let mut submitted = self.submitted.lock().unwrap();
+        submitted.commit_comments.push(post.clone());
+
+        Ok(Some(format!("commit-{}", submitted.commit_comments.len())))
+    }
+
+    async fn get_review_comments(
+        &self,
+        _owner: &str,
+        _repo: &str,
+        _pr_num: u64,
+        id: &str,
+    ) -> Result<Vec<PostedComment>> {
+        let submitted = self.submitted.lock().unwrap();
+        let review = submitted
+            .reviews
+            .iter()
+            .find(|r| r.id == id)
+            .ok_or_else(|| anyhow!("No such review: {}", id))?;
+
+        Ok(review
+            .comments
+            .iter()
+            .enumerate()
+            .map(|(idx, c)| PostedComment {
+                id: format!("{id}-comment-{idx}"),
+                file: c.file.clone(),
+                line: c.line.clone(),
+                start_line: c.start_line.clone(),
+            })
+            .collect())
+    }
+
+    async fn edit_review(
+        &self,
+        _owner: &str,
+        _repo: &str,
+        _pr_num: u64,
+        id: &str,
+        body: &str,
+    ) -> Result<()> {
+        let mut submitted = self.submitted.lock().unwrap();
+        let review = submitted
+            .reviews
+            .iter_mut()
+            .find(|r| r.id == id)
+            .ok_or_else(|| anyhow!("No such review: {}", id))?;
+        review.body = body.to_string();
+
+        Ok(())
+    }
+
+    async fn edit_comment(&self, _owner: &str, _repo: &str, id: &str, body: &str) -> Result<()> {
+        let mut submitted = self.submitted.lock().unwrap();
+        submitted
+            .edited_comments
+            .push((id.to_string(), body.to_string()));
+
+        Ok(())
+    }
+
+    async fn delete_comment(&self, _owner: &str, _repo: &str, id: &str) -> Result<()> {
+        let mut submitted = self.submitted.lock().unwrap();
+        submitted.deleted_comments.push(id.to_string());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,250 @@
+use std::fmt::{Display, Formatter, Result as fmt_result};
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::commits::{Commit, CommitPost};
+use crate::parser::{FileComment, InlineComment, LineLocation, ReviewAction, ThreadReply};
+use crate::review::ReviewThread;
+
+mod forgejo;
+mod gerrit;
+mod github;
+mod gitlab;
+#[cfg(test)]
+pub mod memory;
+mod rest;
+
+pub use forgejo::Forgejo;
+pub use gerrit::{gerrit_owner_repo, gerrit_project, Gerrit, GERRIT_OWNER};
+pub use github::GitHub;
+pub use gitlab::GitLab;
+
+/// The kinds of forges prr can talk to
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ForgeKind {
+    #[default]
+    GitHub,
+    GitLab,
+    /// Forgejo or Gitea, which share an API
+    #[serde(alias = "gitea")]
+    Forgejo,
+    Gerrit,
+}
+
+impl Display for ForgeKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt_result {
+        let text = match self {
+            Self::GitHub => "GitHub",
+            Self::GitLab => "GitLab",
+            Self::Forgejo => "Forgejo",
+            Self::Gerrit => "Gerrit",
+        };
+
+        write!(f, "{text}")
+    }
+}
+
+/// Whether a pull request can still be reviewed
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PullRequestState {
+    #[default]
+    Open,
+    Merged,
+    /// Closed without being merged
+    Closed,
+}
+
+impl Display for PullRequestState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt_result {
+        match self {
+            Self::Open => write!(f, "open"),
+            Self::Merged => write!(f, "merged"),
+            Self::Closed => write!(f, "closed"),
+        }
+    }
+}
+
+/// Pull request metadata
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PullRequest {
+    /// Commit hash of the head of the pull request
+    pub head_sha: String,
+    /// Pull request description. Empty if there is none
+    pub description: String,
+    /// Pull request title
+    pub title: String,
+    /// Login of whoever opened the pull request
+    pub author: String,
+    pub state: PullRequestState,
+}
+
+/// A pull request found by a search
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PullRequestSummary {
+    /// Owner of the repository, in the forge's terms
+    pub owner: String,
+    /// Name of the repository
+    pub repo: String,
+    /// Number of the pull request
+    pub pr_num: u64,
+    /// Title of the pull request
+    pub title: String,
+}
+
+/// A review to be submitted on a pull request
+#[derive(Debug, PartialEq, Eq)]
+pub struct ReviewSubmission<'a> {
+    /// Overall review action
+    pub action: &'a ReviewAction,
+    /// Overall review comment. Empty if there is none
+    pub body: &'a str,
+    /// Comments attached to lines of the diff
+    pub comments: &'a [InlineComment],
+    /// Commit the review was made against, if known
+    pub commit_id: Option<&'a str>,
+}
+
+/// A comment posted on a line of the diff as part of a review
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PostedComment {
+    /// Forge-assigned identifier of the comment
+    pub id: String,
+    /// File the comment is in
+    pub file: String,
+    /// Line the comment is attached to
+    pub line: LineLocation,
+    /// First line of the span the comment covers, if it covers more than one
+    pub start_line: Option<LineLocation>,
+}
+
+/// A code forge hosting pull requests, eg. GitHub
+///
+/// This is everything `Prr` needs from the network. Pull requests are identified
+/// by `owner`, `repo` and `pr_num`, whatever those mean for the forge at hand.
+#[async_trait]
+pub trait Forge: Send + Sync {
+    /// Fetches the diff of a pull request
+    async fn get_diff(&self, owner: &str, repo: &str, pr_num: u64) -> Result<String>;
+
+    /// Fetches pull request metadata
+    async fn get_pr(&self, owner: &str, repo: &str, pr_num: u64) -> Result<PullRequest>;
+
+    /// Fetches all existing review threads that are still attached to the diff
+    async fn get_review_threads(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+    ) -> Result<Vec<ReviewThread>>;
+
+    /// Fetches all commits of a pull request, oldest first, along with their diffs
+    async fn get_commits(&self, owner: &str, repo: &str, pr_num: u64) -> Result<Vec<Commit>>;
+
+    /// Searches for open pull requests matching `query`, in the forge's own
+    /// search syntax
+    async fn search_prs(&self, _query: &str) -> Result<Vec<PullRequestSummary>> {
+        bail!("Searching pull requests is not supported by this forge")
+    }
+
+    /// Submits a review
+    ///
+    /// This and the other `submit_*()` methods return the ID the forge assigned
+    /// to what was posted, if it says.
+    async fn submit_review(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        review: &ReviewSubmission<'_>,
+    ) -> Result<Option<String>>;
+
+    /// Submits a review but leaves it pending, ie. only visible to the reviewer
+    /// until it is finalized. Returns the ID of the pending review.
+    async fn submit_pending_review(
+        &self,
+        _owner: &str,
+        _repo: &str,
+        _pr_num: u64,
+        _review: &ReviewSubmission<'_>,
+    ) -> Result<String> {
+        bail!("Pending reviews are not supported by this forge")
+    }
+
+    /// Submits the pending review `id` with `action`
+    async fn finalize_review(
+        &self,
+        _owner: &str,
+        _repo: &str,
+        _pr_num: u64,
+        _id: &str,
+        _action: &ReviewAction,
+        _body: &str,
+    ) -> Result<()> {
+        bail!("Pending reviews are not supported by this forge")
+    }
+
+    /// Submits a comment attached to a whole file
+    async fn submit_file_comment(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        commit_id: &str,
+        comment: &FileComment,
+    ) -> Result<Option<String>>;
+
+    /// Replies to and/or resolves an existing review thread
+    async fn submit_thread_reply(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        reply: &ThreadReply,
+    ) -> Result<Option<String>>;
+
+    /// Posts a comment on a commit of a pull request
+    async fn submit_commit_comment(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        post: &CommitPost,
+    ) -> Result<Option<String>>;
+
+    /// Fetches the comments attached to lines of the diff in the submitted review `id`
+    async fn get_review_comments(
+        &self,
+        _owner: &str,
+        _repo: &str,
+        _pr_num: u64,
+        _id: &str,
+    ) -> Result<Vec<PostedComment>> {
+        bail!("Amending submitted reviews is not supported by this forge")
+    }
+
+    /// Replaces the overall comment of the submitted review `id`
+    async fn edit_review(
+        &self,
+        _owner: &str,
+        _repo: &str,
+        _pr_num: u64,
+        _id: &str,
+        _body: &str,
+    ) -> Result<()> {
+        bail!("Amending submitted reviews is not supported by this forge")
+    }
+
+    /// Replaces the text of the posted comment `id`
+    async fn edit_comment(&self, _owner: &str, _repo: &str, _id: &str, _body: &str) -> Result<()> {
+        bail!("Amending submitted reviews is not supported by this forge")
+    }
+
+    /// Deletes the posted comment `id`
+    async fn delete_comment(&self, _owner: &str, _repo: &str, _id: &str) -> Result<()> {
+        bail!("Amending submitted reviews is not supported by this forge")
+    }
+}
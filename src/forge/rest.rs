@@ -0,0 +1,119 @@
+use anyhow::{bail, Context, Result};
+use reqwest::{Client, Method, RequestBuilder};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+/// Minimal JSON REST client shared by the forges that do not have their own crate
+pub struct Rest {
+    client: Client,
+    /// Name and value of the header carrying the credentials
+    auth: (&'static str, String),
+}
+
+impl Rest {
+    /// Creates a client that sends `auth_value` in the `auth_header` header
+    pub fn new(auth_header: &'static str, auth_value: String) -> Result<Rest> {
+        let client = Client::builder()
+            .user_agent("prr")
+            .build()
+            .context("Failed to create HTTP client")?;
+
+        Ok(Rest {
+            client,
+            auth: (auth_header, auth_value),
+        })
+    }
+
+    /// Returns an authenticated request builder
+    pub fn request(&self, method: Method, url: &str) -> RequestBuilder {
+        self.client
+            .request(method, url)
+            .header(self.auth.0, &self.auth.1)
+    }
+
+    /// Sends a request and returns the response body
+    pub async fn send(&self, req: RequestBuilder) -> Result<String> {
+        let req = req.build().context("Failed to build request")?;
+        let method = req.method().clone();
+        let resp = self
+            .client
+            .execute(req)
+            .await
+            .with_context(|| format!("Failed to send {method} request"))?;
+        let status = resp.status();
+        let text = resp.text().await.context("Failed to read response")?;
+        if !status.is_success() {
+            bail!(
+                "Error during {}: Status code: {}, Body: {}",
+                method,
+                status,
+                text
+            );
+        }
+
+        Ok(text)
+    }
+
+    /// Fetches `url` as plain text
+    pub async fn get_text(&self, url: &str) -> Result<String> {
+        self.send(self.request(Method::GET, url)).await
+    }
+
+    /// Fetches `url` as JSON
+    pub async fn get<T: DeserializeOwned>(&self, url: &str) -> Result<T> {
+        let text = self.get_text(url).await?;
+        serde_json::from_str(&text).context("Failed to parse response")
+    }
+
+    /// Fetches every page of a paginated endpoint
+    ///
+    /// Pages are requested `per_page` items at a time using the `page` query
+    /// parameter and `limit_param`, until a page comes back short.
+    pub async fn get_pages<T: DeserializeOwned>(
+        &self,
+        url: &str,
+        limit_param: &str,
+        per_page: usize,
+    ) -> Result<Vec<T>> {
+        let mut items = Vec::new();
+        for page in 1.. {
+            let req = self.request(Method::GET, url).query(&[
+                ("page", page.to_string()),
+                (limit_param, per_page.to_string()),
+            ]);
+            let text = self.send(req).await?;
+            let page: Vec<T> = serde_json::from_str(&text).context("Failed to parse response")?;
+            let done = page.len() < per_page;
+            items.extend(page);
+            if done {
+                break;
+            }
+        }
+
+        Ok(items)
+    }
+
+    /// Sends `body` as JSON and returns the response, if any
+    pub async fn send_json(&self, method: Method, url: &str, body: &Value) -> Result<Value> {
+        let text = self.send(self.request(method, url).json(body)).await?;
+        if text.is_empty() {
+            return Ok(Value::Null);
+        }
+
+        serde_json::from_str(&text).context("Failed to parse response")
+    }
+
+    /// POSTs `body` as JSON
+    pub async fn post(&self, url: &str, body: &Value) -> Result<Value> {
+        self.send_json(Method::POST, url, body).await
+    }
+}
+
+/// Returns the `id` of a JSON object a forge responded with, if it has one
+pub fn response_id(value: &Value) -> Option<String> {
+    match &value["id"] {
+        Value::String(id) => Some(id.clone()),
+        Value::Number(id) => Some(id.to_string()),
+        _ => None,
+    }
+}
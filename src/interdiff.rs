@@ -0,0 +1,113 @@
+use std::collections::HashSet;
+
+/// A single file in a diff
+struct FileDiff<'a> {
+    /// Lines from `diff --git` up until the first hunk
+    header: Vec<&'a str>,
+    /// Each hunk, starting with its `@@` line
+    hunks: Vec<Vec<&'a str>>,
+}
+
+impl FileDiff<'_> {
+    /// Returns the `diff --git` line, which identifies the file
+    fn name(&self) -> &str {
+        self.header[0]
+    }
+}
+
+/// Splits a diff into files. Anything before the first file is dropped.
+fn split_files(diff: &str) -> Vec<FileDiff<'_>> {
+    let mut files: Vec<FileDiff> = Vec::new();
+    for line in diff.lines() {
+        if line.starts_with("diff --git ") {
+            files.push(FileDiff {
+                header: vec![line],
+                hunks: Vec::new(),
+            });
+            continue;
+        }
+
+        let file = match files.last_mut() {
+            Some(f) => f,
+            None => continue,
+        };
+
+        if line.starts_with("@@ ") {
+            file.hunks.push(vec![line]);
+        } else if let Some(hunk) = file.hunks.last_mut() {
+            hunk.push(line);
+        } else {
+            file.header.push(line);
+        }
+    }
+
+    files
+}
+
+/// Returns the parts of diff `new` that were not already in diff `old`
+///
+/// The comparison is done hunk by hunk. A hunk in `new` is kept if `old` does
+/// not have a hunk in the same file with the same body. Hunk starts are ignored
+/// for the comparison so that hunks which merely moved (eg. after a rebase) are
+/// not considered new. Kept hunks are copied verbatim, hunk starts included, so
+/// line numbers in the result are those of `new`.
+pub fn interdiff(old: &str, new: &str) -> String {
+    let old_files = split_files(old);
+    let mut old_hunks = HashSet::new();
+    let mut old_headers = HashSet::new();
+    for file in &old_files {
+        old_headers.insert(file.header.clone());
+        for hunk in &file.hunks {
+            old_hunks.insert((file.name(), &hunk[1..]));
+        }
+    }
+
+    let mut ret = String::new();
+    for file in split_files(new) {
+        let hunks: Vec<_> = file
+            .hunks
+            .iter()
+            .filter(|hunk| !old_hunks.contains(&(file.name(), &hunk[1..])))
+            .collect();
+
+        // Files without any hunks (eg. binary files or pure renames) are only
+        // kept if their header changed
+        let keep = if file.hunks.is_empty() {
+            !old_headers.contains(&file.header)
+        } else {
+            !hunks.is_empty()
+        };
+        if !keep {
+            continue;
+        }
+
+        for line in file.header.iter().chain(hunks.into_iter().flatten()) {
+            ret += line;
+            ret += "\n";
+        }
+    }
+
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq as assert_eq_pretty;
+
+    #[test]
+    fn test_interdiff() {
+        let old = include_str!("../testdata/interdiff/old");
+        let new = include_str!("../testdata/interdiff/new");
+        let gold = include_str!("../testdata/interdiff/gold");
+
+        assert_eq_pretty!(interdiff(old, new), gold);
+    }
+
+    #[test]
+    fn test_interdiff_unchanged() {
+        let old = include_str!("../testdata/interdiff/old");
+
+        assert_eq_pretty!(interdiff(old, old), "");
+    }
+}
@@ -0,0 +1,464 @@
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::{json, Value};
+
+use crate::parser::LineLocation;
+use crate::review::{review_at, Review};
+
+/// Review actions offered as code actions
+const DIRECTIVES: &[&str] = &["approve", "reject", "comment"];
+
+/// JSON-RPC error code for requests the server does not support
+const METHOD_NOT_FOUND: i64 = -32601;
+/// JSON-RPC error code for requests the server failed to handle
+const INTERNAL_ERROR: i64 = -32603;
+
+/// Reads a single message, or `None` once the client hangs up
+fn read_message(reader: &mut impl BufRead) -> Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = Some(
+                    value
+                        .trim()
+                        .parse::<usize>()
+                        .context("Invalid Content-Length")?,
+                );
+            }
+        }
+    }
+
+    let Some(length) = length else {
+        bail!("Message is missing Content-Length");
+    };
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+
+    serde_json::from_slice(&body)
+        .map(Some)
+        .context("Failed to parse message")
+}
+
+fn write_message(writer: &mut impl Write, message: &Value) -> Result<()> {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+    writer.flush().context("Failed to write message")
+}
+
+/// Turns a `file://` URI into a path, decoding any percent-encoded bytes
+fn uri_path(uri: &str) -> Result<PathBuf> {
+    let Some(encoded) = uri.strip_prefix("file://") else {
+        bail!("Not a file: {uri}");
+    };
+
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut rest = encoded.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        let decoded = match (b, tail) {
+            (b'%', [hi, lo, ..]) => std::str::from_utf8(&[*hi, *lo])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        match decoded {
+            Some(d) => {
+                bytes.push(d);
+                rest = &tail[2..];
+            }
+            None => {
+                bytes.push(b);
+                rest = tail;
+            }
+        }
+    }
+
+    String::from_utf8(bytes)
+        .map(PathBuf::from)
+        .with_context(|| format!("Invalid file URI: {uri}"))
+}
+
+/// Range covering the whole of `line`, which is line `idx` of a document
+fn line_range(idx: usize, line: &str) -> Value {
+    // Positions count UTF-16 code units unless negotiated otherwise
+    let end = line.encode_utf16().count();
+    json!({
+        "start": {"line": idx, "character": 0},
+        "end": {"line": idx, "character": end},
+    })
+}
+
+/// Human readable form of a `LineLocation`, eg. `RIGHT 3`
+fn describe_location(location: &LineLocation) -> String {
+    match location {
+        LineLocation::Left(line) => format!("LEFT {line}"),
+        LineLocation::Right(line) => format!("RIGHT {line}"),
+    }
+}
+
+/// Language server for review files
+///
+/// Review files are matched to their review by their path in the workdir.
+/// Documents outside of it are ignored.
+struct Server {
+    workdir: PathBuf,
+    /// Contents of open review files, by URI
+    documents: HashMap<String, String>,
+}
+
+impl Server {
+    fn review(&self, uri: &str) -> Result<Review> {
+        let path = uri_path(uri)?;
+        // Editors may hand out resolved paths when the workdir is behind a symlink
+        let workdir = self
+            .workdir
+            .canonicalize()
+            .unwrap_or_else(|_| self.workdir.clone());
+        let path = [&self.workdir, &workdir]
+            .iter()
+            .find_map(|w| path.strip_prefix(w).ok())
+            .ok_or_else(|| anyhow!("{} is not in the workdir", path.display()))?;
+
+        let review = review_at(&self.workdir, &self.workdir.join(path))?;
+        if !review.has_metadata() {
+            bail!("{} has no metadata", path.display());
+        }
+
+        Ok(review)
+    }
+
+    fn document<'a>(&'a self, params: &'a Value) -> Result<(&'a str, &'a str)> {
+        let uri = params["textDocument"]["uri"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Missing document URI"))?;
+        let text = self
+            .documents
+            .get(uri)
+            .ok_or_else(|| anyhow!("Document is not open: {uri}"))?;
+
+        Ok((uri, text))
+    }
+
+    fn diagnostics(&self, uri: &str) -> Result<Vec<Value>> {
+        let Some(text) = self.documents.get(uri) else {
+            return Ok(Vec::new());
+        };
+        let Some(error) = self.review(uri)?.lint(text)? else {
+            return Ok(Vec::new());
+        };
+
+        let line = text.lines().nth(error.line).unwrap_or("");
+        Ok(vec![json!({
+            "range": line_range(error.line, line),
+            "severity": 1,
+            "source": "prr",
+            "message": error.message,
+        })])
+    }
+
+    fn publish_diagnostics(&self, writer: &mut impl Write, uri: &str) -> Result<()> {
+        let diagnostics = self.diagnostics(uri).unwrap_or_else(|e| {
+            eprintln!("Warning: not checking {uri}: {e:#}");
+            Vec::new()
+        });
+
+        write_message(
+            writer,
+            &json!({
+                "jsonrpc": "2.0",
+                "method": "textDocument/publishDiagnostics",
+                "params": {"uri": uri, "diagnostics": diagnostics},
+            }),
+        )
+    }
+
+    fn hover(&self, params: &Value) -> Result<Value> {
+        let (uri, text) = self.document(params)?;
+        let line = params["position"]["line"].as_u64().unwrap_or(0) as usize;
+        let located = self.review(uri)?.locate(text)?;
+
+        Ok(match located.get(line).cloned().flatten() {
+            Some((file, location)) => json!({
+                "contents": {
+                    "kind": "plaintext",
+                    "value": format!("{file}: {}", describe_location(&location)),
+                },
+                "range": line_range(line, text.lines().nth(line).unwrap_or("")),
+            }),
+            None => Value::Null,
+        })
+    }
+
+    /// Offers to set the review action, replacing the one the review file has
+    fn code_actions(&self, params: &Value) -> Result<Value> {
+        let (uri, text) = self.document(params)?;
+
+        // Review actions are only parsed before the first file of the diff
+        let existing = text
+            .lines()
+            .take_while(|l| !l.starts_with("> diff --git "))
+            .enumerate()
+            .find_map(|(idx, l)| {
+                let action = l.trim().strip_prefix("@prr ")?;
+                DIRECTIVES.contains(&action).then_some((idx, action))
+            });
+
+        let actions: Vec<Value> = DIRECTIVES
+            .iter()
+            .filter(|d| !existing.is_some_and(|(_, action)| action == **d))
+            .map(|d| {
+                let (title, start, end) = match existing {
+                    Some((idx, _)) => (format!("Change to `@prr {d}`"), idx, idx + 1),
+                    None => (format!("Insert `@prr {d}`"), 0, 0),
+                };
+                json!({
+                    "title": title,
+                    "edit": {
+                        "changes": {
+                            uri: [{
+                                "range": {
+                                    "start": {"line": start, "character": 0},
+                                    "end": {"line": end, "character": 0},
+                                },
+                                "newText": format!("@prr {d}\n"),
+                            }],
+                        },
+                    },
+                })
+            })
+            .collect();
+
+        Ok(json!(actions))
+    }
+
+    /// Handles a notification, ie. a message that gets no response
+    fn notify(&mut self, writer: &mut impl Write, method: &str, params: &Value) -> Result<()> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+        match method {
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or("");
+                self.documents.insert(uri.to_string(), text.to_string());
+            }
+            // Full document sync, so the last change has the whole document
+            "textDocument/didChange" => {
+                let changes = params["contentChanges"].as_array();
+                if let Some(text) = changes.and_then(|c| c.last()?["text"].as_str()) {
+                    self.documents.insert(uri.to_string(), text.to_string());
+                }
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+            }
+            _ => return Ok(()),
+        }
+
+        self.publish_diagnostics(writer, uri)
+    }
+
+    /// Handles a request. Fails with a JSON-RPC error code and message
+    fn request(&self, method: &str, params: &Value) -> Result<Value, (i64, String)> {
+        let result = match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    // Full document sync
+                    "textDocumentSync": 1,
+                    "hoverProvider": true,
+                    "codeActionProvider": true,
+                },
+                "serverInfo": {"name": "prr", "version": env!("CARGO_PKG_VERSION")},
+            })),
+            "shutdown" => Ok(Value::Null),
+            "textDocument/hover" => self.hover(params),
+            "textDocument/codeAction" => self.code_actions(params),
+            _ => return Err((METHOD_NOT_FOUND, format!("Unsupported method: {method}"))),
+        };
+
+        result.map_err(|e| (INTERNAL_ERROR, format!("{e:#}")))
+    }
+
+    /// Handles a single message. Returns false once the client asks the server to exit
+    fn handle(&mut self, writer: &mut impl Write, message: &Value) -> Result<bool> {
+        let params = &message["params"];
+        // Responses to requests are ignored, as the server sends none
+        let Some(method) = message["method"].as_str() else {
+            return Ok(true);
+        };
+        if method == "exit" {
+            return Ok(false);
+        }
+        let Some(id) = message.get("id") else {
+            self.notify(writer, method, params)?;
+            return Ok(true);
+        };
+
+        let response = match self.request(method, params) {
+            Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+            Err((code, message)) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": {"code": code, "message": message},
+            }),
+        };
+        write_message(writer, &response)?;
+
+        Ok(true)
+    }
+}
+
+/// Serves LSP over `reader` and `writer` until the client exits
+///
+/// Diagnostics are the first error `prr submit` would fail on. Hovering a
+/// quoted line shows where a comment on it would end up, and code actions set
+/// the review action.
+pub fn serve(workdir: &Path, mut reader: impl BufRead, mut writer: impl Write) -> Result<()> {
+    let mut server = Server {
+        workdir: workdir.to_path_buf(),
+        documents: HashMap::new(),
+    };
+
+    while let Some(message) = read_message(&mut reader)? {
+        if !server.handle(&mut writer, &message)? {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Cursor;
+
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn frame(messages: &[Value]) -> Vec<u8> {
+        let mut framed = Vec::new();
+        for message in messages {
+            write_message(&mut framed, message).unwrap();
+        }
+
+        framed
+    }
+
+    #[test]
+    fn test_serve() {
+        let review = include_str!("../testdata/review/snip_multiple/review");
+        let metadata = include_str!("../testdata/review/snip_multiple/metadata");
+        let dir = tempdir().unwrap();
+        let project = dir.path().join("some owner").join("some_repo");
+        fs::create_dir_all(&project).unwrap();
+        fs::write(project.join("3.prr"), review).unwrap();
+        fs::write(project.join(".3"), metadata).unwrap();
+
+        let uri = format!(
+            "file://{}/some%20owner/some_repo/3.prr",
+            dir.path().display()
+        );
+        let corrupted = review.replace("> +++ b/src/review.rs", "> +++ b/src/lsp.rs");
+        let doc = json!({"uri": uri});
+        let input = frame(&[
+            json!({"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {}}),
+            json!({"jsonrpc": "2.0", "method": "initialized", "params": {}}),
+            json!({
+                "jsonrpc": "2.0",
+                "method": "textDocument/didOpen",
+                "params": {"textDocument": {"uri": uri, "text": review}},
+            }),
+            json!({
+                "jsonrpc": "2.0",
+                "id": 2,
+                "method": "textDocument/hover",
+                "params": {"textDocument": doc, "position": {"line": 8, "character": 0}},
+            }),
+            json!({
+                "jsonrpc": "2.0",
+                "id": 3,
+                "method": "textDocument/codeAction",
+                "params": {"textDocument": doc},
+            }),
+            json!({
+                "jsonrpc": "2.0",
+                "method": "textDocument/didChange",
+                "params": {"textDocument": doc, "contentChanges": [{"text": corrupted}]},
+            }),
+            json!({"jsonrpc": "2.0", "id": 4, "method": "textDocument/rename", "params": {}}),
+            json!({"jsonrpc": "2.0", "id": 5, "method": "shutdown"}),
+            json!({"jsonrpc": "2.0", "method": "exit"}),
+        ]);
+
+        let mut output = Vec::new();
+        serve(dir.path(), Cursor::new(input), &mut output).unwrap();
+        let mut reader = Cursor::new(output);
+        let mut responses = Vec::new();
+        while let Some(message) = read_message(&mut reader).unwrap() {
+            responses.push(message);
+        }
+
+        assert_eq!(responses.len(), 7);
+        assert_eq!(
+            responses[0]["result"]["capabilities"]["hoverProvider"],
+            true
+        );
+        assert_eq!(
+            responses[1]["params"],
+            json!({"uri": uri, "diagnostics": []})
+        );
+        assert_eq!(
+            responses[2]["result"]["contents"]["value"],
+            "src/review.rs: RIGHT 158"
+        );
+
+        let actions = responses[3]["result"].as_array().unwrap();
+        assert_eq!(actions.len(), 3);
+        assert_eq!(actions[0]["title"], "Insert `@prr approve`");
+        assert_eq!(
+            actions[0]["edit"]["changes"][&uri][0]["newText"],
+            "@prr approve\n"
+        );
+
+        // Snips cannot be resolved against the corrupted quote
+        assert_eq!(
+            responses[4]["params"]["diagnostics"],
+            json!([{
+                "range": {
+                    "start": {"line": 0, "character": 0},
+                    "end": {"line": 0, "character": 5},
+                },
+                "severity": 1,
+                "source": "prr",
+                "message": "Failed to resolve snips. Did you corrupt quoted text?",
+            }])
+        );
+        assert_eq!(responses[5]["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(
+            responses[6],
+            json!({"jsonrpc": "2.0", "id": 5, "result": null})
+        );
+    }
+
+    #[test]
+    fn test_uri_path() {
+        assert_eq!(
+            uri_path("file:///home/sun/some%20owner/r%C3%A9po/3.prr").unwrap(),
+            Path::new("/home/sun/some owner/répo/3.prr")
+        );
+        assert_eq!(uri_path("file:///100%").unwrap(), Path::new("/100%"));
+        assert!(uri_path("untitled:Untitled-1").is_err());
+    }
+}
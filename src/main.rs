@@ -1,4 +1,5 @@
 use std::env;
+use std::io;
 use std::path::{Path, PathBuf};
 use std::process;
 
@@ -6,12 +7,22 @@ use anyhow::{bail, Context, Result};
 use clap::Parser;
 
 mod cli;
+mod commits;
+mod compose;
+mod email;
+mod forge;
+mod interdiff;
+mod lsp;
+mod mbox;
 mod parser;
 mod prr;
 mod review;
+mod sarif;
+mod suggestion;
 
 use cli::*;
 use prr::Prr;
+use review::Unsubmitted;
 
 /// The name of the local configuration file
 pub const LOCAL_CONFIG_FILE_NAME: &str = ".prr.toml";
@@ -67,9 +78,38 @@ async fn main() -> Result<()> {
     let prr = Prr::new(&config_path, find_project_config_file())?;
 
     match args.command {
-        Command::Get { pr, force, open } => {
-            let (owner, repo, pr_num) = prr.parse_pr_str(&pr)?;
-            let review = prr.get_pr(&owner, &repo, pr_num, force).await?;
+        Command::Get {
+            pr,
+            force,
+            refresh,
+            open,
+            since_last_review,
+            per_commit,
+            mbox,
+        } => {
+            let (owner, repo, pr_num, kind) = prr.parse_pr_str(&pr)?;
+            let unsubmitted = if refresh {
+                Unsubmitted::CarryForward
+            } else if force {
+                Unsubmitted::Discard
+            } else {
+                Unsubmitted::Keep
+            };
+            let review = match mbox {
+                Some(path) => prr.get_mbox(&owner, &repo, pr_num, kind, &path, unsubmitted)?,
+                None => {
+                    prr.get_pr(
+                        &owner,
+                        &repo,
+                        pr_num,
+                        kind,
+                        unsubmitted,
+                        since_last_review,
+                        per_commit,
+                    )
+                    .await?
+                }
+            };
             let path = review.path();
             println!("{}", path.display());
             if open {
@@ -77,20 +117,92 @@ async fn main() -> Result<()> {
             }
         }
         Command::Edit { pr } => {
-            let (owner, repo, pr_num) = prr.parse_pr_str(&pr)?;
-            let review = prr.get_review(&owner, &repo, pr_num)?;
+            let (owner, repo, pr_num, kind) = prr.parse_pr_str(&pr)?;
+            let review = prr.get_review(&owner, &repo, pr_num, kind)?;
             open_review(&review.path()).context("Failed to open review file")?;
         }
-        Command::Submit { pr, debug } => {
-            let (owner, repo, pr_num) = prr.parse_pr_str(&pr)?;
-            prr.submit_pr(&owner, &repo, pr_num, debug).await?;
+        Command::Submit {
+            pr,
+            debug,
+            pending,
+            finalize,
+            resume,
+        } => {
+            let (owner, repo, pr_num, kind) = prr.parse_pr_str(&pr)?;
+            if finalize {
+                prr.finalize_pr(&owner, &repo, pr_num, kind, debug).await?;
+            } else {
+                prr.submit_pr(&owner, &repo, pr_num, kind, pending, resume, debug)
+                    .await?;
+            }
+        }
+        Command::Amend { pr, debug } => {
+            let (owner, repo, pr_num, kind) = prr.parse_pr_str(&pr)?;
+            prr.amend_pr(&owner, &repo, pr_num, kind, debug).await?;
         }
         Command::Apply { pr } => {
-            let (owner, repo, pr_num) = prr.parse_pr_str(&pr)?;
-            prr.apply_pr(&owner, &repo, pr_num, Path::new("./"))?;
+            let (owner, repo, pr_num, kind) = prr.parse_pr_str(&pr)?;
+            prr.apply_pr(&owner, &repo, pr_num, kind, Path::new("./"))?;
+        }
+        Command::ApplySuggestions { pr } => {
+            let (owner, repo, pr_num, kind) = prr.parse_pr_str(&pr)?;
+            prr.apply_suggestions(&owner, &repo, pr_num, kind, Path::new("./"))
+                .await?;
+        }
+        Command::Export { pr, format, email } => {
+            let (owner, repo, pr_num, kind) = prr.parse_pr_str(&pr)?;
+            // Without `--format`, clap requires `--email`
+            debug_assert!(format.is_some() || email);
+            let format = format.unwrap_or(ExportFormat::Email);
+            let text = match format {
+                ExportFormat::Email => prr.export_email(&owner, &repo, pr_num, kind)?,
+            };
+            print!("{text}");
+        }
+        Command::ImportEmail { pr, email, force } => {
+            let (owner, repo, pr_num, kind) = prr.parse_pr_str(&pr)?;
+            let review = prr.import_email(&owner, &repo, pr_num, kind, &email, force)?;
+            println!("{}", review.path().display());
+        }
+        Command::ImportSarif {
+            pr,
+            sarif,
+            keep_outside,
+        } => {
+            let (owner, repo, pr_num, kind) = prr.parse_pr_str(&pr)?;
+            let review = prr.import_sarif(&owner, &repo, pr_num, kind, &sarif, keep_outside)?;
+            println!("{}", review.path().display());
+        }
+        Command::Compose { pr } => {
+            let (owner, repo, pr_num, kind) = prr.parse_pr_str(&pr)?;
+            let json = io::read_to_string(io::stdin()).context("Failed to read comments")?;
+            let review = prr.compose(&owner, &repo, pr_num, kind, &json)?;
+            println!("{}", review.path().display());
+        }
+        Command::Status {
+            no_titles,
+            remote,
+            format,
+        } => {
+            prr.print_status(no_titles, remote, format == OutputFormat::Json)
+                .await?;
+        }
+        Command::Parse { pr, json } => {
+            let (owner, repo, pr_num, kind) = prr.parse_pr_str(&pr)?;
+            prr.print_parsed(&owner, &repo, pr_num, kind, json)?;
+        }
+        Command::Lsp => {
+            prr.lsp()?;
+        }
+        Command::Sync { prs } => {
+            prr.print_sync(&prs).await?;
+        }
+        Command::Inbox { no_titles } => {
+            prr.print_inbox(no_titles).await?;
         }
-        Command::Status { no_titles } => {
-            prr.print_status(no_titles)?;
+        Command::History { pr } => {
+            let (owner, repo, pr_num, kind) = prr.parse_pr_str(&pr)?;
+            prr.print_history(&owner, &repo, pr_num, kind)?;
         }
         Command::Remove {
             prs,
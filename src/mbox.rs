@@ -0,0 +1,465 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::commits::Commit;
+
+lazy_static! {
+    // Tags in front of a patch subject. Example:
+    //
+    //      [PATCH v2 1/3] [RFC] ch1: Expand on the second point
+    //
+    static ref SUBJECT_TAGS: Regex = Regex::new(r"^\s*(\[[^\]]*\]\s*)+").unwrap();
+    // Position of a patch in its series, inside the subject tags
+    static ref SERIES_POSITION: Regex = Regex::new(r"\b(?P<n>\d+)/(?P<total>\d+)\]").unwrap();
+    // mboxrd escapes `From ` lines in bodies by prefixing them with `>`
+    static ref ESCAPED_FROM: Regex = Regex::new(r"^>+From ").unwrap();
+}
+
+/// An email a patch series was sent as
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PatchEmail {
+    /// Message ID, including the angle brackets
+    pub message_id: Option<String>,
+    /// Full subject, including tags such as `[PATCH 1/2]`
+    pub subject: String,
+    /// Sender in `Name <email>` form
+    pub from: String,
+    /// Recipients, as written in the `To` header
+    #[serde(default)]
+    pub to: String,
+    /// Carbon copy recipients, as written in the `Cc` header
+    #[serde(default)]
+    pub cc: String,
+    /// Message IDs of the thread the email is part of, oldest first
+    pub references: Vec<String>,
+}
+
+/// A patch series read from an mbox
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Series {
+    /// The cover letter, if there is one
+    pub cover: Option<PatchEmail>,
+    /// The patches, in the order they are applied
+    pub patches: Vec<PatchEmail>,
+}
+
+/// Everything read from the mbox of a patch series
+pub struct ParsedSeries {
+    pub series: Series,
+    /// The patches as commits, in the order they are applied
+    pub commits: Vec<Commit>,
+    /// Summary and body of the cover letter, if there is one
+    pub cover_letter: Option<String>,
+}
+
+/// A single email of an mbox
+struct Message {
+    /// Hash on the mbox `From ` line if it is a commit hash, as it is for
+    /// `git format-patch` output
+    sha: Option<String>,
+    /// Headers in order. Folded headers are unfolded.
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl Message {
+    /// Returns the first header called `name`, ignoring case
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Returns all message IDs in the header called `name`
+    fn message_ids(&self, name: &str) -> Vec<String> {
+        self.header(name)
+            .unwrap_or_default()
+            .split_whitespace()
+            .filter(|id| id.starts_with('<') && id.ends_with('>'))
+            .map(str::to_string)
+            .collect()
+    }
+
+    fn email(&self) -> PatchEmail {
+        let mut references = self.message_ids("References");
+        for id in self.message_ids("In-Reply-To") {
+            if !references.contains(&id) {
+                references.push(id);
+            }
+        }
+
+        PatchEmail {
+            message_id: self.message_ids("Message-Id").into_iter().next(),
+            subject: self.header("Subject").unwrap_or_default().to_string(),
+            from: self.header("From").unwrap_or_default().to_string(),
+            to: self.header("To").unwrap_or_default().to_string(),
+            cc: self.header("Cc").unwrap_or_default().to_string(),
+            references,
+        }
+    }
+}
+
+/// Splits an mbox into its messages
+///
+/// Text that does not start with a `From ` line is taken to be a single email,
+/// eg. a `.eml` or `.patch` file.
+fn split_mbox(text: &str) -> Vec<(Option<String>, String)> {
+    let mut messages = Vec::new();
+    let mut current: Option<(Option<String>, String)> = None;
+    let mut prev_blank = true;
+    for line in text.lines() {
+        if prev_blank && line.starts_with("From ") {
+            messages.extend(current.take());
+            let sha = line
+                .split_whitespace()
+                .nth(1)
+                .filter(|s| s.len() == 40 && s.chars().all(|c| c.is_ascii_hexdigit()))
+                .map(str::to_string);
+            current = Some((sha, String::new()));
+            prev_blank = false;
+            continue;
+        }
+
+        let (_, body) = current.get_or_insert_with(|| (None, String::new()));
+        if ESCAPED_FROM.is_match(line) {
+            body.push_str(&line[1..]);
+        } else {
+            body.push_str(line);
+        }
+        body.push('\n');
+        prev_blank = line.is_empty();
+    }
+    messages.extend(current);
+
+    messages
+}
+
+/// Parses the headers and body of an email
+fn parse_message(sha: Option<String>, text: &str) -> Message {
+    let mut headers: Vec<(String, String)> = Vec::new();
+    let mut lines = text.lines();
+    for line in lines.by_ref() {
+        if line.is_empty() {
+            break;
+        }
+
+        if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = headers.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+
+    let mut body = String::new();
+    for line in lines {
+        body.push_str(line);
+        body.push('\n');
+    }
+
+    Message { sha, headers, body }
+}
+
+/// Returns the commit message and the diff of a patch email body
+///
+/// The message ends at the `---` line, the diff starts at the first file
+/// header. Both end at the signature (`-- `), if any.
+fn split_body(body: &str) -> (String, String) {
+    let mut message = String::new();
+    let mut diff = String::new();
+    let mut in_message = true;
+    let mut in_diff = false;
+    for line in body.lines() {
+        if line.starts_with("diff --git ") {
+            in_message = false;
+            in_diff = true;
+        } else if line == "-- " {
+            break;
+        } else if line == "---" {
+            in_message = false;
+        }
+
+        if in_message {
+            message.push_str(line);
+            message.push('\n');
+        } else if in_diff {
+            diff.push_str(line);
+            diff.push('\n');
+        }
+    }
+
+    (message.trim().to_string(), diff)
+}
+
+/// Returns the position of a patch in its series, if its subject says
+fn series_position(subject: &str) -> Option<u64> {
+    let tags = SUBJECT_TAGS.find(subject)?;
+    SERIES_POSITION
+        .captures(tags.as_str())
+        .and_then(|c| c.name("n").unwrap().as_str().parse().ok())
+}
+
+/// Parses a patch series out of mbox `texts`
+///
+/// Emails without a diff are dropped, except for the cover letter (patch 0).
+/// Patches are ordered by their position in the series. Patches that were not
+/// generated by `git format-patch` carry no commit hash, so they get a made up
+/// one from their position.
+pub fn parse_series(texts: &[String]) -> Result<ParsedSeries> {
+    let messages: Vec<Message> = texts
+        .iter()
+        .flat_map(|t| split_mbox(t))
+        .map(|(sha, text)| parse_message(sha, &text))
+        .collect();
+
+    let mut cover = None;
+    let mut patches = Vec::new();
+    for message in messages {
+        let email = message.email();
+        let position = series_position(&email.subject);
+        let (body, diff) = split_body(&message.body);
+        let summary = SUBJECT_TAGS.replace(&email.subject, "").to_string();
+        let text = if body.is_empty() {
+            summary
+        } else {
+            format!("{summary}\n\n{body}")
+        };
+
+        if diff.is_empty() {
+            if position == Some(0) && cover.is_none() {
+                cover = Some((email, text));
+            }
+            continue;
+        }
+
+        if let Some(encoding) = message.header("Content-Transfer-Encoding") {
+            let encoding = encoding.to_ascii_lowercase();
+            if !["7bit", "8bit", "binary"].contains(&encoding.as_str()) {
+                bail!(
+                    "Unsupported Content-Transfer-Encoding in {}: {}",
+                    email.subject,
+                    encoding
+                );
+            }
+        }
+
+        let commit = Commit {
+            sha: message.sha.clone().unwrap_or_default(),
+            author: email.from.clone(),
+            date: message.header("Date").unwrap_or_default().to_string(),
+            message: text,
+            diff,
+        };
+        patches.push((position, email, commit));
+    }
+
+    if patches.is_empty() {
+        bail!("No patches found");
+    }
+    patches.sort_by_key(|(position, ..)| position.unwrap_or(u64::MAX));
+
+    let mut emails = Vec::with_capacity(patches.len());
+    let mut commits = Vec::with_capacity(patches.len());
+    for (idx, (_, email, mut commit)) in patches.into_iter().enumerate() {
+        if commit.sha.is_empty() {
+            commit.sha = format!("{:040x}", idx + 1);
+        }
+        commits.push(commit);
+        emails.push(email);
+    }
+
+    let (cover, cover_letter) = cover.unzip();
+    Ok(ParsedSeries {
+        series: Series {
+            cover,
+            patches: emails,
+        },
+        commits,
+        cover_letter,
+    })
+}
+
+/// Decodes a quoted-printable body
+fn decode_quoted_printable(body: &str) -> String {
+    let mut bytes = Vec::with_capacity(body.len());
+    for line in body.lines() {
+        let line = line.trim_end();
+        // A trailing `=` is a soft line break
+        let (line, soft) = match line.strip_suffix('=') {
+            Some(l) => (l, true),
+            None => (line, false),
+        };
+
+        let mut rest = line.as_bytes();
+        while let Some((&b, tail)) = rest.split_first() {
+            let escaped = (b == b'=')
+                .then(|| tail.get(..2))
+                .flatten()
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            match escaped {
+                Some(decoded) => {
+                    bytes.push(decoded);
+                    rest = &tail[2..];
+                }
+                None => {
+                    bytes.push(b);
+                    rest = tail;
+                }
+            }
+        }
+
+        if !soft {
+            bytes.push(b'\n');
+        }
+    }
+
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Parses a single email, eg. a reply saved as a `.eml` file
+///
+/// Returns the email and its decoded body.
+pub fn parse_email(text: &str) -> Result<(PatchEmail, String)> {
+    let (sha, text) = split_mbox(text)
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("Email is empty"))?;
+    let message = parse_message(sha, &text);
+
+    let content_type = message.header("Content-Type").unwrap_or_default();
+    if content_type.to_ascii_lowercase().starts_with("multipart/") {
+        bail!("Multipart emails are not supported, please reply in plain text");
+    }
+
+    let encoding = message
+        .header("Content-Transfer-Encoding")
+        .unwrap_or("7bit")
+        .to_ascii_lowercase();
+    let body = match encoding.as_str() {
+        "7bit" | "8bit" | "binary" => message.body.clone(),
+        "quoted-printable" => decode_quoted_printable(&message.body),
+        _ => bail!("Unsupported Content-Transfer-Encoding: {}", encoding),
+    };
+
+    Ok((message.email(), body))
+}
+
+/// Reads the mbox at `path`, or every file in `path` if it is a directory
+pub fn read_mbox(path: &Path) -> Result<Vec<String>> {
+    if !path.is_dir() {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        return Ok(vec![text]);
+    }
+
+    let mut files: Vec<_> = fs::read_dir(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.is_file())
+        .collect();
+    // `git format-patch` numbers its files in order
+    files.sort();
+
+    files
+        .iter()
+        .map(|f| fs::read_to_string(f).with_context(|| format!("Failed to read {}", f.display())))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commits::render_commits;
+    use pretty_assertions::assert_eq as assert_eq_pretty;
+
+    #[test]
+    fn test_parse_series() {
+        let mbox = include_str!("../testdata/mbox/series.mbox").to_string();
+        let parsed = parse_series(&[mbox]).unwrap();
+        let series = parsed.series;
+
+        let cover = series.cover.unwrap();
+        assert_eq!(
+            cover.message_id.as_deref(),
+            Some("<cover.1704067200@example.com>")
+        );
+        assert_eq!(series.patches.len(), 2);
+        assert_eq!(
+            series.patches[1].subject,
+            "[PATCH 2/2] ch1: Add a fourth point"
+        );
+        assert_eq!(
+            series.patches[1].references,
+            vec!["<cover.1704067200@example.com>".to_string()]
+        );
+
+        // Patches are rendered in series order even though the mbox is not
+        assert_eq_pretty!(
+            render_commits(&parsed.commits),
+            include_str!("../testdata/mbox/gold")
+        );
+        let cover_letter = parsed.cover_letter.unwrap();
+        assert!(cover_letter.starts_with("ch1: Improve the text\n\nThe first chapter"));
+        assert!(cover_letter.ends_with("1 deletion(-)"));
+    }
+
+    #[test]
+    fn test_single_patch_without_from_line() {
+        let patch = "From: Sun Tzu <sun@example.com>\n\
+                     Subject: [PATCH] ch1: Fix typo\n\
+                     Message-Id: <1@example.com>\n\
+                     \n\
+                     ---\n\
+                     diff --git a/ch1.txt b/ch1.txt\n\
+                     --- a/ch1.txt\n\
+                     +++ b/ch1.txt\n\
+                     @@ -1 +1 @@\n\
+                     -Teh\n\
+                     +The\n"
+            .to_string();
+        let ParsedSeries {
+            series, commits, ..
+        } = parse_series(&[patch]).unwrap();
+
+        assert_eq!(series.cover, None);
+        assert_eq!(commits.len(), 1);
+        assert_eq!(commits[0].sha, format!("{:040x}", 1));
+        assert_eq!(commits[0].message, "ch1: Fix typo");
+        assert!(commits[0].diff.ends_with("+The\n"));
+    }
+
+    #[test]
+    fn test_parse_email_quoted_printable() {
+        let reply = "From: Wu Qi <wu@example.com>\n\
+                     Content-Transfer-Encoding: quoted-printable\n\
+                     \n\
+                     > 1. Sun Tzu said: The art of war is of vital importance to the =\n\
+                     State.\n\
+                     Caf=C3=A9 =3D coffee\n"
+            .to_string();
+        let (email, body) = parse_email(&reply).unwrap();
+
+        assert_eq!(email.from, "Wu Qi <wu@example.com>");
+        assert_eq!(
+            body,
+            "> 1. Sun Tzu said: The art of war is of vital importance to the State.\n\
+             Café = coffee\n"
+        );
+    }
+
+    #[test]
+    fn test_no_patches() {
+        let reply = "From: Sun Tzu <sun@example.com>\nSubject: Re: hi\n\nHello\n".to_string();
+        assert!(parse_series(&[reply]).is_err());
+    }
+}
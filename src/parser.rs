@@ -1,6 +1,7 @@
 use anyhow::{anyhow, bail, Context, Result};
 use lazy_static::lazy_static;
 use regex::Regex;
+use serde_derive::Serialize;
 
 // Use lazy static to ensure regex is only compiled once
 lazy_static! {
@@ -14,6 +15,16 @@ lazy_static! {
     //      `diff --git a/ch1.txt b/ch1.txt`
     //
     static ref DIFF_START: Regex = Regex::new(r"^diff --git a/.+ b/(?P<new>.+)$").unwrap();
+    // Regex for the start of an existing review thread. The start of a thread should look like:
+    //
+    //      `>> [thread PRRT_kwDOGmgkY85Ayq4w] (resolved)`
+    //
+    static ref THREAD_START: Regex = Regex::new(r"^>> \[thread (?P<id>[^\]]+)\]").unwrap();
+    // Regex for the start of a commit in a per-commit review. The start of a commit should look like:
+    //
+    //      `commit 6c5a5bb2f1f52c3a4b1a2b7b0a8f2a2f9f4e3d21`
+    //
+    static ref COMMIT_START: Regex = Regex::new(r"^commit (?P<sha>[0-9a-f]{40})$").unwrap();
 }
 
 /// The location of a line
@@ -22,7 +33,10 @@ lazy_static! {
 /// deleted or added lines. A useful way to think about the line location is
 /// the line number a comment should be attached to in the file pre-change (left)
 /// or the file post-change (right)
-#[derive(Debug, PartialEq, Eq, Clone)]
+///
+/// Serializes as eg. `{"side": "right", "line": 3}`.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+#[serde(tag = "side", content = "line", rename_all = "lowercase")]
 pub enum LineLocation {
     /// The "red"/deleted side of the diff
     Left(u64),
@@ -31,7 +45,7 @@ pub enum LineLocation {
 }
 
 /// Represents a single inline comment on a review
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
 pub struct InlineComment {
     /// File the comment is in
     ///
@@ -42,10 +56,12 @@ pub struct InlineComment {
     pub start_line: Option<LineLocation>,
     /// The user-supplied review comment
     pub comment: String,
+    /// For a per-commit review, the commit the comment was made in
+    pub commit: Option<String>,
 }
 
 /// Represents a single file-level comment on a review
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
 pub struct FileComment {
     /// File the comment is in
     ///
@@ -53,9 +69,32 @@ pub struct FileComment {
     pub file: String,
     /// The user-supplied review comment
     pub comment: String,
+    /// For a per-commit review, the commit the comment was made in
+    pub commit: Option<String>,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+/// Represents a comment on a commit message in a per-commit review
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct CommitComment {
+    /// Commit the comment is on
+    pub commit: String,
+    /// The user-supplied review comment
+    pub comment: String,
+}
+
+/// Represents a reply to an existing review thread
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct ThreadReply {
+    /// Forge-assigned identifier of the thread
+    pub thread: String,
+    /// The user-supplied reply. Empty if the thread is only being resolved
+    pub comment: String,
+    /// Whether to mark the thread as resolved
+    pub resolve: bool,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum ReviewAction {
     Approve,
     RequestChanges,
@@ -71,8 +110,25 @@ pub enum Comment {
     Inline(InlineComment),
     /// Overall approve, reject, or comment on review
     ReviewAction(ReviewAction),
+    /// Leave the review pending instead of submitting it
+    Pending,
     // A file-level comment (attached to the whole file)
     File(FileComment),
+    /// A reply to an existing review thread
+    Reply(ThreadReply),
+    /// A comment on a commit message (attached to the whole commit)
+    Commit(CommitComment),
+}
+
+impl Comment {
+    /// Attaches inline and file-level comments to `commit`
+    fn in_commit(self, commit: Option<String>) -> Comment {
+        match self {
+            Comment::Inline(c) => Comment::Inline(InlineComment { commit, ..c }),
+            Comment::File(c) => Comment::File(FileComment { commit, ..c }),
+            c => c,
+        }
+    }
 }
 
 #[derive(Default)]
@@ -125,6 +181,24 @@ struct CommentState {
     comment: Vec<String>,
 }
 
+struct CommitMessageState {
+    /// Commit the message belongs to
+    commit: String,
+    /// Each line of comment is stored as an entry
+    comment: Vec<String>,
+}
+
+struct ThreadState {
+    /// State of the file diff the thread is attached to
+    file_diff_state: FileDiffState,
+    /// Forge-assigned identifier of the thread
+    thread: String,
+    /// Each line of reply is stored as an entry
+    comment: Vec<String>,
+    /// Whether we saw a `@prr resolve` directive
+    resolve: bool,
+}
+
 /// State machine states
 ///
 /// Only the following state transitions are valid:
@@ -139,6 +213,12 @@ struct CommentState {
 ///                 |             |  |                              |
 ///                 +-------------+  +------------------------------+
 ///
+/// In addition, FileDiff, StartSpanOrComment and Comment transition to Thread
+/// on the start of an existing review thread. Thread transitions out the same
+/// way Comment does, or to a new Thread.
+///
+/// In a per-commit review, every state transitions to CommitMessage on the
+/// start of a commit. CommitMessage transitions to FilePreamble.
 enum State {
     /// Starting state
     Start(StartState),
@@ -153,17 +233,51 @@ enum State {
     SpanStartOrComment(SpanStartOrCommentState),
     /// We are inside a user-supplied comment
     Comment(CommentState),
+    /// We are inside an existing review thread or a reply to it
+    Thread(ThreadState),
+    /// We are inside the message of a commit in a per-commit review
+    CommitMessage(CommitMessageState),
 }
 
 /// Simple state machine to parse a review file
 pub struct ReviewParser {
     state: State,
+    /// Whether the review is split into commits, whose starts are then detected
+    per_commit: bool,
+    /// For a per-commit review, the commit we are currently in
+    commit: Option<String>,
 }
 
 fn is_diff_header(s: &str) -> bool {
     s.starts_with("diff --git ")
 }
 
+/// Returns whether a raw review file line belongs to an existing review thread
+///
+/// Review threads are quoted one level deeper than the diff (`>> `). Quoted
+/// diff lines always start with `> ` or are a bare `>`, so this is unambiguous.
+pub fn is_thread_line(s: &str) -> bool {
+    s.starts_with(">>")
+}
+
+/// Parses the commit hash out of the start of a commit
+///
+/// Returns Some(sha) if `s` is the start of a commit, else None
+pub fn parse_commit_start(s: &str) -> Option<String> {
+    COMMIT_START
+        .captures(s)
+        .map(|captures| captures.name("sha").unwrap().as_str().to_owned())
+}
+
+/// Parses the thread identifier out of the start of a review thread
+///
+/// Returns Some(id) if `s` is the start of a thread, else None
+pub fn parse_thread_start(s: &str) -> Option<String> {
+    THREAD_START
+        .captures(s)
+        .map(|captures| captures.name("id").unwrap().as_str().to_owned())
+}
+
 /// Parses lines in the form of `@prr DIRECTIVE`
 ///
 /// Returns Some(directive) if found, else None
@@ -176,6 +290,56 @@ fn is_prr_directive(s: &str) -> Option<&str> {
     }
 }
 
+/// Turns the `@prr suggest` blocks in an inline comment into suggestions
+///
+/// A suggestion is a fenced code block on the line after `@prr suggest`. It
+/// replaces the commented line (or span) and so must be on the right side of
+/// the diff.
+fn render_suggestions(
+    comment: &str,
+    line: &LineLocation,
+    start_line: Option<&LineLocation>,
+) -> Result<String> {
+    let mut rendered = Vec::new();
+    let mut lines = comment.lines();
+    while let Some(l) = lines.next() {
+        if is_prr_directive(l) != Some("suggest") {
+            rendered.push(l.to_string());
+            continue;
+        }
+
+        let on_left = |l: Option<&LineLocation>| matches!(l, Some(LineLocation::Left(_)));
+        if on_left(Some(line)) || on_left(start_line) {
+            bail!("Suggestions can only replace lines on the right side of the diff");
+        }
+
+        let fence = match lines.next().map(str::trim) {
+            Some(open) if open.starts_with("```") => open.chars().take_while(|c| *c == '`').count(),
+            _ => bail!("`@prr suggest` must be followed by a fenced code block"),
+        };
+        let fence = "`".repeat(fence);
+        rendered.push(format!("{fence}suggestion"));
+        loop {
+            match lines.next() {
+                Some(l) if l.trim() == fence => break,
+                Some(l) => rendered.push(l.to_string()),
+                None => bail!("Suggestion is missing its closing {}", fence),
+            }
+        }
+        rendered.push(fence);
+    }
+
+    Ok(rendered.join("\n"))
+}
+
+/// Returns whether `comment`, as rendered by the parser, holds a suggestion
+pub fn has_suggestion(comment: &str) -> bool {
+    comment.lines().any(|l| {
+        let l = l.trim();
+        l.starts_with("```") && l.trim_start_matches('`') == "suggestion"
+    })
+}
+
 /// Parses the new filename out of a diff header
 fn parse_diff_header(line: &str) -> Result<String> {
     if let Some(captures) = DIFF_START.captures(line) {
@@ -236,14 +400,130 @@ fn get_next_lines(line: &str, left: u64, right: u64) -> (u64, u64) {
     }
 }
 
+/// Given the file diff state before quoted diff `line`, returns the state after it
+///
+/// Any span in `state` is not carried over.
+fn next_file_diff_state(state: &FileDiffState, line: &str) -> Result<FileDiffState> {
+    let (left, right) = match parse_hunk_start(line)? {
+        // Subtract 1 b/c this line is before the actual diff hunk
+        Some((left, right)) => (left.saturating_sub(1), right.saturating_sub(1)),
+        None => get_next_lines(line, state.left_line, state.right_line),
+    };
+
+    Ok(FileDiffState {
+        file: state.file.to_owned(),
+        left_line: left,
+        right_line: right,
+        line: if is_left_line(line) {
+            LineLocation::Left(left)
+        } else {
+            LineLocation::Right(right)
+        },
+        span_start_line: None,
+    })
+}
+
+impl CommentState {
+    fn comment(&self) -> Result<Comment> {
+        let line = &self.file_diff_state.line;
+        let start_line = self.file_diff_state.span_start_line.as_ref();
+        let comment = self.comment.join("\n").trim_end().to_string();
+
+        Ok(Comment::Inline(InlineComment {
+            file: self.file_diff_state.file.clone(),
+            line: line.clone(),
+            start_line: start_line.cloned(),
+            comment: render_suggestions(&comment, line, start_line)?,
+            commit: None,
+        }))
+    }
+}
+
+impl FilePreambleState {
+    fn comment(&self) -> Option<Comment> {
+        if self.comment.is_empty() {
+            return None;
+        }
+
+        Some(Comment::File(FileComment {
+            file: self.file.to_owned(),
+            comment: self.comment.join("\n").trim().to_string(),
+            commit: None,
+        }))
+    }
+}
+
+impl CommitMessageState {
+    fn comment(&self) -> Option<Comment> {
+        let comment = self.comment.join("\n").trim().to_string();
+        if comment.is_empty() {
+            return None;
+        }
+
+        Some(Comment::Commit(CommitComment {
+            commit: self.commit.clone(),
+            comment,
+        }))
+    }
+}
+
+impl ThreadState {
+    fn new(file_diff_state: &FileDiffState, thread: String) -> ThreadState {
+        ThreadState {
+            file_diff_state: FileDiffState {
+                span_start_line: None,
+                ..file_diff_state.clone()
+            },
+            thread,
+            comment: vec![],
+            resolve: false,
+        }
+    }
+
+    /// Returns the reply to the thread, if any
+    fn reply(&self) -> Option<Comment> {
+        let comment = self.comment.join("\n").trim().to_string();
+        if comment.is_empty() && !self.resolve {
+            return None;
+        }
+
+        Some(Comment::Reply(ThreadReply {
+            thread: self.thread.clone(),
+            comment,
+            resolve: self.resolve,
+        }))
+    }
+}
+
 impl ReviewParser {
-    pub fn new() -> ReviewParser {
+    /// Creates a parser for a review file, which with `per_commit` is split
+    /// into commits as rendered by `render_commits()`
+    ///
+    /// Otherwise a quoted line that looks like the start of a commit, eg. in
+    /// the PR description, is just that.
+    pub fn new(per_commit: bool) -> ReviewParser {
         ReviewParser {
             state: State::Start(StartState::default()),
+            per_commit,
+            commit: None,
         }
     }
 
-    pub fn parse_line(&mut self, mut line: &str) -> Result<Option<Comment>> {
+    pub fn parse_line(&mut self, line: &str) -> Result<Option<Comment>> {
+        // A comment belongs to the commit it was made in, even if it is
+        // finished by the start of the next commit
+        let commit = self.commit.clone();
+        let comment = self.parse_any_line(line)?;
+
+        Ok(comment.map(|c| c.in_commit(commit)))
+    }
+
+    fn parse_any_line(&mut self, mut line: &str) -> Result<Option<Comment>> {
+        // Existing review threads have no bearing on line accounting
+        if is_thread_line(line) {
+            return self.parse_thread_line(line);
+        }
+
         let is_quoted = line.starts_with('>');
         if is_quoted {
             if let Some(stripped) = line.strip_prefix("> ") {
@@ -251,6 +531,10 @@ impl ReviewParser {
             } else if let Some(stripped) = line.strip_prefix('>') {
                 line = stripped;
             }
+
+            if let Some(commit) = parse_commit_start(line).filter(|_| self.per_commit) {
+                return self.parse_commit_start(commit);
+            }
         }
 
         match &mut self.state {
@@ -290,6 +574,7 @@ impl ReviewParser {
                         "approve" => Ok(Some(Comment::ReviewAction(ReviewAction::Approve))),
                         "reject" => Ok(Some(Comment::ReviewAction(ReviewAction::RequestChanges))),
                         "comment" => Ok(Some(Comment::ReviewAction(ReviewAction::Comment))),
+                        "pending" => Ok(Some(Comment::Pending)),
                         _ => bail!("Unknown @prr directive: {}", d),
                     };
                 } else {
@@ -320,14 +605,7 @@ impl ReviewParser {
                     right_start = right_start.saturating_sub(1);
 
                     // Finish up our file-level comment if we had one
-                    let comment = if !state.comment.is_empty() {
-                        Some(Comment::File(FileComment {
-                            file: state.file.to_owned(),
-                            comment: state.comment.join("\n").trim().to_string(),
-                        }))
-                    } else {
-                        None
-                    };
+                    let comment = state.comment();
 
                     self.state = State::FileDiff(FileDiffState {
                         file: state.file.to_owned(),
@@ -457,12 +735,7 @@ impl ReviewParser {
             }
             State::Comment(state) => {
                 if is_quoted {
-                    let comment = Comment::Inline(InlineComment {
-                        file: state.file_diff_state.file.clone(),
-                        line: state.file_diff_state.line.clone(),
-                        start_line: state.file_diff_state.span_start_line.clone(),
-                        comment: state.comment.join("\n").trim_end().to_string(),
-                    });
+                    let comment = state.comment()?;
 
                     if is_diff_header(line) {
                         self.state = State::FilePreamble(FilePreambleState {
@@ -470,22 +743,8 @@ impl ReviewParser {
                             comment: vec![],
                         });
                     } else {
-                        let (next_left, next_right) = get_next_lines(
-                            line,
-                            state.file_diff_state.left_line,
-                            state.file_diff_state.right_line,
-                        );
-                        self.state = State::FileDiff(FileDiffState {
-                            file: state.file_diff_state.file.to_owned(),
-                            left_line: next_left,
-                            right_line: next_right,
-                            line: if is_left_line(line) {
-                                LineLocation::Left(next_left)
-                            } else {
-                                LineLocation::Right(next_right)
-                            },
-                            span_start_line: None,
-                        });
+                        self.state =
+                            State::FileDiff(next_file_diff_state(&state.file_diff_state, line)?);
                     }
 
                     return Ok(Some(comment));
@@ -494,20 +753,220 @@ impl ReviewParser {
                 state.comment.push(line.to_owned());
                 Ok(None)
             }
+            State::Thread(state) => {
+                if is_quoted {
+                    let reply = state.reply();
+
+                    if is_diff_header(line) {
+                        self.state = State::FilePreamble(FilePreambleState {
+                            file: parse_diff_header(line)?,
+                            comment: vec![],
+                        });
+                    } else {
+                        let mut next = next_file_diff_state(&state.file_diff_state, line)?;
+                        // Only whitespace between the thread and this line means
+                        // a span is starting, same as in StartSpanOrComment
+                        if reply.is_none() && !state.comment.is_empty() {
+                            next.span_start_line = Some(next.line.clone());
+                        }
+                        self.state = State::FileDiff(next);
+                    }
+
+                    return Ok(reply);
+                }
+
+                if let Some(d) = is_prr_directive(line) {
+                    match d {
+                        "resolve" => state.resolve = true,
+                        _ => bail!("Unknown @prr directive in thread: {}", d),
+                    }
+                } else {
+                    state.comment.push(line.to_owned());
+                }
+
+                Ok(None)
+            }
+            State::CommitMessage(state) => {
+                if !is_quoted {
+                    state.comment.push(line.to_owned());
+                    return Ok(None);
+                }
+
+                if is_diff_header(line) {
+                    let comment = state.comment();
+                    self.state = State::FilePreamble(FilePreambleState {
+                        file: parse_diff_header(line)?,
+                        comment: vec![],
+                    });
+
+                    return Ok(comment);
+                }
+
+                Ok(None)
+            }
         }
     }
 
-    pub fn finish(self) -> Option<Comment> {
-        match self.state {
-            State::Comment(state) => Some(Comment::Inline(InlineComment {
-                file: state.file_diff_state.file,
-                line: state.file_diff_state.line,
-                start_line: state.file_diff_state.span_start_line,
-                comment: state.comment.join("\n").trim_end().to_string(),
-            })),
+    /// Handles the start of a commit in a per-commit review
+    ///
+    /// Whatever comment was in progress is finished, as comments do not span commits.
+    fn parse_commit_start(&mut self, commit: String) -> Result<Option<Comment>> {
+        let comment = match &self.state {
+            State::Start(state) => {
+                if state.had_unquoted_content {
+                    Some(Comment::Review(state.comment.join("\n").trim().to_string()))
+                } else {
+                    None
+                }
+            }
+            State::FilePreamble(state) => state.comment(),
+            State::FileDiff(FileDiffState {
+                file,
+                span_start_line: Some(_),
+                ..
+            })
+            | State::SpanStartOrComment(SpanStartOrCommentState {
+                file_diff_state:
+                    FileDiffState {
+                        file,
+                        span_start_line: Some(_),
+                        ..
+                    },
+            }) => bail!(
+                "Detected span that was not terminated with a comment, file: {}",
+                file
+            ),
+            State::FileDiff(_) | State::SpanStartOrComment(_) => None,
+            State::Comment(state) => Some(state.comment()?),
+            State::Thread(state) => state.reply(),
+            State::CommitMessage(state) => state.comment(),
+        };
+
+        self.state = State::CommitMessage(CommitMessageState {
+            commit: commit.clone(),
+            comment: vec![],
+        });
+        self.commit = Some(commit);
+
+        Ok(comment)
+    }
+
+    /// Handles a line belonging to an existing review thread (`>>`)
+    ///
+    /// Lines inside a thread are read-only. Only the start of a thread matters,
+    /// as it finishes any preceding comment and begins a potential reply.
+    fn parse_thread_line(&mut self, line: &str) -> Result<Option<Comment>> {
+        let thread = match parse_thread_start(line) {
+            Some(t) => t,
+            None => return Ok(None),
+        };
+
+        match &self.state {
+            State::FileDiff(state) => {
+                if state.span_start_line.is_some() {
+                    bail!(
+                        "Detected span that was not terminated with a comment, file: {}",
+                        state.file
+                    );
+                }
+
+                self.state = State::Thread(ThreadState::new(state, thread));
+                Ok(None)
+            }
+            State::SpanStartOrComment(state) => {
+                if state.file_diff_state.span_start_line.is_some() {
+                    bail!(
+                        "Detected span that was not terminated with a comment, file: {}",
+                        state.file_diff_state.file
+                    );
+                }
+
+                self.state = State::Thread(ThreadState::new(&state.file_diff_state, thread));
+                Ok(None)
+            }
+            State::Comment(state) => {
+                let comment = state.comment()?;
+                self.state = State::Thread(ThreadState::new(&state.file_diff_state, thread));
+                Ok(Some(comment))
+            }
+            State::Thread(state) => {
+                let reply = state.reply();
+                self.state = State::Thread(ThreadState::new(&state.file_diff_state, thread));
+                Ok(reply)
+            }
+            // Threads are only ever attached to diff lines
+            State::Start(_) | State::FilePreamble(_) | State::CommitMessage(_) => Ok(None),
+        }
+    }
+
+    pub fn finish(self) -> Result<Option<Comment>> {
+        let comment = match self.state {
+            State::Comment(state) => Some(state.comment()?),
+            State::Thread(state) => state.reply(),
+            State::CommitMessage(state) => state.comment(),
             _ => None,
+        };
+
+        Ok(comment.map(|c| c.in_commit(self.commit)))
+    }
+}
+
+/// Walks `diff` and returns `f` applied to the file diff state after each line
+///
+/// `diff` is unquoted original text (as stored in review metadata). The returned
+/// vector has one entry per line. Lines that cannot be commented on, such as the
+/// PR description, file headers and hunk starts, map to `None`.
+fn walk_diff<T>(diff: &str, f: impl Fn(&str, &FileDiffState) -> T) -> Vec<Option<T>> {
+    // Commit starts cannot be part of a diff, and those in the PR description
+    // leave no trace in the file diff state
+    let mut parser = ReviewParser::new(true);
+    let mut ret = Vec::new();
+    for line in diff.lines() {
+        // Quoted lines never produce comments nor fail to parse beyond malformed
+        // headers, in which case there is no location to speak of anyways
+        if parser.parse_line(&format!("> {line}")).is_err() {
+            ret.push(None);
+            continue;
         }
+
+        let item = match &parser.state {
+            State::FileDiff(state) if !HUNK_START.is_match(line) => Some(f(line, state)),
+            _ => None,
+        };
+        ret.push(item);
     }
+
+    ret
+}
+
+/// Returns the location a comment on each line of `diff` would be attached to
+///
+/// See `walk_diff()` for details on `diff` and the returned vector.
+pub fn diff_locations(diff: &str) -> Vec<Option<(String, LineLocation)>> {
+    walk_diff(diff, |_, state| (state.file.clone(), state.line.clone()))
+}
+
+/// File, old line number and new line number of a line in a diff
+///
+/// Context lines have both line numbers, added lines only a new one and removed
+/// lines only an old one.
+pub type LineNumbers = (String, Option<u64>, Option<u64>);
+
+/// Returns the `LineNumbers` of each line of `diff`
+///
+/// See `walk_diff()` for details on `diff` and the returned vector.
+pub fn diff_line_numbers(diff: &str) -> Vec<Option<LineNumbers>> {
+    walk_diff(diff, |line, state| {
+        let (old, new) = if is_left_line(line) {
+            (Some(state.left_line), None)
+        } else if line.starts_with('+') {
+            (None, Some(state.right_line))
+        } else {
+            (Some(state.left_line), Some(state.right_line))
+        };
+
+        (state.file.clone(), old, new)
+    })
 }
 
 #[cfg(test)]
@@ -515,7 +974,7 @@ mod tests {
     use super::*;
 
     fn test_fail(input: &str) {
-        let mut parser = ReviewParser::new();
+        let mut parser = ReviewParser::new(false);
 
         for line in input.lines() {
             if parser.parse_line(line).is_err() {
@@ -527,7 +986,10 @@ mod tests {
     }
 
     fn test(input: &str, expected: &[Comment]) {
-        let mut parser = ReviewParser::new();
+        test_parser(ReviewParser::new(false), input, expected);
+    }
+
+    fn test_parser(mut parser: ReviewParser, input: &str, expected: &[Comment]) {
         let mut comments = Vec::new();
 
         for line in input.lines() {
@@ -536,7 +998,7 @@ mod tests {
             }
         }
 
-        if let Some(c) = parser.finish() {
+        if let Some(c) = parser.finish().unwrap() {
             comments.push(c);
         }
 
@@ -556,11 +1018,32 @@ mod tests {
             line: LineLocation::Right(734),
             start_line: Some(LineLocation::Right(731)),
             comment: "Comment 1".to_string(),
+            commit: None,
+        })];
+
+        test(input, &expected);
+    }
+
+    #[test]
+    fn suggestion() {
+        let input = include_str!("../testdata/suggestion");
+        let expected = vec![Comment::Inline(InlineComment {
+            file: "libbpf-cargo/src/btf/btf.rs".to_string(),
+            line: LineLocation::Right(734),
+            start_line: Some(LineLocation::Right(731)),
+            comment: "```suggestion\n        let kind = (t.info >> 24) & 0x1f; // BTF_KIND_MASK\n```\n\nMaybe name the mask?".to_string(),
+            commit: None,
         })];
 
         test(input, &expected);
     }
 
+    #[test]
+    fn suggestion_left_side() {
+        let input = include_str!("../testdata/suggestion_left_side");
+        test_fail(input);
+    }
+
     #[test]
     fn approve_review() {
         let input = include_str!("../testdata/approve_review");
@@ -571,6 +1054,7 @@ mod tests {
                 line: LineLocation::Right(734),
                 start_line: Some(LineLocation::Right(731)),
                 comment: "Comment 1".to_string(),
+                commit: None,
             }),
         ];
 
@@ -587,6 +1071,7 @@ mod tests {
                 line: LineLocation::Right(734),
                 start_line: Some(LineLocation::Right(731)),
                 comment: "Comment 1".to_string(),
+                commit: None,
             }),
         ];
 
@@ -603,6 +1088,7 @@ mod tests {
                 line: LineLocation::Right(734),
                 start_line: Some(LineLocation::Right(731)),
                 comment: "Comment 1".to_string(),
+                commit: None,
             }),
         ];
 
@@ -615,6 +1101,7 @@ mod tests {
         let expected = vec![Comment::File(FileComment {
             file: "libbpf-cargo/src/btf/btf.rs".to_string(),
             comment: "This is a file-level comment!".to_string(),
+            commit: None,
         })];
 
         test(input, &expected);
@@ -639,6 +1126,7 @@ mod tests {
             line: LineLocation::Right(736),
             start_line: None,
             comment: "Comment line 1\nComment line 2\n\nComment line 4".to_string(),
+            commit: None,
         })];
 
         test(input, &expected);
@@ -653,12 +1141,14 @@ mod tests {
                 line: LineLocation::Right(734),
                 start_line: Some(LineLocation::Right(731)),
                 comment: "Comment 1".to_string(),
+                commit: None,
             }),
             Comment::Inline(InlineComment {
                 file: "libbpf-cargo/src/btf/btf.rs".to_string(),
                 line: LineLocation::Right(737),
                 start_line: None,
                 comment: "Comment 2".to_string(),
+                commit: None,
             }),
         ];
 
@@ -674,12 +1164,14 @@ mod tests {
                 line: LineLocation::Right(734),
                 start_line: None,
                 comment: "Comment 1".to_string(),
+                commit: None,
             }),
             Comment::Inline(InlineComment {
                 file: "libbpf-cargo/src/test.rs".to_string(),
                 line: LineLocation::Right(2159),
                 start_line: None,
                 comment: "Comment 2".to_string(),
+                commit: None,
             }),
         ];
 
@@ -694,6 +1186,7 @@ mod tests {
             line: LineLocation::Right(7),
             start_line: None,
             comment: "Great passage".to_string(),
+            commit: None,
         })];
 
         test(input, &expected);
@@ -708,12 +1201,14 @@ mod tests {
                 line: LineLocation::Right(0),
                 start_line: None,
                 comment: "Comment 1".to_string(),
+                commit: None,
             }),
             Comment::Inline(InlineComment {
                 file: "foo.rs".to_string(),
                 line: LineLocation::Right(1),
                 start_line: None,
                 comment: "Comment 2".to_string(),
+                commit: None,
             }),
         ];
 
@@ -728,6 +1223,7 @@ mod tests {
             line: LineLocation::Left(58),
             start_line: Some(LineLocation::Left(1)),
             comment: "Comment 1".to_string(),
+            commit: None,
         })];
 
         test(input, &expected);
@@ -741,6 +1237,7 @@ mod tests {
             line: LineLocation::Right(2159),
             start_line: None,
             comment: "Comment".to_string(),
+            commit: None,
         })];
 
         test(input, &expected);
@@ -754,6 +1251,7 @@ mod tests {
             line: LineLocation::Left(59),
             start_line: Some(LineLocation::Left(1)),
             comment: "Comment 1".to_string(),
+            commit: None,
         })];
 
         test(input, &expected);
@@ -768,11 +1266,171 @@ mod tests {
             line: LineLocation::Right(2),
             start_line: None,
             comment: "foo".to_string(),
+            commit: None,
+        })];
+
+        test(input, &expected);
+    }
+
+    #[test]
+    fn thread_ignored() {
+        let input = include_str!("../testdata/thread_ignored");
+        let expected = vec![Comment::Inline(InlineComment {
+            file: "ch1.txt".to_string(),
+            line: LineLocation::Right(4),
+            start_line: None,
+            comment: "Comment 1".to_string(),
+            commit: None,
         })];
 
         test(input, &expected);
     }
 
+    #[test]
+    fn diff_line_locations() {
+        let diff = "Some description\n\
+                    diff --git a/foo.rs b/foo.rs\n\
+                    --- a/foo.rs\n\
+                    +++ b/foo.rs\n\
+                    @@ -1,2 +1,2 @@\n\
+                    -old\n\
+                    +new\n\
+                    \x20same\n";
+        let expected = vec![
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some(("foo.rs".to_string(), LineLocation::Left(1))),
+            Some(("foo.rs".to_string(), LineLocation::Right(1))),
+            Some(("foo.rs".to_string(), LineLocation::Right(2))),
+        ];
+
+        assert_eq!(diff_locations(diff), expected);
+
+        let file = |old, new| Some(("foo.rs".to_string(), old, new));
+        let expected = vec![
+            None,
+            None,
+            None,
+            None,
+            None,
+            file(Some(1), None),
+            file(None, Some(1)),
+            file(Some(2), Some(2)),
+        ];
+        assert_eq!(diff_line_numbers(diff), expected);
+    }
+
+    #[test]
+    fn comment_before_hunk_start() {
+        let input = include_str!("../testdata/comment_before_hunk_start");
+        let expected = vec![
+            Comment::Inline(InlineComment {
+                file: "ch1.txt".to_string(),
+                line: LineLocation::Right(1),
+                start_line: None,
+                comment: "Comment 1".to_string(),
+                commit: None,
+            }),
+            Comment::Inline(InlineComment {
+                file: "ch1.txt".to_string(),
+                line: LineLocation::Right(31),
+                start_line: None,
+                comment: "Comment 2".to_string(),
+                commit: None,
+            }),
+        ];
+
+        test(input, &expected);
+    }
+
+    #[test]
+    fn thread_reply() {
+        let input = include_str!("../testdata/thread_reply");
+        let expected = vec![
+            Comment::Inline(InlineComment {
+                file: "ch1.txt".to_string(),
+                line: LineLocation::Left(3),
+                start_line: None,
+                comment: "Comment 1".to_string(),
+                commit: None,
+            }),
+            Comment::Reply(ThreadReply {
+                thread: "PRRT_left".to_string(),
+                comment: "I disagree.".to_string(),
+                resolve: true,
+            }),
+            Comment::Reply(ThreadReply {
+                thread: "PRRT_right".to_string(),
+                comment: "".to_string(),
+                resolve: true,
+            }),
+            Comment::Inline(InlineComment {
+                file: "ch1.txt".to_string(),
+                line: LineLocation::Right(5),
+                start_line: Some(LineLocation::Right(4)),
+                comment: "Comment 2".to_string(),
+                commit: None,
+            }),
+        ];
+
+        test(input, &expected);
+    }
+
+    #[test]
+    fn thread_unknown_directive() {
+        let input = include_str!("../testdata/thread_unknown_directive");
+        test_fail(input);
+    }
+
+    #[test]
+    fn per_commit() {
+        let input = include_str!("../testdata/per_commit");
+        let first = "1111111111111111111111111111111111111111";
+        let second = "2222222222222222222222222222222222222222";
+        let expected = vec![
+            Comment::Commit(CommitComment {
+                commit: first.to_string(),
+                comment: "Not really.".to_string(),
+            }),
+            Comment::Inline(InlineComment {
+                file: "ch1.txt".to_string(),
+                line: LineLocation::Right(4),
+                start_line: None,
+                comment: "Drop this.".to_string(),
+                commit: Some(first.to_string()),
+            }),
+            Comment::Inline(InlineComment {
+                file: "ch1.txt".to_string(),
+                line: LineLocation::Right(5),
+                start_line: None,
+                comment: "Nice.".to_string(),
+                commit: Some(second.to_string()),
+            }),
+        ];
+
+        test_parser(ReviewParser::new(true), input, &expected);
+    }
+
+    #[test]
+    fn commit_start_in_description() {
+        let input = include_str!("../testdata/commit_start_in_description");
+        let expected = vec![
+            Comment::Review("> Fixes the regression from\n> commit 1111111111111111111111111111111111111111\n> \n\nWhich commit?".to_string()),
+            Comment::Inline(InlineComment {
+                file: "ch1.txt".to_string(),
+                line: LineLocation::Right(3),
+                start_line: None,
+                comment: "Not in a commit.".to_string(),
+                commit: None,
+            }),
+        ];
+
+        test(input, &expected);
+    }
+
     #[test]
     fn unterminated_span() {
         let input = include_str!("../testdata/unterminated_span");
@@ -847,6 +1505,7 @@ mod tests {
                 line: LineLocation::Right(2),
                 start_line: None,
                 comment: "Doesn't seem necessary ...".to_string(),
+                commit: None,
             }),
         ];
 
@@ -864,6 +1523,7 @@ mod tests {
                 line: LineLocation::Right(2),
                 start_line: None,
                 comment: "Doesn't seem necessary ...".to_string(),
+                commit: None,
             }),
         ];
 
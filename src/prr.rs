@@ -1,18 +1,35 @@
+use std::collections::HashMap;
 use std::env;
+use std::fmt::{Display, Formatter, Result as fmt_result};
 use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::{anyhow, bail, Context, Result};
-use git2::{ApplyLocation, Diff, Repository, StatusOptions};
-use http::{StatusCode, Uri};
+use futures::stream::{self, StreamExt};
+use git2::{ApplyLocation, Diff, Patch, Repository, StatusOptions};
+use http::Uri;
 use lazy_static::lazy_static;
-use octocrab::Octocrab;
 use prettytable::{format, row, Table};
-use serde_derive::Deserialize;
-use serde_json::{json, Value};
-
-use crate::parser::{FileComment, LineLocation, ReviewAction};
-use crate::review::{get_all_existing, Review, ReviewStatus};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::commits::{map_comments, render_commits, CommitPost};
+use crate::compose::ComposedComment;
+use crate::email::{import_reply, new_message_id, render_reply, rfc5322_date, Envelope};
+use crate::forge::{
+    gerrit_owner_repo, Forge, ForgeKind, Forgejo, Gerrit, GitHub, GitLab, PullRequestState,
+    PullRequestSummary, ReviewSubmission,
+};
+use crate::interdiff::interdiff;
+use crate::lsp;
+use crate::mbox::{parse_email, parse_series, read_mbox};
+use crate::parser::{diff_locations, has_suggestion, InlineComment, ReviewAction};
+use crate::review::{
+    get_all_existing, Posted, Review, ReviewComments, ReviewStatus, Submission, Unsubmitted,
+};
+use crate::sarif::sarif_comments;
+use crate::suggestion::find_suggestions;
 use regex::Regex;
 
 // Use lazy static to ensure regex is only compiled once
@@ -22,9 +39,26 @@ lazy_static! {
     //      danobi/prr-test-repo/6
     //
     static ref SHORT: Regex = Regex::new(r"^(?P<org>[\w\-_\.]+)/(?P<repo>[\w\-_\.]+)/(?P<pr_num>\d+)").unwrap();
+
+    // Regex for short GitLab input. The group may contain subgroups. Example:
+    //
+    //      group/subgroup/project/!123
+    //
+    static ref GITLAB_SHORT: Regex = Regex::new(r"^(?P<org>[\w\-_\./]+)/(?P<repo>[\w\-_\.]+)/!(?P<pr_num>\d+)").unwrap();
+
+    // Regex for Gerrit input. Example:
+    //
+    //      gerrit:platform/build~12345
+    //
+    static ref GERRIT_SHORT: Regex = Regex::new(r"^gerrit:(?P<project>[\w\-_\./]+)~(?P<change>\d+)$").unwrap();
 }
 
 const GITHUB_BASE_URL: &str = "https://api.github.com";
+const GITLAB_BASE_URL: &str = "https://gitlab.com";
+/// Number of PRs fetched from forges at once, eg. by `prr sync`
+const CONCURRENT_FETCHES: usize = 8;
+/// PRs listed by `prr inbox` unless configured otherwise
+const DEFAULT_INBOX_QUERY: &str = "is:open is:pr review-requested:@me archived:false";
 
 /// Resolves a GitHub token from either environment variables or config value.
 ///
@@ -62,6 +96,132 @@ where
     bail!("No GitHub token found in config or environment variables")
 }
 
+/// Resolves a token for a forge other than GitHub from either the config value
+/// or the first of `env_vars` that `lookup` finds, in that order. Empty values
+/// count as unset. Returns `None` if there is none.
+fn resolve_forge_token(
+    config_token: Option<&str>,
+    env_vars: &[&str],
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Option<String> {
+    config_token
+        .filter(|token| !token.is_empty())
+        .map(str::to_string)
+        .or_else(|| {
+            env_vars
+                .iter()
+                .filter_map(|var| lookup(var))
+                .find(|token| !token.is_empty())
+        })
+}
+
+/// How the PR of a review looks on its forge
+#[derive(Debug, PartialEq, Serialize)]
+pub struct RemoteStatus {
+    pub state: PullRequestState,
+    /// Whether the head of the PR moved since the review was downloaded
+    pub head_moved: bool,
+    pub title: String,
+    pub author: String,
+}
+
+/// A review as listed by `prr status`
+#[derive(Debug, Serialize)]
+pub struct StatusEntry {
+    pub handle: String,
+    pub status: ReviewStatus,
+    /// Path to the review file
+    pub path: PathBuf,
+    /// State of the PR on the forge, if it was fetched
+    pub remote: Option<RemoteStatus>,
+}
+
+/// What `prr sync` did with a PR
+#[derive(Debug, PartialEq)]
+pub enum SyncOutcome {
+    /// Downloaded into a new review file
+    Created,
+    /// Review file was downloaded again, since the PR changed
+    Refreshed,
+    /// Review file is for the latest version of the PR already
+    UpToDate,
+    /// Review file was left alone because of its status
+    Skipped(ReviewStatus),
+}
+
+impl Display for SyncOutcome {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt_result {
+        match self {
+            Self::Created => write!(f, "downloaded"),
+            Self::Refreshed => write!(f, "refreshed"),
+            Self::UpToDate => write!(f, "up to date"),
+            Self::Skipped(status) => write!(f, "left alone ({status})"),
+        }
+    }
+}
+
+/// Records that `item` of a submission in `progress` was posted as `id`
+fn record_posted(
+    review: &Review,
+    progress: &mut Submission,
+    item: String,
+    id: Option<String>,
+) -> Result<()> {
+    progress.posted.push(Posted { item, id });
+    review
+        .set_submission(progress)
+        .context("Failed to update review metadata")
+}
+
+/// Parses the comments in `contents`, a version of `review`'s review file
+///
+/// Comments in a per-commit review are moved onto the PR where possible. The
+/// ones that have to be posted on their commit are returned separately.
+fn review_posts(review: &Review, contents: &str) -> Result<(ReviewComments, Vec<CommitPost>)> {
+    let mut comments = review.parse_comments(contents)?;
+    let commit_comments = std::mem::take(&mut comments.commit_comments);
+    let commit_posts = map_comments(
+        &review.original()?,
+        &review.diff()?,
+        &mut comments.inline_comments,
+        &mut comments.file_comments,
+        commit_comments,
+    );
+
+    Ok((comments, commit_posts))
+}
+
+/// Returns whether two inline comments are attached to the same line(s)
+fn same_place(a: &InlineComment, b: &InlineComment) -> bool {
+    a.file == b.file && a.line == b.line && a.start_line == b.start_line
+}
+
+/// Opens the git repository at `path`, refusing to if it has uncommitted changes
+fn open_clean_repo(path: &Path) -> Result<Repository> {
+    let repo = Repository::open(path).context("Failed to open git repository")?;
+
+    // Best effort check to prevent clobbering any work in progress
+    let mut opts = StatusOptions::new();
+    opts.include_ignored(false);
+    let dirty = !repo
+        .statuses(Some(&mut opts))
+        .context("Failed to get repo status")?
+        .is_empty();
+    if dirty {
+        bail!("Working directory is dirty");
+    }
+
+    Ok(repo)
+}
+
+/// Returns the user's git identity in `Name <email>` form, if configured
+fn git_identity() -> Option<String> {
+    let config = git2::Config::open_default().ok()?;
+    let name = config.get_string("user.name").ok()?;
+    let email = config.get_string("user.email").ok()?;
+    Some(format!("{name} <{email}>"))
+}
+
 #[derive(Debug, Deserialize)]
 struct PrrConfig {
     /// GH personal token
@@ -72,6 +232,26 @@ struct PrrConfig {
     ///
     /// Useful for enterprise instances with custom URLs
     url: Option<String>,
+    /// GitLab personal token
+    gitlab_token: Option<String>,
+    /// GitLab URL. Defaults to gitlab.com
+    gitlab_url: Option<String>,
+    /// Forgejo (or Gitea) personal token
+    forgejo_token: Option<String>,
+    /// Forgejo (or Gitea) URL
+    forgejo_url: Option<String>,
+    /// Gerrit username
+    gerrit_username: Option<String>,
+    /// Gerrit HTTP password
+    gerrit_password: Option<String>,
+    /// Gerrit URL
+    gerrit_url: Option<String>,
+    /// GitHub search query for the PRs listed by `prr inbox`
+    inbox_query: Option<String>,
+    /// Forge hosting each repository, keyed by `owner/repo` or just `owner`.
+    /// Repositories not listed are on GitHub.
+    #[serde(default)]
+    forges: HashMap<String, ForgeKind>,
 
     /// Activate experimental PR metadata support. Currently this option
     /// just activates downloading the actual PR description in addition
@@ -100,8 +280,10 @@ pub struct Prr {
     config: Config,
     /// Path to local config file
     local_config: Option<PathBuf>,
-    /// Instantiated github client
-    crab: Octocrab,
+    /// Forges hosting the pull requests. Only configured forges are present
+    forges: HashMap<ForgeKind, Box<dyn Forge>>,
+    /// Why there is no GitHub forge, if there is none
+    github_error: Option<String>,
 }
 
 impl Config {
@@ -123,6 +305,23 @@ impl Config {
             None => GITHUB_BASE_URL.into(),
         }
     }
+
+    /// Returns GitLab URL to use
+    fn gitlab_url(&self) -> &str {
+        self.prr.gitlab_url.as_deref().unwrap_or(GITLAB_BASE_URL)
+    }
+
+    /// Returns the forge hosting `owner/repo`
+    ///
+    /// Only meaningful for PR strings that do not name their forge themselves.
+    fn forge_kind(&self, owner: &str, repo: &str) -> ForgeKind {
+        let forges = &self.prr.forges;
+        forges
+            .get(&format!("{owner}/{repo}"))
+            .or_else(|| forges.get(owner))
+            .copied()
+            .unwrap_or_default()
+    }
 }
 
 impl Prr {
@@ -154,23 +353,67 @@ impl Prr {
             }
         };
 
-        let token = resolve_github_token(config.prr.token.as_deref(), |var| env::var(var))
-            .context("Failed to locate GitHub token")?;
+        let env_var = |var: &str| env::var(var).ok();
+        let mut forges: HashMap<ForgeKind, Box<dyn Forge>> = HashMap::new();
+        if let Some(token) = resolve_forge_token(
+            config.prr.gitlab_token.as_deref(),
+            &["GITLAB_TOKEN"],
+            env_var,
+        ) {
+            let forge = GitLab::new(config.gitlab_url(), token)?;
+            forges.insert(ForgeKind::GitLab, Box::new(forge));
+        }
+
+        if let Some(url) = &config.prr.forgejo_url {
+            let env_vars = ["FORGEJO_TOKEN", "GITEA_TOKEN"];
+            if let Some(token) =
+                resolve_forge_token(config.prr.forgejo_token.as_deref(), &env_vars, env_var)
+            {
+                forges.insert(ForgeKind::Forgejo, Box::new(Forgejo::new(url, token)?));
+            }
+        }
+
+        if let (Some(url), Some(username)) = (&config.prr.gerrit_url, &config.prr.gerrit_username) {
+            let password = resolve_forge_token(
+                config.prr.gerrit_password.as_deref(),
+                &["GERRIT_PASSWORD"],
+                env_var,
+            );
+            if let Some(password) = password {
+                let forge = Gerrit::new(url, username, &password)?;
+                forges.insert(ForgeKind::Gerrit, Box::new(forge));
+            }
+        }
 
-        let octocrab = Octocrab::builder()
-            .personal_token(token)
-            .base_uri(config.url())
-            .context("Failed to parse github base URL")?
-            .build()
-            .context("Failed to create GH client")?;
+        // A missing GitHub token is only an error once GitHub is needed
+        let mut github_error = None;
+        match resolve_github_token(config.prr.token.as_deref(), |var| env::var(var)) {
+            Ok(token) => {
+                let forge = GitHub::new(token, config.url())?;
+                forges.insert(ForgeKind::GitHub, Box::new(forge));
+            }
+            Err(e) => github_error = Some(e.to_string()),
+        }
 
         Ok(Prr {
             config,
             local_config: local_config_path,
-            crab: octocrab,
+            forges,
+            github_error,
         })
     }
 
+    /// Returns the forge of the given kind
+    fn forge(&self, kind: ForgeKind) -> Result<&dyn Forge> {
+        match (self.forges.get(&kind), &self.github_error) {
+            (Some(forge), _) => Ok(forge.as_ref()),
+            (None, Some(err)) if kind == ForgeKind::GitHub => {
+                bail!("Failed to locate GitHub token: {}", err)
+            }
+            (None, _) => bail!("No {} token found in config or environment variables", kind),
+        }
+    }
+
     /// Returns path to prr workdir
     fn workdir(&self) -> Result<PathBuf> {
         // Try local config first
@@ -220,8 +463,11 @@ impl Prr {
     }
 
     /// Parses a PR string in the form of `danobi/prr/24` and returns
-    /// a tuple ("danobi", "prr", 24) or an error if string is malformed.
-    pub fn parse_pr_str(&self, s: &str) -> Result<(String, String, u64)> {
+    /// a tuple ("danobi", "prr", 24, ForgeKind::GitHub) or an error if string is malformed.
+    ///
+    /// GitLab merge requests are written `group/project/!24` and Gerrit changes
+    /// `gerrit:project~24`.
+    pub fn parse_pr_str(&self, s: &str) -> Result<(String, String, u64, ForgeKind)> {
         let repo = if let Some(local_config) = &self.config.local {
             if let Some(url) = &local_config.repository {
                 if url.ends_with('/') {
@@ -236,6 +482,32 @@ impl Prr {
             s.to_string()
         };
 
+        if let Some(captures) = GERRIT_SHORT.captures(&repo) {
+            let project = captures.name("project").unwrap().as_str();
+            let (owner, repo) = gerrit_owner_repo(project);
+            let change: u64 = captures
+                .name("change")
+                .unwrap()
+                .as_str()
+                .parse()
+                .context("Failed to parse change number")?;
+
+            return Ok((owner, repo, change, ForgeKind::Gerrit));
+        }
+
+        if let Some(captures) = GITLAB_SHORT.captures(&repo) {
+            let owner = captures.name("org").unwrap().as_str().to_owned();
+            let repo = captures.name("repo").unwrap().as_str().to_owned();
+            let pr_nr: u64 = captures
+                .name("pr_num")
+                .unwrap()
+                .as_str()
+                .parse()
+                .context("Failed to parse MR number")?;
+
+            return Ok((owner, repo, pr_nr, ForgeKind::GitLab));
+        }
+
         if let Some(captures) = SHORT.captures(&repo) {
             let owner = captures.name("org").unwrap().as_str().to_owned();
             let repo = captures.name("repo").unwrap().as_str().to_owned();
@@ -246,7 +518,8 @@ impl Prr {
                 .parse()
                 .context("Failed to parse pr number")?;
 
-            return Ok((owner, repo, pr_nr));
+            let kind = self.config.forge_kind(&owner, &repo);
+            return Ok((owner, repo, pr_nr, kind));
         }
 
         if repo.starts_with("http") || repo.contains("://") {
@@ -255,12 +528,54 @@ impl Prr {
             let path = uri.path().trim_start_matches('/');
             let segments: Vec<_> = path.split('/').collect();
 
-            if segments.len() >= 4 && segments[2] == "pull" {
+            // Forgejo uses `pulls` where GitHub uses `pull`
+            if segments.len() >= 4 && (segments[2] == "pull" || segments[2] == "pulls") {
                 let pr_num = segments[3]
                     .parse::<u64>()
                     .context("Failed to parse PR number")?;
+                let kind = if segments[2] == "pulls" {
+                    ForgeKind::Forgejo
+                } else {
+                    ForgeKind::GitHub
+                };
+
+                return Ok((
+                    segments[0].to_string(),
+                    segments[1].to_string(),
+                    pr_num,
+                    kind,
+                ));
+            }
+
+            // Gerrit: `c/project/+/24`
+            if let Some(idx) = segments.iter().position(|s| *s == "+") {
+                if idx >= 2 && segments[0] == "c" && segments.len() > idx + 1 {
+                    let change = segments[idx + 1]
+                        .parse::<u64>()
+                        .context("Failed to parse change number")?;
+                    let (owner, repo) = gerrit_owner_repo(&segments[1..idx].join("/"));
+
+                    return Ok((owner, repo, change, ForgeKind::Gerrit));
+                }
+            }
 
-                return Ok((segments[0].to_string(), segments[1].to_string(), pr_num));
+            // GitLab: `group/subgroup/project/-/merge_requests/24`
+            if let Some(idx) = segments
+                .windows(2)
+                .position(|w| w == ["-", "merge_requests"])
+            {
+                if idx >= 2 && segments.len() > idx + 2 {
+                    let mr_num = segments[idx + 2]
+                        .parse::<u64>()
+                        .context("Failed to parse MR number")?;
+
+                    return Ok((
+                        segments[..idx - 1].join("/"),
+                        segments[idx - 1].to_string(),
+                        mr_num,
+                        ForgeKind::GitLab,
+                    ));
+                }
             }
         }
 
@@ -268,245 +583,1118 @@ impl Prr {
     }
 
     /// Gets a new review from the internet and writes it to the filesystem
+    ///
+    /// If `since_last_review` is set, the review file only contains changes made
+    /// since the last submitted review. If `per_commit` is set, the review file
+    /// has a section for each commit instead of the squashed diff.
+    #[allow(clippy::too_many_arguments)]
     pub async fn get_pr(
         &self,
         owner: &str,
         repo: &str,
         pr_num: u64,
-        force: bool,
+        kind: ForgeKind,
+        unsubmitted: Unsubmitted,
+        since_last_review: bool,
+        per_commit: bool,
     ) -> Result<Review> {
-        let pr_handler = self.crab.pulls(owner, repo);
-
-        let diff = pr_handler
-            .get_diff(pr_num)
-            .await
-            .context("Failed to fetch diff")?;
+        let reviewed_diff = if since_last_review {
+            let review = self.get_review(owner, repo, pr_num, kind)?;
+            Some(review.submitted_diff()?)
+        } else {
+            None
+        };
 
-        let pr = pr_handler.get(pr_num).await.context("Failed to fetch pr")?;
-        let commit_id = pr.head.sha;
+        let forge = self.forge(kind)?;
+        let (diff, pr, threads) = tokio::try_join!(
+            async {
+                forge
+                    .get_diff(owner, repo, pr_num)
+                    .await
+                    .context("Failed to fetch diff")
+            },
+            async {
+                forge
+                    .get_pr(owner, repo, pr_num)
+                    .await
+                    .context("Failed to fetch pr")
+            },
+            async {
+                // Threads are attached to lines of the PR head, which need not be
+                // the same lines in any one commit
+                if per_commit {
+                    return Ok(Vec::new());
+                }
+                forge
+                    .get_review_threads(owner, repo, pr_num)
+                    .await
+                    .context("Failed to fetch review threads")
+            },
+        )?;
+        let commit_id = pr.head_sha;
 
         let mut pr_description = None;
         if self.is_pr_metadata_experiment_active() {
-            pr_description = Some(pr.body.unwrap_or("".to_string()));
+            pr_description = Some(pr.description);
         }
 
-        Review::new(
+        let (diff, pr_diff) = match reviewed_diff {
+            Some(reviewed) => {
+                let interdiff = interdiff(&reviewed, &diff);
+                if interdiff.is_empty() {
+                    bail!("Nothing changed since the last submitted review");
+                }
+
+                (interdiff, Some(diff))
+            }
+            None if per_commit => {
+                let commits = forge
+                    .get_commits(owner, repo, pr_num)
+                    .await
+                    .context("Failed to fetch commits")?;
+                (render_commits(&commits), Some(diff))
+            }
+            None => (diff, None),
+        };
+
+        let review = Review::new(
             &self.workdir()?,
             diff,
             owner,
             repo,
             pr_description,
             pr_num,
+            kind,
             commit_id,
-            force,
+            &threads,
+            unsubmitted,
+        )?;
+        if let Some(d) = pr_diff {
+            review.set_pr_diff(d)?;
+        }
+        if per_commit && !since_last_review {
+            review.set_per_commit()?;
+        }
+
+        Ok(review)
+    }
+
+    /// Brings the review of a single PR up to date for `sync()`
+    async fn sync_pr(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        kind: ForgeKind,
+    ) -> Result<SyncOutcome> {
+        let review = self.get_review(owner, repo, pr_num, kind)?;
+        if review.has_metadata() {
+            match review.status()? {
+                ReviewStatus::New => {
+                    let pr = self
+                        .forge(kind)?
+                        .get_pr(owner, repo, pr_num)
+                        .await
+                        .context("Failed to fetch pr")?;
+                    if review.commit_id()?.as_deref() == Some(pr.head_sha.as_str()) {
+                        return Ok(SyncOutcome::UpToDate);
+                    }
+                }
+                status => return Ok(SyncOutcome::Skipped(status)),
+            }
+        }
+
+        let outcome = if review.has_metadata() {
+            SyncOutcome::Refreshed
+        } else {
+            SyncOutcome::Created
+        };
+        // Only reviews without changes get this far
+        self.get_pr(
+            owner,
+            repo,
+            pr_num,
+            kind,
+            Unsubmitted::Discard,
+            false,
+            false,
         )
+        .await?;
+
+        Ok(outcome)
+    }
+
+    /// Downloads `prs`, or the PRs in the inbox if none are given, concurrently
+    ///
+    /// PRs without a review file get one. Review files that were not started
+    /// yet are refreshed if the PR changed, and all others are left alone.
+    /// Returns what happened to each PR, by handle.
+    pub async fn sync(&self, prs: &[String]) -> Result<Vec<(String, Result<SyncOutcome>)>> {
+        let targets = if prs.is_empty() {
+            self.inbox()
+                .await?
+                .into_iter()
+                .map(|(pr, _)| {
+                    let handle = format!("{}/{}/{}", pr.owner, pr.repo, pr.pr_num);
+                    (handle, pr.owner, pr.repo, pr.pr_num, ForgeKind::GitHub)
+                })
+                .collect()
+        } else {
+            prs.iter()
+                .map(|pr| {
+                    let (owner, repo, pr_num, kind) = self.parse_pr_str(pr)?;
+                    Ok((pr.clone(), owner, repo, pr_num, kind))
+                })
+                .collect::<Result<Vec<_>>>()?
+        };
+
+        Ok(stream::iter(targets)
+            .map(|(handle, owner, repo, pr_num, kind)| async move {
+                let outcome = self.sync_pr(&owner, &repo, pr_num, kind).await;
+                (handle, outcome)
+            })
+            .buffered(CONCURRENT_FETCHES)
+            .collect()
+            .await)
+    }
+
+    /// Syncs PRs like `sync()` and prints what happened
+    pub async fn print_sync(&self, prs: &[String]) -> Result<()> {
+        let mut failed = 0;
+        for (handle, outcome) in self.sync(prs).await? {
+            match outcome {
+                Ok(SyncOutcome::Skipped(status)) if status.is_unsubmitted() => {
+                    eprintln!("Warning: {handle} has unsubmitted changes, leaving it alone")
+                }
+                Ok(outcome) => println!("{handle}: {outcome}"),
+                Err(e) => {
+                    failed += 1;
+                    eprintln!("{handle}: {e:#}");
+                }
+            }
+        }
+
+        if failed != 0 {
+            bail!("Failed to sync {} pull request(s)", failed);
+        }
+
+        Ok(())
+    }
+
+    /// Reads a patch series from the mbox (or directory of patches) at `path`
+    /// and writes it to the filesystem as a review
+    ///
+    /// Each patch gets its own section, like with `per_commit`. No forge is
+    /// involved, `kind` only decides where the review is filed.
+    pub fn get_mbox(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        kind: ForgeKind,
+        path: &Path,
+        unsubmitted: Unsubmitted,
+    ) -> Result<Review> {
+        let texts = read_mbox(path)?;
+        let parsed =
+            parse_series(&texts).with_context(|| format!("Failed to parse {}", path.display()))?;
+        // `parse_series()` fails if there are no patches
+        let commit_id = parsed.commits.last().unwrap().sha.clone();
+
+        let review = Review::new(
+            &self.workdir()?,
+            render_commits(&parsed.commits),
+            owner,
+            repo,
+            parsed.cover_letter,
+            pr_num,
+            kind,
+            commit_id,
+            &[],
+            unsubmitted,
+        )?;
+        review.set_series(parsed.series)?;
+
+        Ok(review)
     }
 
     /// Gets an existing review from the filesystem
-    pub fn get_review(&self, owner: &str, repo: &str, pr_num: u64) -> Result<Review> {
+    pub fn get_review(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        kind: ForgeKind,
+    ) -> Result<Review> {
         let workdir = self.workdir()?;
-        Ok(Review::new_existing(&workdir, owner, repo, pr_num))
+        Ok(Review::new_existing(&workdir, owner, repo, pr_num, kind))
     }
 
-    pub async fn submit_pr(&self, owner: &str, repo: &str, pr_num: u64, debug: bool) -> Result<()> {
-        let review = Review::new_existing(&self.workdir()?, owner, repo, pr_num);
-        let (review_action, review_comment, inline_comments, file_comments) = review.comments()?;
-
-        if review_comment.is_empty()
-            && inline_comments.is_empty()
-            && review_action != ReviewAction::Approve
-        {
-            bail!("No review comments");
+    /// Submits a review. With `pending`, the review is left pending instead.
+    ///
+    /// What was posted is recorded as it is posted. If posting fails partway,
+    /// rerunning with `resume` posts only what is missing.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn submit_pr(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        kind: ForgeKind,
+        pending: bool,
+        resume: bool,
+        debug: bool,
+    ) -> Result<()> {
+        let review = Review::new_existing(&self.workdir()?, owner, repo, pr_num, kind);
+        if review.series()?.is_some() {
+            bail!(
+                "{} was read from an mbox and cannot be submitted to a forge",
+                review.handle()
+            );
+        }
+        if review.pending()?.is_some() {
+            bail!(
+                "{} is already pending. Finalize it with `prr submit --finalize`.",
+                review.handle()
+            );
         }
 
-        let mut body = json!({
-            "body": review_comment,
-            "event": match review_action {
-                ReviewAction::Approve => "APPROVE",
-                ReviewAction::RequestChanges => "REQUEST_CHANGES",
-                ReviewAction::Comment => "COMMENT"
+        let contents = review.contents()?;
+        let mut progress = match review.submission()? {
+            Some(progress) if !progress.complete => {
+                if !resume {
+                    bail!(
+                        "A previous submission of {} failed partway. \
+                        Re-run this command with --resume to post what is missing.",
+                        review.handle()
+                    );
+                }
+                if progress.contents != contents {
+                    bail!(
+                        "The review file changed since the submission that failed. \
+                        Undo the changes to resume it."
+                    );
+                }
+                progress
+            }
+            _ if resume => bail!("{} has no failed submission to resume", review.handle()),
+            _ => Submission {
+                contents,
+                ..Default::default()
             },
-            "comments": inline_comments
-                .iter()
-                .map(|c| {
-                    let (line, side) = match c.line {
-                        LineLocation::Left(line) => (line, "LEFT"),
-                        LineLocation::Right(line) => (line, "RIGHT"),
-                    };
-
-                    let mut json_comment = json!({
-                        "path": c.file,
-                        "line": line,
-                        "body": c.comment,
-                        "side": side,
-                    });
-                    if let Some(start_line) = &c.start_line {
-                        let (line, side) = match start_line {
-                            LineLocation::Left(line) => (line, "LEFT"),
-                            LineLocation::Right(line) => (line, "RIGHT"),
-                        };
-
-                        json_comment["start_line"] = (*line).into();
-                        json_comment["start_side"] = side.into();
-                    }
+        };
 
-                    json_comment
-                })
-                .collect::<Vec<Value>>(),
-        });
+        let forge = self.forge(review.forge())?;
+        let (
+            ReviewComments {
+                review_action,
+                review_comment,
+                inline_comments,
+                file_comments,
+                thread_replies,
+                pending: pending_directive,
+                ..
+            },
+            commit_posts,
+        ) = review_posts(&review, &progress.contents)?;
+        let pending = pending || pending_directive;
+        // Other forges would show the suggestion's fenced code block as is
+        if review.forge() != ForgeKind::GitHub
+            && inline_comments.iter().any(|c| has_suggestion(&c.comment))
+        {
+            bail!(
+                "{} is on {}, where `@prr suggest` is not supported",
+                review.handle(),
+                review.forge()
+            );
+        }
+
+        // Replying to threads does not require submitting a review, and neither
+        // do file comments, which are posted on their own
+        let posts_review = !review_comment.is_empty()
+            || !inline_comments.is_empty()
+            || review_action == ReviewAction::Approve;
+        let has_review = posts_review || !file_comments.is_empty();
+        if !has_review && thread_replies.is_empty() && commit_posts.is_empty() {
+            bail!("No review comments");
+        }
 
         let commit = review.commit_id()?;
-        if let Some(id) = &commit {
-            if let serde_json::Value::Object(ref mut obj) = body {
-                obj.insert("commit_id".to_string(), json!(id));
-            }
-        } else if !file_comments.is_empty() {
+        if commit.is_none() && !file_comments.is_empty() {
             bail!(
                 "Metadata contained no commit_id, but it's required to leave file-level comments"
             );
         }
 
-        if debug {
-            println!("{}", serde_json::to_string_pretty(&body)?);
+        let submission = ReviewSubmission {
+            action: &review_action,
+            body: &review_comment,
+            comments: &inline_comments,
+            commit_id: commit.as_deref(),
+        };
+
+        if pending {
+            if !has_review {
+                bail!("No review comments to leave pending");
+            }
+            if !file_comments.is_empty() || !thread_replies.is_empty() || !commit_posts.is_empty() {
+                bail!(
+                    "Only the review comment and inline comments can be left pending. \
+                    File comments, thread replies and commit comments would be posted right away."
+                );
+            }
+
+            if debug {
+                println!("{:#?}", submission);
+            }
+            let id = forge
+                .submit_pending_review(owner, repo, pr_num, &submission)
+                .await?;
+            // Remembered for `prr amend`
+            progress.posted.push(Posted {
+                item: "review".to_string(),
+                id: Some(id.clone()),
+            });
+            progress.complete = true;
+            return review
+                .set_pending(id)
+                .and_then(|_| review.set_submission(&progress))
+                .context("Failed to update review metadata");
         }
-        self.submit_review(&review, owner, repo, pr_num, &body)
-            .await?;
 
-        for fc in &file_comments {
-            self.submit_file_comment(owner, repo, pr_num, commit.as_ref().unwrap(), fc)
-                .await?
+        // Record the submission before posting anything, so that a failure
+        // partway is noticed next time
+        review
+            .set_submission(&progress)
+            .context("Failed to update review metadata")?;
+
+        if posts_review && !progress.has_posted("review") {
+            if debug {
+                println!("{:#?}", submission);
+            }
+            let id = forge
+                .submit_review(owner, repo, pr_num, &submission)
+                .await?;
+            record_posted(&review, &mut progress, "review".to_string(), id)?;
         }
 
-        Ok(())
+        for (idx, fc) in file_comments.iter().enumerate() {
+            let item = format!("file {idx}");
+            if progress.has_posted(&item) {
+                continue;
+            }
+            let id = forge
+                .submit_file_comment(owner, repo, pr_num, commit.as_ref().unwrap(), fc)
+                .await?;
+            record_posted(&review, &mut progress, item, id)?;
+        }
+
+        for (idx, reply) in thread_replies.iter().enumerate() {
+            let item = format!("reply {idx}");
+            if progress.has_posted(&item) {
+                continue;
+            }
+            if debug {
+                println!("{:#?}", reply);
+            }
+            let id = forge
+                .submit_thread_reply(owner, repo, pr_num, reply)
+                .await
+                .with_context(|| format!("Failed to reply to thread {}", reply.thread))?;
+            record_posted(&review, &mut progress, item, id)?;
+        }
+
+        for (idx, post) in commit_posts.iter().enumerate() {
+            let item = format!("commit {idx}");
+            if progress.has_posted(&item) {
+                continue;
+            }
+            if debug {
+                println!("{:#?}", post);
+            }
+            let id = forge
+                .submit_commit_comment(owner, repo, pr_num, post)
+                .await
+                .with_context(|| format!("Failed to comment on commit {}", post.commit))?;
+            record_posted(&review, &mut progress, item, id)?;
+        }
+
+        progress.complete = true;
+        review
+            .set_submission(&progress)
+            .and_then(|_| review.mark_submitted())
+            .context("Failed to update review metadata")
     }
 
-    async fn submit_review(
+    /// Submits a review that was left pending, with the review action currently
+    /// in the review file
+    pub async fn finalize_pr(
         &self,
-        review: &Review,
         owner: &str,
         repo: &str,
         pr_num: u64,
-        body: &Value,
+        kind: ForgeKind,
+        debug: bool,
     ) -> Result<()> {
-        let path = format!("repos/{}/{}/pulls/{}/reviews", owner, repo, pr_num);
-        let uri = Uri::builder()
-            .path_and_query(path)
-            .build()
-            .context("Invalid URI")?;
-        match self.crab._post(uri, Some(body)).await {
-            Ok(resp) => {
-                let status = resp.status();
-                if status != StatusCode::OK {
-                    let text = self
-                        .crab
-                        .body_to_string(resp)
-                        .await
-                        .context("Failed to decode failed response")?;
-                    bail!("Error during POST: Status code: {}, Body: {}", status, text);
+        let review = Review::new_existing(&self.workdir()?, owner, repo, pr_num, kind);
+        let id = review
+            .pending()?
+            .ok_or_else(|| anyhow!("{} has no pending review", review.handle()))?;
+        let mut submission = review
+            .submission()?
+            .ok_or_else(|| anyhow!("{} has no record of the pending review", review.handle()))?;
+        let forge = self.forge(review.forge())?;
+        let contents = review.contents()?;
+        let comments = review.parse_comments(&contents)?;
+
+        // Only the review action and comment are sent along when finalizing.
+        // Anything else the forge already has from when the review was left pending.
+        let left_pending = review.parse_comments(&submission.contents)?;
+        if comments.inline_comments != left_pending.inline_comments
+            || comments.file_comments != left_pending.file_comments
+            || comments.thread_replies != left_pending.thread_replies
+            || comments.commit_comments != left_pending.commit_comments
+        {
+            bail!(
+                "Comments in {} changed since it was left pending. Only the review action and \
+                review comment can change when finalizing, so undo the other changes. Comments \
+                can be edited with `prr amend` once the review is finalized.",
+                review.handle()
+            );
+        }
+        if debug {
+            println!("Finalizing review {id}: {:#?}", comments.review_action);
+        }
+
+        forge
+            .finalize_review(
+                owner,
+                repo,
+                pr_num,
+                &id,
+                &comments.review_action,
+                &comments.review_comment,
+            )
+            .await?;
+
+        // What was finalized is what `prr amend` has to compare against. That is
+        // the comments left pending, with the current review action and comment.
+        submission.contents = contents;
+        review
+            .set_submission(&submission)
+            .and_then(|_| review.mark_submitted())
+            .context("Failed to update review metadata")
+    }
+
+    /// Posts the changes made to the review file since it was submitted
+    ///
+    /// Edited comments are updated on the forge and removed ones are deleted.
+    /// Nothing can be added, and replies, commit comments and the review action
+    /// cannot be changed.
+    pub async fn amend_pr(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        kind: ForgeKind,
+        debug: bool,
+    ) -> Result<()> {
+        let review = Review::new_existing(&self.workdir()?, owner, repo, pr_num, kind);
+        if review.status()? != ReviewStatus::Submitted {
+            bail!("{} has not been submitted yet", review.handle());
+        }
+        let mut submission = match review.submission()? {
+            Some(submission) if submission.complete => submission,
+            _ => bail!(
+                "There is no record of what was submitted for {}",
+                review.handle()
+            ),
+        };
+        let posted_id = |item: &str| {
+            let posted = submission
+                .posted
+                .iter()
+                .find(|p| p.item == item)
+                .ok_or_else(|| anyhow!("There is no record of the {} being submitted", item))?;
+            posted.id.clone().ok_or_else(|| {
+                anyhow!(
+                    "The ID the forge gave the {} is unknown, so it cannot be amended. \
+                    Edit it on the forge instead.",
+                    item
+                )
+            })
+        };
+
+        let forge = self.forge(review.forge())?;
+        let contents = review.contents()?;
+        let (old, old_commit_posts) = review_posts(&review, &submission.contents)?;
+        let (new, new_commit_posts) = review_posts(&review, &contents)?;
+        if new.review_action != old.review_action {
+            bail!("The review action cannot be changed once the review is submitted");
+        }
+        if new.thread_replies != old.thread_replies || new_commit_posts != old_commit_posts {
+            bail!("Thread replies and commit comments cannot be amended");
+        }
+        if let Some(c) = new
+            .inline_comments
+            .iter()
+            .find(|c| !old.inline_comments.iter().any(|o| same_place(o, c)))
+        {
+            bail!(
+                "The new comment on {} cannot be added to a submitted review. \
+                Start a new review with `prr get` instead.",
+                c.file
+            );
+        }
+        if let Some(c) = new
+            .file_comments
+            .iter()
+            .find(|c| !old.file_comments.iter().any(|o| o.file == c.file))
+        {
+            bail!(
+                "The new file comment on {} cannot be added to a submitted review. \
+                Start a new review with `prr get` instead.",
+                c.file
+            );
+        }
+
+        // Work out everything to do before doing any of it
+        let mut edits = Vec::new();
+        let mut deletions = Vec::new();
+        let changed_inline: Vec<_> = old
+            .inline_comments
+            .iter()
+            .map(|o| (o, new.inline_comments.iter().find(|n| same_place(o, n))))
+            .filter(|(o, n)| n.map(|n| &n.comment) != Some(&o.comment))
+            .collect();
+        if !changed_inline.is_empty() {
+            let posted = forge
+                .get_review_comments(owner, repo, pr_num, &posted_id("review")?)
+                .await
+                .context("Failed to fetch submitted comments")?;
+            for (o, n) in changed_inline {
+                let id = posted
+                    .iter()
+                    .find(|p| p.file == o.file && p.line == o.line && p.start_line == o.start_line)
+                    .map(|p| p.id.clone())
+                    .ok_or_else(|| anyhow!("Failed to find the submitted comment on {}", o.file))?;
+                match n {
+                    Some(n) => edits.push((id, &n.comment)),
+                    None => deletions.push(id),
+                }
+            }
+        }
+        let mut file_ids = Vec::new();
+        for (idx, o) in old.file_comments.iter().enumerate() {
+            let id = posted_id(&format!("file {idx}"))?;
+            match new.file_comments.iter().position(|n| n.file == o.file) {
+                Some(n_idx) => {
+                    let comment = &new.file_comments[n_idx].comment;
+                    if *comment != o.comment {
+                        edits.push((id.clone(), comment));
+                    }
+                    file_ids.push((n_idx, id));
                 }
+                None => deletions.push(id),
+            }
+        }
 
-                review
-                    .mark_submitted()
-                    .context("Failed to update review metadata")?;
+        let review_body = (new.review_comment != old.review_comment).then_some(&new.review_comment);
+        if review_body.is_none() && edits.is_empty() && deletions.is_empty() {
+            bail!(
+                "No comments changed since {} was submitted",
+                review.handle()
+            );
+        }
 
-                Ok(())
+        if let Some(body) = review_body {
+            if debug {
+                println!("Editing review comment: {body:?}");
             }
-            // GH is known to send unescaped control characters in JSON responses which
-            // serde will fail to parse (not that it should succeed)
-            Err(octocrab::Error::Json {
-                source: _,
-                backtrace: _,
-            }) => {
-                eprintln!("Warning: GH response had invalid JSON");
-                Ok(())
+            forge
+                .edit_review(owner, repo, pr_num, &posted_id("review")?, body)
+                .await
+                .context("Failed to edit review comment")?;
+        }
+        for (id, body) in edits {
+            if debug {
+                println!("Editing comment {id}: {body:?}");
+            }
+            forge
+                .edit_comment(owner, repo, &id, body)
+                .await
+                .with_context(|| format!("Failed to edit comment {id}"))?;
+        }
+        for id in deletions {
+            if debug {
+                println!("Deleting comment {id}");
             }
-            Err(e) => bail!("Error during POST: {}", e),
+            forge
+                .delete_comment(owner, repo, &id)
+                .await
+                .with_context(|| format!("Failed to delete comment {id}"))?;
         }
+
+        // File comments are recorded by their position in the review file,
+        // which may have changed
+        submission.posted.retain(|p| !p.item.starts_with("file "));
+        submission
+            .posted
+            .extend(file_ids.into_iter().map(|(idx, id)| Posted {
+                item: format!("file {idx}"),
+                id: Some(id),
+            }));
+        submission.contents = contents;
+        review
+            .set_submission(&submission)
+            .context("Failed to update review metadata")
     }
 
-    async fn submit_file_comment(
+    pub fn apply_pr(
         &self,
         owner: &str,
         repo: &str,
         pr_num: u64,
-        commit_id: &str,
-        fc: &FileComment,
+        kind: ForgeKind,
+        apply_repo: &Path,
     ) -> Result<()> {
-        let body = json!({
-            "body": fc.comment,
-            "commit_id": commit_id,
-            "path": fc.file,
-            "subject_type": "file",
-        });
-        let path = format!("repos/{}/{}/pulls/{}/comments", owner, repo, pr_num);
-        let uri = Uri::builder()
-            .path_and_query(path)
-            .build()
-            .context("Invalid URI")?;
-        match self.crab._post(uri, Some(&body)).await {
-            Ok(resp) => {
-                let status = resp.status();
-                if status != StatusCode::CREATED {
-                    let text = self
-                        .crab
-                        .body_to_string(resp)
-                        .await
-                        .context("Failed to decode failed response")?;
-                    bail!("Error during POST: Status code: {}, Body: {}", status, text);
-                }
+        let review = Review::new_existing(&self.workdir()?, owner, repo, pr_num, kind);
+        let diff = Diff::from_buffer(review.diff()?.as_bytes()).context("Failed to load diff")?;
+        let apply_repo = open_clean_repo(apply_repo)?;
+
+        apply_repo
+            .apply(&diff, ApplyLocation::WorkDir, None)
+            .context("Failed to apply diff")
+    }
+
+    /// Applies the changes suggested in a pull request's review threads to the
+    /// working directory
+    ///
+    /// Each suggestion is applied on its own, so one that no longer applies
+    /// cleanly does not hold back the others. Those are reported at the end.
+    pub async fn apply_suggestions(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        kind: ForgeKind,
+        apply_repo: &Path,
+    ) -> Result<()> {
+        let forge = self.forge(kind)?;
+        let pr = forge
+            .get_pr(owner, repo, pr_num)
+            .await
+            .context("Failed to fetch pull request")?;
+        let threads = forge
+            .get_review_threads(owner, repo, pr_num)
+            .await
+            .context("Failed to fetch review threads")?;
+        let suggestions = find_suggestions(&threads);
+        if suggestions.is_empty() {
+            println!("No suggestions to apply");
+            return Ok(());
+        }
+
+        let apply_repo = open_clean_repo(apply_repo)?;
+        let workdir = apply_repo
+            .workdir()
+            .ok_or_else(|| anyhow!("Repository has no working directory"))?
+            .to_path_buf();
+        // Suggestions are made against the head of the PR, which may have
+        // moved on locally since
+        let head = apply_repo
+            .revparse_single(&pr.head_sha)
+            .and_then(|o| o.peel_to_tree())
+            .with_context(|| {
+                format!(
+                    "PR head {} is not in the repository, fetch it first",
+                    pr.head_sha
+                )
+            })?;
+
+        let mut failed = Vec::new();
+        for suggestion in &suggestions {
+            let applied = (|| -> Result<()> {
+                let path = Path::new(&suggestion.file);
+                let blob = head
+                    .get_path(path)?
+                    .to_object(&apply_repo)?
+                    .peel_to_blob()?;
+                let original = std::str::from_utf8(blob.content())?;
+                let current = fs::read_to_string(workdir.join(path))?;
+                let new = suggestion.apply(original, &current)?;
+                let mut patch = Patch::from_buffers(
+                    current.as_bytes(),
+                    Some(path),
+                    new.as_bytes(),
+                    Some(path),
+                    None,
+                )?;
+                let diff = Diff::from_buffer(&patch.to_buf()?)?;
+                apply_repo.apply(&diff, ApplyLocation::WorkDir, None)?;
                 Ok(())
+            })();
+
+            if let Err(e) = applied {
+                eprintln!("Suggestion {} does not apply: {}", suggestion, e);
+                failed.push(suggestion);
             }
-            // GH is known to send unescaped control characters in JSON responses which
-            // serde will fail to parse (not that it should succeed)
-            Err(octocrab::Error::Json {
-                source: _,
-                backtrace: _,
-            }) => {
-                eprintln!("Warning: GH response had invalid JSON");
-                Ok(())
+        }
+
+        println!(
+            "Applied {} of {} suggestions",
+            suggestions.len() - failed.len(),
+            suggestions.len()
+        );
+        if !failed.is_empty() {
+            bail!("{} suggestion(s) no longer apply cleanly", failed.len());
+        }
+
+        Ok(())
+    }
+
+    /// Renders a review as an email reply to the patch series it was read from
+    ///
+    /// Reviews fetched from a forge are rendered too, but without threading.
+    pub fn export_email(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        kind: ForgeKind,
+    ) -> Result<String> {
+        let review = Review::new_existing(&self.workdir()?, owner, repo, pr_num, kind);
+        let comments = review.comments()?;
+        let original = review.original()?;
+        let series = review.series()?;
+        let parent = series
+            .as_ref()
+            .and_then(|s| s.cover.as_ref().or(s.patches.first()));
+
+        let now = SystemTime::now();
+        let from = git_identity();
+        let envelope = Envelope {
+            message_id: new_message_id(from.as_deref(), now),
+            from,
+            date: rfc5322_date(now),
+        };
+
+        Ok(render_reply(
+            &original,
+            &comments,
+            parent,
+            &review.handle(),
+            &envelope,
+        ))
+    }
+
+    /// Turns an email reply to a review into the review's review file
+    pub fn import_email(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        kind: ForgeKind,
+        path: &Path,
+        force: bool,
+    ) -> Result<Review> {
+        let review = Review::new_existing(&self.workdir()?, owner, repo, pr_num, kind);
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let (email, body) = parse_email(&text)?;
+        let contents = import_reply(&body, &review.original()?, &email.from)?;
+        review.import(&contents, force)?;
+
+        Ok(review)
+    }
+
+    /// Returns the review of a PR, which must have been downloaded already
+    fn existing_review(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        kind: ForgeKind,
+    ) -> Result<Review> {
+        let review = self.get_review(owner, repo, pr_num, kind)?;
+        if !review.has_metadata() {
+            bail!(
+                "No review for {}. Run `prr get` to download it first.",
+                review.handle()
+            );
+        }
+
+        Ok(review)
+    }
+
+    /// Adds the results in the SARIF log at `path` to an existing review
+    ///
+    /// Results outside of the diff are dropped, unless `keep_outside` is set.
+    /// Then they are listed in file-level comments.
+    pub fn import_sarif(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        kind: ForgeKind,
+        path: &Path,
+        keep_outside: bool,
+    ) -> Result<Review> {
+        let review = self.existing_review(owner, repo, pr_num, kind)?;
+        let sarif = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let locations = diff_locations(&review.original()?);
+        let imported = sarif_comments(&sarif, &locations, keep_outside)?;
+        if imported.dropped != 0 {
+            eprintln!(
+                "Warning: dropped {} result(s) that are not part of the diff",
+                imported.dropped
+            );
+        }
+        if !imported.comments.is_empty() {
+            review.compose(&imported.comments)?;
+        }
+
+        Ok(review)
+    }
+
+    /// Adds the comments in `json`, a list of `ComposedComment`s, to an existing review
+    pub fn compose(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        kind: ForgeKind,
+        json: &str,
+    ) -> Result<Review> {
+        let review = self.existing_review(owner, repo, pr_num, kind)?;
+        let comments: Vec<ComposedComment> =
+            serde_json::from_str(json).context("Failed to parse comments")?;
+        review.compose(&comments)?;
+
+        Ok(review)
+    }
+
+    /// Fetches the PR a review is for, to see how it changed since. Returns
+    /// `None` for reviews that are not on a forge.
+    pub async fn remote_status(&self, review: &Review) -> Result<Option<RemoteStatus>> {
+        if review.series()?.is_some() {
+            return Ok(None);
+        }
+
+        let (owner, repo, pr_num) = review.pr();
+        let pr = self
+            .forge(review.forge())?
+            .get_pr(owner, repo, pr_num)
+            .await
+            .context("Failed to fetch pr")?;
+        let head_moved = review.commit_id()?.as_deref() != Some(pr.head_sha.as_str());
+
+        Ok(Some(RemoteStatus {
+            state: pr.state,
+            head_moved,
+            title: pr.title,
+            author: pr.author,
+        }))
+    }
+
+    /// Returns the status of all reviews. With `remote`, the PRs are fetched
+    /// to see how they look on the forge as well.
+    pub async fn status(&self, remote: bool) -> Result<Vec<StatusEntry>> {
+        let reviews = get_all_existing(&self.workdir()?).context("Failed to get all reviews")?;
+        let mut remote_statuses: Vec<_> = if remote {
+            stream::iter(&reviews)
+                .map(|review| self.remote_status(review))
+                .buffered(CONCURRENT_FETCHES)
+                .collect()
+                .await
+        } else {
+            Vec::new()
+        };
+        remote_statuses.resize_with(reviews.len(), || Ok(None));
+
+        let mut entries = Vec::with_capacity(reviews.len());
+        for (review, remote_status) in reviews.iter().zip(remote_statuses) {
+            let remote = remote_status.unwrap_or_else(|e| {
+                eprintln!("Warning: {}: {:#}", review.handle(), e);
+                None
+            });
+            entries.push(StatusEntry {
+                handle: review.handle(),
+                status: review.status()?,
+                path: review.path(),
+                remote,
+            });
+        }
+
+        Ok(entries)
+    }
+
+    /// Prints the status of all reviews, as a table or as JSON
+    pub async fn print_status(&self, no_titles: bool, remote: bool, json: bool) -> Result<()> {
+        let entries = self.status(remote).await?;
+        if json {
+            println!("{}", serde_json::to_string_pretty(&entries)?);
+            return Ok(());
+        }
+
+        let mut table = Table::new();
+        let mut table_fmt = *format::consts::FORMAT_CLEAN;
+        // Get rid of leading padding on each line
+        table_fmt.padding(0, 2);
+        table.set_format(table_fmt);
+        if !no_titles {
+            if remote {
+                table.set_titles(row![
+                    "Handle",
+                    "Status",
+                    "State",
+                    "Head",
+                    "Author",
+                    "Title",
+                    "Review file"
+                ])
+            } else {
+                table.set_titles(row!["Handle", "Status", "Review file"])
             }
-            Err(e) => bail!("Error during POST: {}", e),
         }
+
+        for entry in entries {
+            if !remote {
+                table.add_row(row![entry.handle, entry.status, entry.path.display()]);
+                continue;
+            }
+
+            let (state, head, author, title) = match entry.remote {
+                Some(s) => (
+                    s.state.to_string(),
+                    if s.head_moved { "moved" } else { "same" },
+                    s.author,
+                    s.title,
+                ),
+                None => ("-".to_string(), "-", "-".to_string(), "-".to_string()),
+            };
+            table.add_row(row![
+                entry.handle,
+                entry.status,
+                state,
+                head,
+                author,
+                title,
+                entry.path.display()
+            ]);
+        }
+
+        table.printstd();
+
+        Ok(())
     }
 
-    pub fn apply_pr(&self, owner: &str, repo: &str, pr_num: u64, apply_repo: &Path) -> Result<()> {
-        let review = Review::new_existing(&self.workdir()?, owner, repo, pr_num);
-        let diff = Diff::from_buffer(review.diff()?.as_bytes()).context("Failed to load diff")?;
-        let apply_repo_path = Path::new(apply_repo);
-        let apply_repo =
-            Repository::open(apply_repo_path).context("Failed to open git repository")?;
+    /// Prints the comments of a review as JSON. Without `json`, the review
+    /// file is only checked for errors.
+    pub fn print_parsed(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        kind: ForgeKind,
+        json: bool,
+    ) -> Result<()> {
+        let review = self.get_review(owner, repo, pr_num, kind)?;
+        let comments = review.comments()?;
+        if json {
+            println!("{}", serde_json::to_string_pretty(&comments)?);
+        }
+
+        Ok(())
+    }
+
+    /// Serves LSP for the review files in the workdir over stdio
+    pub fn lsp(&self) -> Result<()> {
+        lsp::serve(&self.workdir()?, io::stdin().lock(), io::stdout().lock())
+    }
+
+    /// Returns the open PRs awaiting review, along with the status of their
+    /// local review if there is one
+    pub async fn inbox(&self) -> Result<Vec<(PullRequestSummary, Option<ReviewStatus>)>> {
+        let query = self
+            .config
+            .prr
+            .inbox_query
+            .as_deref()
+            .unwrap_or(DEFAULT_INBOX_QUERY);
+        let prs = self.forge(ForgeKind::GitHub)?.search_prs(query).await?;
+
+        let workdir = self.workdir()?;
+        let mut inbox = Vec::with_capacity(prs.len());
+        for pr in prs {
+            let review =
+                Review::new_existing(&workdir, &pr.owner, &pr.repo, pr.pr_num, ForgeKind::GitHub);
+            let status = if review.has_metadata() {
+                Some(review.status()?)
+            } else {
+                None
+            };
+            inbox.push((pr, status));
+        }
+
+        Ok(inbox)
+    }
+
+    /// Prints the open PRs awaiting review
+    pub async fn print_inbox(&self, no_titles: bool) -> Result<()> {
+        let mut table = Table::new();
+        let mut table_fmt = *format::consts::FORMAT_CLEAN;
+        // Get rid of leading padding on each line
+        table_fmt.padding(0, 2);
+        table.set_format(table_fmt);
+        if !no_titles {
+            table.set_titles(row!["Handle", "Status", "Title"])
+        }
 
-        // Best effort check to prevent clobbering any work in progress
-        let mut opts = StatusOptions::new();
-        opts.include_ignored(false);
-        let statuses = apply_repo
-            .statuses(Some(&mut opts))
-            .context("Failed to get repo status")?;
-        if !statuses.is_empty() {
-            bail!("Working directory is dirty");
+        for (pr, status) in self.inbox().await? {
+            let status = status.map_or("-".to_string(), |s| s.to_string());
+            table.add_row(row![
+                format!("{}/{}/{}", pr.owner, pr.repo, pr.pr_num),
+                status,
+                pr.title
+            ]);
         }
 
-        apply_repo
-            .apply(&diff, ApplyLocation::WorkDir, None)
-            .context("Failed to apply diff")
+        table.printstd();
+
+        Ok(())
     }
 
-    pub fn print_status(&self, no_titles: bool) -> Result<()> {
+    /// Prints the rounds of review on a PR, oldest first
+    pub fn print_history(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        kind: ForgeKind,
+    ) -> Result<()> {
+        let review = self.get_review(owner, repo, pr_num, kind)?;
         let mut table = Table::new();
         let mut table_fmt = *format::consts::FORMAT_CLEAN;
-        // Get rid of leading padding on each line
         table_fmt.padding(0, 2);
         table.set_format(table_fmt);
-        if !no_titles {
-            table.set_titles(row!["Handle", "Status", "Review file"])
-        }
+        table.set_titles(row!["Round", "Commit", "Submitted", "Review file"]);
 
-        let reviews = get_all_existing(&self.workdir()?).context("Failed to get all reviews")?;
-        for review in reviews {
+        let rounds = review.rounds()?;
+        if rounds.is_empty() {
+            bail!("No existing review for {}", review.handle());
+        }
+        for round in rounds {
+            let submitted = match round.submitted {
+                Some(secs) => rfc5322_date(UNIX_EPOCH + Duration::from_secs(secs)),
+                None => "-".to_string(),
+            };
             table.add_row(row![
-                review.handle(),
-                review.status()?,
-                review.path().display()
+                round.number,
+                round.commit_id.as_deref().unwrap_or("-"),
+                submitted,
+                round.path.display()
             ]);
         }
 
@@ -518,8 +1706,8 @@ impl Prr {
     /// Removes reviews from the filesystem
     pub async fn remove(&self, prs: &[String], force: bool, submitted: bool) -> Result<()> {
         for pr in prs {
-            let (owner, repo, pr_num) = self.parse_pr_str(pr)?;
-            let review = self.get_review(&owner, &repo, pr_num)?;
+            let (owner, repo, pr_num, kind) = self.parse_pr_str(pr)?;
+            let review = self.get_review(&owner, &repo, pr_num, kind)?;
             review
                 .remove(force)
                 .with_context(|| anyhow!("Failed to remove {}", pr))?;
@@ -546,6 +1734,12 @@ impl Prr {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::commits::Commit;
+    use crate::forge::memory::{MemoryForge, MemoryPullRequest};
+    use crate::forge::PullRequest;
+    use crate::parser::{InlineComment, LineLocation, ThreadReply};
+    use crate::review::{ReviewThread, ThreadComment};
+    use serde_json::{json, Value};
     use std::fs::File;
     use std::io::Write;
     use tempfile::TempDir;
@@ -590,7 +1784,12 @@ mod tests {
         let pr_ref = "example/prr/42";
         assert_eq!(
             PRR.0.parse_pr_str(pr_ref).unwrap(),
-            ("example".to_string(), "prr".to_string(), 42)
+            (
+                "example".to_string(),
+                "prr".to_string(),
+                42,
+                ForgeKind::GitHub
+            )
         )
     }
 
@@ -599,7 +1798,12 @@ mod tests {
         let pr_ref = "example/prr.test/42";
         assert_eq!(
             PRR.0.parse_pr_str(pr_ref).unwrap(),
-            ("example".to_string(), "prr.test".to_string(), 42)
+            (
+                "example".to_string(),
+                "prr.test".to_string(),
+                42,
+                ForgeKind::GitHub
+            )
         )
     }
 
@@ -608,7 +1812,12 @@ mod tests {
         let pr_ref = "example/prr_test/42";
         assert_eq!(
             PRR.0.parse_pr_str(pr_ref).unwrap(),
-            ("example".to_string(), "prr_test".to_string(), 42)
+            (
+                "example".to_string(),
+                "prr_test".to_string(),
+                42,
+                ForgeKind::GitHub
+            )
         )
     }
 
@@ -617,7 +1826,12 @@ mod tests {
         let pr_ref = "example/prr-test/42";
         assert_eq!(
             PRR.0.parse_pr_str(pr_ref).unwrap(),
-            ("example".to_string(), "prr-test".to_string(), 42)
+            (
+                "example".to_string(),
+                "prr-test".to_string(),
+                42,
+                ForgeKind::GitHub
+            )
         )
     }
 
@@ -626,7 +1840,12 @@ mod tests {
         let pr_ref = "example/prr1/42";
         assert_eq!(
             PRR.0.parse_pr_str(pr_ref).unwrap(),
-            ("example".to_string(), "prr1".to_string(), 42)
+            (
+                "example".to_string(),
+                "prr1".to_string(),
+                42,
+                ForgeKind::GitHub
+            )
         )
     }
 
@@ -635,7 +1854,12 @@ mod tests {
         let pr_ref = "example/prr1.test_test-/42";
         assert_eq!(
             PRR.0.parse_pr_str(pr_ref).unwrap(),
-            ("example".to_string(), "prr1.test_test-".to_string(), 42)
+            (
+                "example".to_string(),
+                "prr1.test_test-".to_string(),
+                42,
+                ForgeKind::GitHub
+            )
         )
     }
 
@@ -644,7 +1868,12 @@ mod tests {
         let pr_ref = "https://github.com/example/repo/pull/42";
         assert_eq!(
             PRR.0.parse_pr_str(pr_ref).unwrap(),
-            ("example".to_string(), "repo".to_string(), 42)
+            (
+                "example".to_string(),
+                "repo".to_string(),
+                42,
+                ForgeKind::GitHub
+            )
         )
     }
 
@@ -653,7 +1882,12 @@ mod tests {
         let pr_ref = "https://github.com/example/repo/pull/42/files";
         assert_eq!(
             PRR.0.parse_pr_str(pr_ref).unwrap(),
-            ("example".to_string(), "repo".to_string(), 42)
+            (
+                "example".to_string(),
+                "repo".to_string(),
+                42,
+                ForgeKind::GitHub
+            )
         )
     }
 
@@ -662,7 +1896,12 @@ mod tests {
         let pr_ref = "https://github.com/example/repo/pull/42/files/abc123..def456";
         assert_eq!(
             PRR.0.parse_pr_str(pr_ref).unwrap(),
-            ("example".to_string(), "repo".to_string(), 42)
+            (
+                "example".to_string(),
+                "repo".to_string(),
+                42,
+                ForgeKind::GitHub
+            )
         )
     }
 
@@ -671,7 +1910,145 @@ mod tests {
         let pr_ref = "https://github.acme.com/example/repo/pull/42";
         assert_eq!(
             PRR.0.parse_pr_str(pr_ref).unwrap(),
-            ("example".to_string(), "repo".to_string(), 42)
+            (
+                "example".to_string(),
+                "repo".to_string(),
+                42,
+                ForgeKind::GitHub
+            )
+        )
+    }
+
+    #[tokio::test]
+    async fn test_parse_gitlab_pr_str() {
+        let pr_ref = "group/project/!42";
+        assert_eq!(
+            PRR.0.parse_pr_str(pr_ref).unwrap(),
+            (
+                "group".to_string(),
+                "project".to_string(),
+                42,
+                ForgeKind::GitLab
+            )
+        )
+    }
+
+    #[tokio::test]
+    async fn test_parse_gitlab_subgroup_pr_str() {
+        let pr_ref = "group/sub.group/project/!42";
+        assert_eq!(
+            PRR.0.parse_pr_str(pr_ref).unwrap(),
+            (
+                "group/sub.group".to_string(),
+                "project".to_string(),
+                42,
+                ForgeKind::GitLab
+            )
+        )
+    }
+
+    #[tokio::test]
+    async fn test_parse_gitlab_url() {
+        let pr_ref = "https://gitlab.example.com/group/sub/project/-/merge_requests/42/diffs";
+        assert_eq!(
+            PRR.0.parse_pr_str(pr_ref).unwrap(),
+            (
+                "group/sub".to_string(),
+                "project".to_string(),
+                42,
+                ForgeKind::GitLab
+            )
+        )
+    }
+
+    #[test]
+    fn test_resolve_forge_token() {
+        let env = HashMap::from([("PRR_TEST_TOKEN_EMPTY", ""), ("PRR_TEST_TOKEN", "from-env")]);
+        let lookup = |var: &str| env.get(var).map(|v| v.to_string());
+        let vars = [
+            "PRR_TEST_TOKEN_UNSET",
+            "PRR_TEST_TOKEN_EMPTY",
+            "PRR_TEST_TOKEN",
+        ];
+
+        assert_eq!(
+            resolve_forge_token(Some("from-config"), &vars, lookup).as_deref(),
+            Some("from-config")
+        );
+        assert_eq!(
+            resolve_forge_token(Some(""), &vars, lookup).as_deref(),
+            Some("from-env")
+        );
+        assert_eq!(
+            resolve_forge_token(None, &vars, lookup).as_deref(),
+            Some("from-env")
+        );
+        assert_eq!(resolve_forge_token(Some(""), &vars[..2], lookup), None);
+    }
+
+    #[tokio::test]
+    async fn test_parse_forgejo_url() {
+        let pr_ref = "https://codeberg.org/example/repo/pulls/42/files";
+        assert_eq!(
+            PRR.0.parse_pr_str(pr_ref).unwrap(),
+            (
+                "example".to_string(),
+                "repo".to_string(),
+                42,
+                ForgeKind::Forgejo
+            )
+        )
+    }
+
+    #[tokio::test]
+    async fn test_configured_forges() {
+        let gconfig = r#"
+            [prr]
+            token = "test"
+            forgejo_url = "https://codeberg.org"
+            forgejo_token = "test"
+
+            [prr.forges]
+            "mirrors" = "forgejo"
+            "example/mirror" = "gitea"
+            "mirrors/upstream" = "github"
+        "#;
+
+        let (prr, _dir) = config(gconfig, None);
+        let kind = |pr_ref| prr.parse_pr_str(pr_ref).unwrap().3;
+        assert_eq!(kind("mirrors/prr/42"), ForgeKind::Forgejo);
+        assert_eq!(kind("example/mirror/42"), ForgeKind::Forgejo);
+        assert_eq!(kind("mirrors/upstream/42"), ForgeKind::GitHub);
+        assert_eq!(kind("example/repo/42"), ForgeKind::GitHub);
+        assert!(prr.forge(ForgeKind::Forgejo).is_ok());
+        assert!(prr.forge(ForgeKind::GitLab).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_parse_gerrit_pr_str() {
+        let pr_ref = "gerrit:platform/build~12345";
+        assert_eq!(
+            PRR.0.parse_pr_str(pr_ref).unwrap(),
+            (
+                "gerrit/platform".to_string(),
+                "build".to_string(),
+                12345,
+                ForgeKind::Gerrit
+            )
+        )
+    }
+
+    #[tokio::test]
+    async fn test_parse_gerrit_url() {
+        let pr_ref = "https://review.example.com/c/build/+/12345/2";
+        assert_eq!(
+            PRR.0.parse_pr_str(pr_ref).unwrap(),
+            (
+                "gerrit".to_string(),
+                "build".to_string(),
+                12345,
+                ForgeKind::Gerrit
+            )
         )
     }
 
@@ -689,7 +2066,12 @@ mod tests {
         let (prr, _dir) = config(gconfig, Some(lconfig));
         assert_eq!(
             prr.parse_pr_str("42").unwrap(),
-            ("testorg".to_string(), "testrepo".to_string(), 42)
+            (
+                "testorg".to_string(),
+                "testrepo".to_string(),
+                42,
+                ForgeKind::GitHub
+            )
         )
     }
 
@@ -919,8 +2301,14 @@ mod tests {
         )
         .expect("copy README-not-tracked.md failed");
 
-        prr.apply_pr("apply_pr", "review", 1, Path::new(&test_repo_path))
-            .expect("apply_pr failed");
+        prr.apply_pr(
+            "apply_pr",
+            "review",
+            1,
+            ForgeKind::GitHub,
+            Path::new(&test_repo_path),
+        )
+        .expect("apply_pr failed");
 
         let got_after_apply = fs::read(test_repo_path.clone() + "README.md")
             .expect("failed to read README.md with diff applied");
@@ -928,4 +2316,931 @@ mod tests {
             .expect("failed to read README-applied.md");
         assert_eq!(got_after_apply, want_after_apply);
     }
+
+    // Sets up a `Prr` talking to `forge` with a fresh workdir
+    fn memory_prr(forge: MemoryForge) -> (Prr, TempDir) {
+        let workdir = TempDir::new().unwrap();
+        let gconfig = format!(
+            r#"
+                [prr]
+                token = "test"
+                workdir = "{}"
+            "#,
+            workdir.path().display()
+        );
+
+        let (mut prr, _dir) = config(&gconfig, None);
+        prr.forges.insert(ForgeKind::GitHub, Box::new(forge));
+        (prr, workdir)
+    }
+
+    // A pull request with `diff` and head commit `abc`
+    fn memory_pr(diff: &str) -> MemoryPullRequest {
+        MemoryPullRequest {
+            diff: diff.to_string(),
+            pr: PullRequest {
+                head_sha: "abc".to_string(),
+                ..Default::default()
+            },
+            threads: vec![],
+            commits: vec![],
+        }
+    }
+
+    // The threads diff with bob's open nit on line 3 of ch1.txt
+    fn nit_thread_pr() -> MemoryPullRequest {
+        MemoryPullRequest {
+            threads: vec![ReviewThread {
+                id: "PRRT_right".to_string(),
+                file: "ch1.txt".to_string(),
+                line: LineLocation::Right(3),
+                start_line: None,
+                resolved: false,
+                comments: vec![ThreadComment {
+                    author: "bob".to_string(),
+                    created_at: "2024-01-02T00:00:00Z".to_string(),
+                    body: "Nit: too long".to_string(),
+                }],
+            }],
+            ..memory_pr(include_str!("../testdata/review/threads/diff"))
+        }
+    }
+
+    // Puts `pr` on `forge` as owner/repo/1 and downloads it into a fresh workdir
+    async fn fetched_review(
+        mut forge: MemoryForge,
+        pr: MemoryPullRequest,
+    ) -> (Prr, Review, TempDir) {
+        forge.add_pr("owner", "repo", 1, pr);
+        let (prr, workdir) = memory_prr(forge);
+        let review = prr
+            .get_pr(
+                "owner",
+                "repo",
+                1,
+                ForgeKind::GitHub,
+                Unsubmitted::Keep,
+                false,
+                false,
+            )
+            .await
+            .expect("Failed to get review");
+
+        (prr, review, workdir)
+    }
+
+    #[tokio::test]
+    async fn test_get_mbox() {
+        let workdir = TempDir::new().unwrap();
+        // No forge is configured at all
+        let gconfig = format!(
+            r#"
+                [prr]
+                workdir = "{}"
+            "#,
+            workdir.path().display()
+        );
+        let (prr, _dir) = config(&gconfig, None);
+
+        let review = prr
+            .get_mbox(
+                "local",
+                "series",
+                1,
+                ForgeKind::GitHub,
+                Path::new("testdata/mbox/series.mbox"),
+                Unsubmitted::Keep,
+            )
+            .expect("Failed to read mbox");
+        assert_eq!(review.status().unwrap(), ReviewStatus::New);
+        assert_eq!(review.series().unwrap().unwrap().patches.len(), 2);
+        let contents = fs::read_to_string(review.path()).unwrap();
+        assert!(contents.starts_with("> ch1: Improve the text\n"));
+        assert!(contents.contains("> commit 1111111111111111111111111111111111111111\n"));
+
+        fs::write(review.path(), format!("{contents}\nComment\n")).unwrap();
+        assert_eq!(review.status().unwrap(), ReviewStatus::Reviewed);
+        let err = prr
+            .submit_pr("local", "series", 1, ForgeKind::GitHub, false, false, false)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("read from an mbox"));
+
+        // Filed under the forge the PR string names, where other commands look
+        prr.get_mbox(
+            "group",
+            "project",
+            2,
+            ForgeKind::GitLab,
+            Path::new("testdata/mbox/series.mbox"),
+            Unsubmitted::Keep,
+        )
+        .expect("Failed to read mbox");
+        let found = |kind| {
+            prr.get_review("group", "project", 2, kind)
+                .unwrap()
+                .has_metadata()
+        };
+        assert!(found(ForgeKind::GitLab));
+        assert!(!found(ForgeKind::GitHub));
+    }
+
+    #[test]
+    fn test_import_email() {
+        let workdir = TempDir::new().unwrap();
+        let gconfig = format!(
+            r#"
+                [prr]
+                workdir = "{}"
+            "#,
+            workdir.path().display()
+        );
+        let (prr, _dir) = config(&gconfig, None);
+        prr.get_mbox(
+            "local",
+            "series",
+            1,
+            ForgeKind::GitHub,
+            Path::new("testdata/mbox/series.mbox"),
+            Unsubmitted::Keep,
+        )
+        .expect("Failed to read mbox");
+
+        // What `export --email` sends comes back the same
+        let review = prr
+            .import_email(
+                "local",
+                "series",
+                1,
+                ForgeKind::GitHub,
+                Path::new("testdata/email/reply"),
+                false,
+            )
+            .expect("Failed to import email");
+        let comments = review.comments().unwrap();
+        assert_eq!(comments.review_action, ReviewAction::Approve);
+        assert!(comments.review_comment.starts_with("Review by Wu Qi"));
+        assert!(comments.review_comment.ends_with("\n\nNice series."));
+        assert_eq!(
+            comments.inline_comments,
+            vec![InlineComment {
+                file: "ch1.txt".to_string(),
+                line: LineLocation::Right(4),
+                start_line: None,
+                comment: "Why add this only to remove it again?".to_string(),
+                commit: Some("1111111111111111111111111111111111111111".to_string()),
+            }]
+        );
+        assert_eq!(comments.file_comments.len(), 1);
+        assert_eq!(comments.commit_comments.len(), 1);
+
+        // Importing again would clobber the imported comments
+        let err = prr
+            .import_email(
+                "local",
+                "series",
+                1,
+                ForgeKind::GitHub,
+                Path::new("testdata/email/reply"),
+                false,
+            )
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("--force"));
+    }
+
+    #[tokio::test]
+    async fn test_get_edit_submit() {
+        let forge = MemoryForge::default();
+        let submitted = forge.submitted();
+        let pr = nit_thread_pr();
+
+        // Get
+        let (prr, review, _workdir) = fetched_review(forge, pr).await;
+        assert_eq!(review.status().unwrap(), ReviewStatus::New);
+
+        // Edit
+        let contents = fs::read_to_string(review.path()).unwrap();
+        let contents = format!("@prr approve\n\nLGTM\n\n{contents}\nLast line comment\n").replace(
+            ">> Nit: too long\n",
+            ">> Nit: too long\n\nFixed.\n\n@prr resolve\n\n",
+        );
+        fs::write(review.path(), contents).unwrap();
+        assert_eq!(review.status().unwrap(), ReviewStatus::Reviewed);
+
+        // Submit
+        prr.submit_pr("owner", "repo", 1, ForgeKind::GitHub, false, false, false)
+            .await
+            .expect("Failed to submit review");
+        assert_eq!(review.status().unwrap(), ReviewStatus::Submitted);
+
+        let submitted = submitted.lock().unwrap();
+        assert_eq!(submitted.reviews.len(), 1);
+        let r = &submitted.reviews[0];
+        assert_eq!(r.action, ReviewAction::Approve);
+        assert_eq!(r.body, "LGTM");
+        assert_eq!(r.commit_id.as_deref(), Some("abc"));
+        assert_eq!(
+            r.comments,
+            vec![InlineComment {
+                file: "ch1.txt".to_string(),
+                line: LineLocation::Right(4),
+                start_line: None,
+                comment: "Last line comment".to_string(),
+                commit: None,
+            }]
+        );
+        assert_eq!(
+            submitted.thread_replies,
+            vec![ThreadReply {
+                thread: "PRRT_right".to_string(),
+                comment: "Fixed.".to_string(),
+                resolve: true,
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_submit_without_review() {
+        let forge = MemoryForge::default();
+        let submitted = forge.submitted();
+        let pr = nit_thread_pr();
+        let (prr, review, _workdir) = fetched_review(forge, pr).await;
+
+        // Only a file comment and a reply, neither of which is part of a review
+        let contents = fs::read_to_string(review.path())
+            .unwrap()
+            .replacen("ch1.txt\n", "ch1.txt\n\nWhole file\n\n", 1)
+            .replace(">> Nit: too long\n", ">> Nit: too long\n\nFixed.\n\n");
+        fs::write(review.path(), contents).unwrap();
+        prr.submit_pr("owner", "repo", 1, ForgeKind::GitHub, false, false, false)
+            .await
+            .expect("Failed to submit review");
+        assert_eq!(review.status().unwrap(), ReviewStatus::Submitted);
+
+        let submitted = submitted.lock().unwrap();
+        assert!(submitted.reviews.is_empty());
+        assert_eq!(submitted.file_comments.len(), 1);
+        assert_eq!(submitted.thread_replies.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_per_commit() {
+        let diff = include_str!("../testdata/review/threads/diff");
+        let sha = "1111111111111111111111111111111111111111";
+        let mut forge = MemoryForge::default();
+        let submitted = forge.submitted();
+        let pr = MemoryPullRequest {
+            commits: vec![Commit {
+                sha: sha.to_string(),
+                author: "Sun Tzu <suntzu@example.com>".to_string(),
+                date: "2024-01-01T00:00:00Z".to_string(),
+                message: "Update chapter 1".to_string(),
+                diff: diff.to_string(),
+            }],
+            ..memory_pr(diff)
+        };
+        forge.add_pr("owner", "repo", 1, pr);
+        let (prr, _workdir) = memory_prr(forge);
+        let review = prr
+            .get_pr(
+                "owner",
+                "repo",
+                1,
+                ForgeKind::GitHub,
+                Unsubmitted::Keep,
+                false,
+                true,
+            )
+            .await
+            .expect("Failed to get review");
+
+        let contents = fs::read_to_string(review.path())
+            .unwrap()
+            .replace("Update chapter 1\n", "Update chapter 1\n\nGood title.\n\n");
+        fs::write(review.path(), contents).unwrap();
+        prr.submit_pr("owner", "repo", 1, ForgeKind::GitHub, false, false, false)
+            .await
+            .expect("Failed to submit review");
+
+        let submitted = submitted.lock().unwrap();
+        assert!(submitted.reviews.is_empty());
+        assert_eq!(submitted.commit_comments.len(), 1);
+        assert_eq!(submitted.commit_comments[0].commit, sha);
+        assert_eq!(submitted.commit_comments[0].body, "Good title.");
+    }
+
+    #[tokio::test]
+    async fn test_submit_suggestion_off_github() {
+        let mut forge = MemoryForge::default();
+        let submitted = forge.submitted();
+        forge.add_pr(
+            "group",
+            "project",
+            1,
+            memory_pr(include_str!("../testdata/review/threads/diff")),
+        );
+        let (mut prr, _workdir) = memory_prr(forge);
+        let forge = prr.forges.remove(&ForgeKind::GitHub).unwrap();
+        prr.forges.insert(ForgeKind::GitLab, forge);
+        let review = prr
+            .get_pr(
+                "group",
+                "project",
+                1,
+                ForgeKind::GitLab,
+                Unsubmitted::Keep,
+                false,
+                false,
+            )
+            .await
+            .expect("Failed to get review");
+
+        let contents = fs::read_to_string(review.path()).unwrap().replace(
+            "to ruin.\n",
+            "to ruin.\n\n@prr suggest\n```\n2. Life and death.\n```\n\n",
+        );
+        fs::write(review.path(), contents).unwrap();
+        let err = prr
+            .submit_pr(
+                "group",
+                "project",
+                1,
+                ForgeKind::GitLab,
+                false,
+                false,
+                false,
+            )
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("not supported"));
+        assert!(submitted.lock().unwrap().reviews.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_pending_finalize() {
+        let forge = MemoryForge::default();
+        let submitted = forge.submitted();
+        let (prr, review, _workdir) = fetched_review(
+            forge,
+            memory_pr(include_str!("../testdata/review/threads/diff")),
+        )
+        .await;
+
+        let contents = fs::read_to_string(review.path()).unwrap();
+        fs::write(
+            review.path(),
+            format!("@prr pending\n\nWIP\n\n{contents}\nLast line comment\n"),
+        )
+        .unwrap();
+        prr.submit_pr("owner", "repo", 1, ForgeKind::GitHub, false, false, false)
+            .await
+            .expect("Failed to submit pending review");
+        assert_eq!(review.status().unwrap(), ReviewStatus::Pending);
+        assert_eq!(review.pending().unwrap().as_deref(), Some("pending-1"));
+        assert!(submitted.lock().unwrap().reviews.is_empty());
+
+        // A second pending review is refused
+        let err = prr
+            .submit_pr("owner", "repo", 1, ForgeKind::GitHub, true, false, false)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("--finalize"));
+
+        // Neither is fetching it again, which would lose track of the pending review
+        for unsubmitted in [Unsubmitted::Discard, Unsubmitted::CarryForward] {
+            let err = prr
+                .get_pr(
+                    "owner",
+                    "repo",
+                    1,
+                    ForgeKind::GitHub,
+                    unsubmitted,
+                    false,
+                    false,
+                )
+                .await
+                .err()
+                .expect("Fetched a pending review again");
+            assert!(err.to_string().contains("--finalize"));
+        }
+        assert_eq!(review.pending().unwrap().as_deref(), Some("pending-1"));
+
+        // Comments added since would never be posted
+        let contents = fs::read_to_string(review.path()).unwrap();
+        fs::write(review.path(), format!("{contents}\nAnother comment\n")).unwrap();
+        let err = prr
+            .finalize_pr("owner", "repo", 1, ForgeKind::GitHub, false)
+            .await
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("changed since it was left pending"));
+        assert_eq!(review.status().unwrap(), ReviewStatus::Pending);
+
+        // Finalize with whatever the review action and comment are by then
+        fs::write(
+            review.path(),
+            contents
+                .replace("@prr pending", "@prr approve")
+                .replace("WIP", "Looks good"),
+        )
+        .unwrap();
+        prr.finalize_pr("owner", "repo", 1, ForgeKind::GitHub, false)
+            .await
+            .expect("Failed to finalize review");
+        assert_eq!(review.status().unwrap(), ReviewStatus::Submitted);
+
+        let submitted = submitted.lock().unwrap();
+        assert!(submitted.pending.is_empty());
+        assert_eq!(submitted.reviews.len(), 1);
+        assert_eq!(submitted.reviews[0].action, ReviewAction::Approve);
+        assert_eq!(submitted.reviews[0].body, "Looks good");
+        assert_eq!(submitted.reviews[0].comments.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_submit_resume() {
+        let forge = MemoryForge::default();
+        let submitted = forge.submitted();
+        let failing_files = forge.failing_files();
+        let pr = nit_thread_pr();
+        let (prr, review, _workdir) = fetched_review(forge, pr).await;
+
+        let contents = fs::read_to_string(review.path()).unwrap();
+        let contents = format!("LGTM\n\n{contents}")
+            .replacen(
+                "> diff --git a/ch1.txt b/ch1.txt\n",
+                "> diff --git a/ch1.txt b/ch1.txt\n\nWhole file\n\n",
+                1,
+            )
+            .replace(">> Nit: too long\n", ">> Nit: too long\n\nFixed.\n\n");
+        fs::write(review.path(), contents).unwrap();
+
+        // The file comment fails after the review went through
+        failing_files.lock().unwrap().insert("ch1.txt".to_string());
+        prr.submit_pr("owner", "repo", 1, ForgeKind::GitHub, false, false, false)
+            .await
+            .expect_err("File comment should fail");
+        assert_eq!(review.status().unwrap(), ReviewStatus::Reviewed);
+        let progress = review.submission().unwrap().unwrap();
+        assert!(!progress.complete);
+        assert_eq!(
+            progress.posted,
+            vec![Posted {
+                item: "review".to_string(),
+                id: Some("review-1".to_string()),
+            }]
+        );
+
+        // Submitting from scratch would post the review twice
+        let err = prr
+            .submit_pr("owner", "repo", 1, ForgeKind::GitHub, false, false, false)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("--resume"));
+
+        failing_files.lock().unwrap().clear();
+        prr.submit_pr("owner", "repo", 1, ForgeKind::GitHub, false, true, false)
+            .await
+            .expect("Failed to resume submission");
+        assert_eq!(review.status().unwrap(), ReviewStatus::Submitted);
+        assert!(review.submission().unwrap().unwrap().complete);
+
+        let submitted = submitted.lock().unwrap();
+        assert_eq!(submitted.reviews.len(), 1);
+        assert_eq!(submitted.file_comments.len(), 1);
+        assert_eq!(submitted.thread_replies.len(), 1);
+    }
+
+    // Commits `content` as ch1.txt on top of HEAD, returning the commit ID
+    fn commit_ch1(repo: &Repository, content: &str) -> String {
+        fs::write(repo.workdir().unwrap().join("ch1.txt"), content).unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("ch1.txt")).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = git2::Signature::now("someone", "someone@somewhere.com").unwrap();
+        let parent = repo.head().ok().map(|h| h.peel_to_commit().unwrap());
+        repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            "Update ch1.txt",
+            &tree,
+            &parent.iter().collect::<Vec<_>>(),
+        )
+        .unwrap()
+        .to_string()
+    }
+
+    #[tokio::test]
+    async fn test_apply_suggestions() {
+        let repo_dir = TempDir::new().unwrap();
+        let repo = Repository::init(repo_dir.path()).unwrap();
+        let lines: Vec<String> = (1..=12).map(|i| format!("Line {i}")).collect();
+        let head_sha = commit_ch1(&repo, &(lines.join("\n") + "\n"));
+
+        // The author has moved on since: a line was added at the top and line
+        // 10 was changed
+        let mut moved_on = lines.clone();
+        moved_on.insert(0, "Title".to_string());
+        moved_on[10] = "Line ten".to_string();
+        commit_ch1(&repo, &(moved_on.join("\n") + "\n"));
+
+        let thread = |start_line: Option<u64>, line, body: &str| ReviewThread {
+            id: "PRRT".to_string(),
+            file: "ch1.txt".to_string(),
+            line: LineLocation::Right(line),
+            start_line: start_line.map(LineLocation::Right),
+            resolved: false,
+            comments: vec![ThreadComment {
+                author: "bob".to_string(),
+                created_at: "2024-01-02T00:00:00Z".to_string(),
+                body: body.to_string(),
+            }],
+        };
+        let mut forge = MemoryForge::default();
+        forge.add_pr(
+            "owner",
+            "repo",
+            1,
+            MemoryPullRequest {
+                pr: PullRequest {
+                    head_sha,
+                    ..Default::default()
+                },
+                threads: vec![
+                    thread(Some(2), 3, "```suggestion\nLines 2 and 3\n```"),
+                    thread(None, 10, "```suggestion\nLine 10!\n```"),
+                ],
+                ..memory_pr("")
+            },
+        );
+        let (prr, _workdir) = memory_prr(forge);
+
+        let result = prr
+            .apply_suggestions("owner", "repo", 1, ForgeKind::GitHub, repo_dir.path())
+            .await;
+        assert!(result
+            .err()
+            .unwrap()
+            .to_string()
+            .contains("1 suggestion(s) no longer apply"));
+
+        let mut want = moved_on.clone();
+        want.splice(2..4, ["Lines 2 and 3".to_string()]);
+        assert_eq!(
+            fs::read_to_string(repo_dir.path().join("ch1.txt")).unwrap(),
+            want.join("\n") + "\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_amend() {
+        let forge = MemoryForge::default();
+        let submitted = forge.submitted();
+        let (prr, review, _workdir) = fetched_review(
+            forge,
+            memory_pr(include_str!("../testdata/review/threads/diff")),
+        )
+        .await;
+
+        let contents = fs::read_to_string(review.path()).unwrap();
+        let contents = format!("Overall\n\n{contents}\nSecond\n")
+            .replacen("ch1.txt\n", "ch1.txt\n\nWhole file\n\n", 1)
+            // Comments are matched on their whole span
+            .replace("> -2. It is", "\n> -2. It is")
+            .replace("to ruin.\n", "to ruin.\n\nFirst\n\n");
+        fs::write(review.path(), &contents).unwrap();
+        let err = prr
+            .amend_pr("owner", "repo", 1, ForgeKind::GitHub, false)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("not been submitted"));
+        prr.submit_pr("owner", "repo", 1, ForgeKind::GitHub, false, false, false)
+            .await
+            .expect("Failed to submit review");
+
+        let err = prr
+            .amend_pr("owner", "repo", 1, ForgeKind::GitHub, false)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("No comments changed"));
+
+        // New comments need a new review
+        fs::write(
+            review.path(),
+            contents.replace("State.\n", "State.\n\nThird\n\n"),
+        )
+        .unwrap();
+        let err = prr
+            .amend_pr("owner", "repo", 1, ForgeKind::GitHub, false)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("cannot be added"));
+
+        let amended = contents
+            .replace("Overall", "Overall, amended")
+            .replace("First", "First, amended")
+            .replace("Whole file", "Whole file, amended")
+            .replace("\nSecond\n", "");
+        fs::write(review.path(), &amended).unwrap();
+        prr.amend_pr("owner", "repo", 1, ForgeKind::GitHub, false)
+            .await
+            .expect("Failed to amend review");
+        assert_eq!(review.status().unwrap(), ReviewStatus::Submitted);
+
+        {
+            let submitted = submitted.lock().unwrap();
+            assert_eq!(submitted.reviews[0].body, "Overall, amended");
+            assert_eq!(
+                submitted.edited_comments,
+                vec![
+                    (
+                        "review-1-comment-0".to_string(),
+                        "First, amended".to_string()
+                    ),
+                    ("file-1".to_string(), "Whole file, amended".to_string()),
+                ]
+            );
+            assert_eq!(submitted.deleted_comments, vec!["review-1-comment-1"]);
+        }
+
+        // Amending again compares against the amended review
+        fs::write(
+            review.path(),
+            amended.replace("\nWhole file, amended\n\n", ""),
+        )
+        .unwrap();
+        prr.amend_pr("owner", "repo", 1, ForgeKind::GitHub, false)
+            .await
+            .expect("Failed to amend review again");
+        let submitted = submitted.lock().unwrap();
+        assert_eq!(
+            submitted.deleted_comments,
+            vec!["review-1-comment-1", "file-1"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_amend_unknown_id() {
+        let (prr, review, _workdir) = fetched_review(
+            MemoryForge::default(),
+            memory_pr(include_str!("../testdata/review/threads/diff")),
+        )
+        .await;
+        let contents = fs::read_to_string(review.path()).unwrap();
+        fs::write(review.path(), format!("Overall\n\n{contents}")).unwrap();
+        prr.submit_pr("owner", "repo", 1, ForgeKind::GitHub, false, false, false)
+            .await
+            .expect("Failed to submit review");
+
+        // As if the forge's response could not be read
+        let mut submission = review.submission().unwrap().unwrap();
+        for posted in &mut submission.posted {
+            posted.id = None;
+        }
+        review.set_submission(&submission).unwrap();
+
+        fs::write(review.path(), format!("Overall, amended\n\n{contents}")).unwrap();
+        let err = prr
+            .amend_pr("owner", "repo", 1, ForgeKind::GitHub, false)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("review is unknown"));
+    }
+
+    #[tokio::test]
+    async fn test_inbox() {
+        let summary = |pr_num, title: &str| PullRequestSummary {
+            owner: "owner".to_string(),
+            repo: "repo".to_string(),
+            pr_num,
+            title: title.to_string(),
+        };
+        let mut forge = MemoryForge::default();
+        forge.add_search(
+            DEFAULT_INBOX_QUERY,
+            vec![summary(1, "Fix typo"), summary(2, "Add chapter 2")],
+        );
+        let (prr, _review, _workdir) = fetched_review(
+            forge,
+            memory_pr(include_str!("../testdata/review/threads/diff")),
+        )
+        .await;
+
+        let inbox = prr.inbox().await.expect("Failed to list inbox");
+        assert_eq!(
+            inbox,
+            vec![
+                (summary(1, "Fix typo"), Some(ReviewStatus::New)),
+                (summary(2, "Add chapter 2"), None),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sync() {
+        let diff = include_str!("../testdata/review/threads/diff");
+        let mut forge = MemoryForge::default();
+        for pr_num in 2..=4 {
+            forge.add_pr("owner", "repo", pr_num, memory_pr(diff));
+        }
+        // 1 is up to date
+        let (prr, _review, workdir) = fetched_review(forge, memory_pr(diff)).await;
+        // 2 and 3 were downloaded before the PR changed, and 3 was reviewed since
+        for pr_num in [2, 3] {
+            Review::new(
+                workdir.path(),
+                diff.to_string(),
+                "owner",
+                "repo",
+                None,
+                pr_num,
+                ForgeKind::GitHub,
+                "old".to_string(),
+                &[],
+                Unsubmitted::Keep,
+            )
+            .unwrap();
+        }
+        let reviewed = prr
+            .get_review("owner", "repo", 3, ForgeKind::GitHub)
+            .unwrap();
+        let contents = fs::read_to_string(reviewed.path()).unwrap();
+        fs::write(reviewed.path(), format!("Looks good\n{contents}")).unwrap();
+
+        let prs: Vec<String> = (1..=5).map(|n| format!("owner/repo/{n}")).collect();
+        let synced = prr.sync(&prs).await.expect("Failed to sync");
+        let outcomes: Vec<_> = synced
+            .iter()
+            .map(|(handle, outcome)| (handle.as_str(), outcome.as_ref().ok()))
+            .collect();
+        assert_eq!(
+            outcomes,
+            vec![
+                ("owner/repo/1", Some(&SyncOutcome::UpToDate)),
+                ("owner/repo/2", Some(&SyncOutcome::Refreshed)),
+                (
+                    "owner/repo/3",
+                    Some(&SyncOutcome::Skipped(ReviewStatus::Reviewed))
+                ),
+                ("owner/repo/4", Some(&SyncOutcome::Created)),
+                // Not on the forge
+                ("owner/repo/5", None),
+            ]
+        );
+
+        let commit = |pr_num| {
+            prr.get_review("owner", "repo", pr_num, ForgeKind::GitHub)
+                .unwrap()
+                .commit_id()
+                .unwrap()
+        };
+        assert_eq!(commit(2).as_deref(), Some("abc"));
+        assert_eq!(commit(4).as_deref(), Some("abc"));
+        assert_eq!(commit(3).as_deref(), Some("old"));
+        assert!(fs::read_to_string(reviewed.path())
+            .unwrap()
+            .starts_with("Looks good\n"));
+    }
+
+    #[tokio::test]
+    async fn test_remote_status() {
+        let diff = include_str!("../testdata/review/threads/diff");
+        let pr = |pr_num, state| MemoryPullRequest {
+            pr: PullRequest {
+                head_sha: "abc".to_string(),
+                title: format!("PR {pr_num}"),
+                author: "alice".to_string(),
+                state,
+                ..Default::default()
+            },
+            ..memory_pr(diff)
+        };
+        let mut forge = MemoryForge::default();
+        forge.add_pr("owner", "repo", 2, pr(2, PullRequestState::Merged));
+        let (prr, _review, workdir) = fetched_review(forge, pr(1, PullRequestState::Open)).await;
+        // Downloaded before the last push, and gone from the forge
+        for pr_num in [2, 3] {
+            Review::new(
+                workdir.path(),
+                diff.to_string(),
+                "owner",
+                "repo",
+                None,
+                pr_num,
+                ForgeKind::GitHub,
+                "old".to_string(),
+                &[],
+                Unsubmitted::Keep,
+            )
+            .unwrap();
+        }
+
+        let prr = &prr;
+        let remote_status = |pr_num| {
+            let review = prr
+                .get_review("owner", "repo", pr_num, ForgeKind::GitHub)
+                .unwrap();
+            async move { prr.remote_status(&review).await }
+        };
+        assert_eq!(
+            remote_status(1).await.unwrap(),
+            Some(RemoteStatus {
+                state: PullRequestState::Open,
+                head_moved: false,
+                title: "PR 1".to_string(),
+                author: "alice".to_string(),
+            })
+        );
+        assert_eq!(
+            remote_status(2).await.unwrap(),
+            Some(RemoteStatus {
+                state: PullRequestState::Merged,
+                head_moved: true,
+                title: "PR 2".to_string(),
+                author: "alice".to_string(),
+            })
+        );
+        assert!(remote_status(3).await.is_err());
+
+        let status = serde_json::to_value(prr.status(true).await.unwrap()).unwrap();
+        let entry = |handle: &str| {
+            status
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|e| e["handle"] == handle)
+                .cloned()
+                .unwrap()
+        };
+        assert_eq!(
+            entry("owner/repo/2"),
+            json!({
+                "handle": "owner/repo/2",
+                "status": "NEW",
+                "path": workdir.path().join("owner/repo/2.prr"),
+                "remote": {
+                    "state": "merged",
+                    "head_moved": true,
+                    "title": "PR 2",
+                    "author": "alice",
+                },
+            })
+        );
+        // Failing to fetch is only a warning
+        assert_eq!(entry("owner/repo/3")["remote"], Value::Null);
+    }
+
+    #[tokio::test]
+    async fn test_compose() {
+        let (prr, review, _workdir) = fetched_review(
+            MemoryForge::default(),
+            memory_pr(include_str!("../testdata/review/threads/diff")),
+        )
+        .await;
+        // Not downloaded
+        assert!(prr
+            .compose("owner", "repo", 2, ForgeKind::GitHub, "[]")
+            .is_err());
+
+        let json = r#"[{"file": "ch1.txt", "line": 3, "start_line": 1, "body": "Too long"}]"#;
+        prr.compose("owner", "repo", 1, ForgeKind::GitHub, json)
+            .expect("Failed to compose review");
+        assert_eq!(review.status().unwrap(), ReviewStatus::Reviewed);
+        assert_eq!(
+            review.comments().unwrap().inline_comments,
+            vec![InlineComment {
+                file: "ch1.txt".to_string(),
+                line: LineLocation::Right(3),
+                start_line: Some(LineLocation::Right(1)),
+                comment: "Too long".to_string(),
+                commit: None,
+            }]
+        );
+        assert!(prr
+            .compose("owner", "repo", 1, ForgeKind::GitHub, "{}")
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_import_sarif() {
+        let (prr, review, workdir) = fetched_review(
+            MemoryForge::default(),
+            memory_pr(include_str!("../testdata/review/threads/diff")),
+        )
+        .await;
+        let sarif = workdir.path().join("results.sarif");
+        fs::write(&sarif, include_str!("../testdata/sarif/results.sarif")).unwrap();
+
+        prr.import_sarif("owner", "repo", 1, ForgeKind::GitHub, &sarif, true)
+            .expect("Failed to import SARIF");
+        // What the results turn into is up to `sarif_comments()`
+        assert_eq!(review.status().unwrap(), ReviewStatus::Reviewed);
+        let comments = review.comments().unwrap();
+        assert_eq!(comments.inline_comments.len(), 2);
+        assert_eq!(comments.file_comments.len(), 1);
+    }
 }
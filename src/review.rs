@@ -1,3 +1,4 @@
+use std::collections::{HashMap, HashSet};
 use std::fmt::{Display, Formatter, Result as fmt_result, Write as fmt_write};
 use std::fs;
 use std::fs::OpenOptions;
@@ -8,11 +9,29 @@ use std::time::SystemTime;
 use anyhow::{anyhow, bail, Context, Result};
 use serde_derive::{Deserialize, Serialize};
 
-use crate::parser::{Comment, FileComment, InlineComment, ReviewAction, ReviewParser};
+use crate::compose::{insert_comments, ComposedComment};
+use crate::forge::{gerrit_project, ForgeKind, GERRIT_OWNER};
+use crate::mbox::Series;
+use crate::parser::{
+    diff_locations, is_thread_line, parse_thread_start, Comment, CommitComment, FileComment,
+    InlineComment, LineLocation, ReviewAction, ReviewParser, ThreadReply,
+};
 
 /// We support a few common variants of snips.
 /// These are semantically identical.
-const SNIP_VARIANTS: &[&str] = &["[..]", "[...]"];
+pub const SNIP_VARIANTS: &[&str] = &["[..]", "[...]"];
+
+/// Number of quoted lines preceding a carried forward comment that must still
+/// match for the comment to be placed. This is in addition to the commented line(s).
+const CARRY_FORWARD_CONTEXT: usize = 2;
+
+/// An error found in a review file while it is being edited
+#[derive(Debug, PartialEq, Eq)]
+pub struct LintError {
+    /// Index of the line of the review file the error is on
+    pub line: usize,
+    pub message: String,
+}
 
 /// Represents the state of a single review
 pub struct Review {
@@ -24,6 +43,8 @@ pub struct Review {
     repo: String,
     /// Issue # of the pull request
     pr_num: u64,
+    /// Forge the PR is hosted on
+    forge: ForgeKind,
 }
 
 /// Metadata for a single review. Stored as dotfile next to user-facing review file
@@ -35,21 +56,144 @@ struct ReviewMetadata {
     submitted: Option<u64>,
     /// The commit hash of the PR at the time the review was started
     commit_id: Option<String>,
+    /// Full PR diff, if `original` is anything other than exactly that
+    pr_diff: Option<String>,
+    /// Emails of the patch series, if the review was read from an mbox
+    #[serde(default)]
+    series: Option<Series>,
+    /// Whether the review is split into commits, as with `--per-commit`. Reviews
+    /// read from an mbox always are.
+    #[serde(default)]
+    per_commit: bool,
+    /// Forge-assigned ID of the review, if it was submitted as pending and not
+    /// finalized yet
+    #[serde(default)]
+    pending: Option<String>,
+    /// What has been posted of the latest submission
+    #[serde(default)]
+    submission: Option<Submission>,
+}
+
+/// Something posted to the forge as part of a submission
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Posted {
+    /// What was posted: `review`, or `file`, `reply` or `commit` followed by
+    /// the index of the comment in the review file
+    pub item: String,
+    /// ID the forge assigned, if it said
+    pub id: Option<String>,
+}
+
+/// Progress of submitting a review, so a submission that failed partway can
+/// be resumed without posting anything twice
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Submission {
+    /// Contents of the review file being submitted
+    pub contents: String,
+    /// Everything posted so far, in order
+    pub posted: Vec<Posted>,
+    /// Whether everything was posted
+    pub complete: bool,
+}
+
+impl Submission {
+    /// Returns whether `item` was posted already
+    pub fn has_posted(&self, item: &str) -> bool {
+        self.posted.iter().any(|p| p.item == item)
+    }
+}
+
+/// A round of review on a PR, ie. one `prr get` and what became of it
+#[derive(Debug, PartialEq, Eq)]
+pub struct Round {
+    /// Round number, starting at 1
+    pub number: u64,
+    /// The commit hash of the PR the round reviewed
+    pub commit_id: Option<String>,
+    /// Time (seconds since epoch) the round was submitted
+    pub submitted: Option<u64>,
+    /// Path to the review file of the round
+    pub path: PathBuf,
+}
+
+/// All user-supplied comments on a review
+///
+/// This is what `prr parse --json` prints, so changes to the serialized form
+/// need to be reflected in the book.
+#[derive(Debug, Serialize)]
+pub struct ReviewComments {
+    /// Overall review action
+    pub review_action: ReviewAction,
+    /// Overall review comment. Empty if there is none
+    pub review_comment: String,
+    /// Comments attached to lines of the diff
+    pub inline_comments: Vec<InlineComment>,
+    /// Comments attached to whole files
+    pub file_comments: Vec<FileComment>,
+    /// Replies to existing review threads
+    pub thread_replies: Vec<ThreadReply>,
+    /// Comments attached to commit messages in a per-commit review
+    pub commit_comments: Vec<CommitComment>,
+    /// Whether to leave the review pending instead of submitting it
+    pub pending: bool,
+}
+
+/// A single comment in an existing review thread
+#[derive(Debug, Clone)]
+pub struct ThreadComment {
+    /// Login of the comment author
+    pub author: String,
+    /// Time the comment was created, as reported by the forge
+    pub created_at: String,
+    /// Comment text
+    pub body: String,
+}
+
+/// An existing review thread on a pull request
+#[derive(Debug, Clone)]
+pub struct ReviewThread {
+    /// Forge-assigned identifier of the thread
+    pub id: String,
+    /// File the thread is in
+    pub file: String,
+    /// Line the thread is attached to. See `LineLocation` for semantics
+    pub line: LineLocation,
+    /// First line of the thread if it spans several lines, ending at `line`
+    pub start_line: Option<LineLocation>,
+    /// Whether the thread has been marked resolved
+    pub resolved: bool,
+    /// Comments in the thread, oldest first
+    pub comments: Vec<ThreadComment>,
+}
+
+/// What to do about unsubmitted changes in an existing review file
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Unsubmitted {
+    /// Refuse to overwrite the review file
+    Keep,
+    /// Overwrite the review file, losing unsubmitted changes
+    Discard,
+    /// Carry unsubmitted comments over to the new review file
+    CarryForward,
 }
 
 /// Status of a review
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
 pub enum ReviewStatus {
     /// Newly downloaded review; no changes yet
     New,
     /// Unsubmitted changes have been made to review file
     Reviewed,
-    /// Review has been submitted. Any further changes to the review file are ignored
+    /// Review has been submitted as pending and is waiting to be finalized
+    Pending,
+    /// Review has been submitted. Any further changes to the review file are
+    /// ignored, unless posted with `prr amend`
     Submitted,
 }
 
 /// Represents a single line in a review file.
-enum LineType<'a> {
+pub enum LineType<'a> {
     /// Original text (but stored without the leading `> `)
     Quoted(&'a str),
     /// A snip (`[..]`)
@@ -58,11 +202,19 @@ enum LineType<'a> {
     Comment(&'a str),
 }
 
+impl ReviewStatus {
+    /// Whether the review file has changes that did not go live yet
+    pub fn is_unsubmitted(&self) -> bool {
+        matches!(self, Self::Reviewed | Self::Pending)
+    }
+}
+
 impl Display for ReviewStatus {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt_result {
         let text = match self {
             Self::New => "NEW",
             Self::Reviewed => "REVIEWED",
+            Self::Pending => "PENDING",
             Self::Submitted => "SUBMITTED",
         };
 
@@ -97,58 +249,202 @@ fn prefix_lines(s: &str, prefix: &str) -> String {
     ret
 }
 
+/// Renders a review thread as a `>>` quoted block
+fn render_thread(thread: &ReviewThread) -> String {
+    let mut text = format!("[thread {}]", thread.id);
+    if thread.resolved {
+        text += " (resolved)";
+    }
+    text += "\n";
+
+    for (idx, comment) in thread.comments.iter().enumerate() {
+        if idx != 0 {
+            text += "\n";
+        }
+        // Appending to heap allocated string cannot fail
+        writeln!(
+            text,
+            "On {}, @{} wrote:",
+            comment.created_at, comment.author
+        )
+        .expect("Failed to write to string");
+        text += comment.body.trim_end();
+        text += "\n";
+    }
+
+    prefix_lines(&text, ">>")
+}
+
+/// Quotes `original` and places each review thread directly under the line it is
+/// attached to. Threads that do not attach to any line in `original` are dropped.
+///
+/// User-supplied lines in `insertions` are placed as well.
+fn render_review_file(original: &str, threads: &[ReviewThread], insertions: &Insertions) -> String {
+    let mut contents = String::with_capacity(original.len());
+    for line in &insertions.top {
+        writeln!(contents, "{line}").expect("Failed to write to string");
+    }
+
+    let write_lines = |contents: &mut String, lines: Option<&Vec<String>>| {
+        for line in lines.into_iter().flatten() {
+            writeln!(contents, "{line}").expect("Failed to write to string");
+        }
+    };
+
+    for (idx, (line, location)) in original.lines().zip(diff_locations(original)).enumerate() {
+        // Same quoting as `prefix_lines()`, but one line at a time
+        if line.is_empty() {
+            contents += "> \n";
+        } else {
+            writeln!(contents, "> {line}").expect("Failed to write to string");
+        }
+
+        // Insertions are keyed by the number of quoted lines before them
+        let pos = idx + 1;
+        write_lines(&mut contents, insertions.comments.get(&pos));
+
+        if let Some((file, loc)) = location {
+            for thread in threads {
+                if thread.file == file && thread.line == loc {
+                    contents += &render_thread(thread);
+                    write_lines(
+                        &mut contents,
+                        insertions.replies.get(&(pos, thread.id.clone())),
+                    );
+                }
+            }
+        }
+
+        write_lines(&mut contents, insertions.span_starts.get(&pos));
+    }
+
+    contents
+}
+
+/// Loads and parses the metadata file at `path`
+fn read_metadata(path: &Path) -> Result<ReviewMetadata> {
+    let meta = fs::read_to_string(path).context("Failed to load metadata file")?;
+    serde_json::from_str::<ReviewMetadata>(&meta).context("Failed to parse metadata file")
+}
+
+/// Collects all non-metadata review files below `dir`
+fn find_review_files(dir: &Path, reviews: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+
+    for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+        // Archived rounds (`<pr_num>.<round>.prr`) belong to the review next to them
+        let archived = path
+            .file_stem()
+            .is_some_and(|s| s.to_string_lossy().contains('.'));
+        if path.is_dir() {
+            find_review_files(&path, reviews);
+        } else if path.is_file() && path.extension().is_some_and(|e| e == "prr") && !archived {
+            reviews.push(path);
+        }
+    }
+}
+
 /// Returns a list of all reviews in a workdir
+///
+/// Review files live at `<owner>/<repo>/<pr_num>.prr`. The owner may span
+/// several directories, eg. for GitLab subgroups. Reviews of PRs on forges
+/// other than GitHub are filed under a directory named after the forge.
 pub fn get_all_existing(workdir: &Path) -> Result<Vec<Review>> {
-    // This pipeline does the following:
-    //   * Iterate through all org directories in workdir
-    //   * For each org directory, iterate through all contained repo directories
-    //   * For each repo directory, enumerate all non-metadata review files
-    let reviews: Vec<PathBuf> = fs::read_dir(workdir)
-        .context("Failed to read workdir")?
-        .filter_map(|entry| entry.ok())
-        .map(|org| org.path())
-        .filter(|org| org.is_dir())
-        .filter_map(|org| fs::read_dir(org).ok())
-        .flatten()
-        .filter_map(|repo| repo.ok())
-        .map(|repo| repo.path())
-        .filter(|repo| repo.is_dir())
-        .filter_map(|repo| fs::read_dir(repo).ok())
-        .flatten()
-        .filter_map(|review| review.ok())
-        .map(|review| review.path())
-        .filter(|review| review.is_file())
-        .filter(|review| match review.extension() {
-            Some(e) => e == "prr",
-            None => false,
-        })
+    fs::read_dir(workdir).context("Failed to read workdir")?;
+    let mut reviews = Vec::new();
+    find_review_files(workdir, &mut reviews);
+
+    reviews
+        .iter()
+        .map(|review| review_at(workdir, review))
+        .collect()
+}
+
+/// Returns the review that `path`, a review file in `workdir`, belongs to
+pub fn review_at(workdir: &Path, path: &Path) -> Result<Review> {
+    let parts: Vec<_> = path
+        .strip_prefix(workdir)
+        .unwrap_or(path)
+        .iter()
+        .map(|p| p.to_string_lossy())
         .collect();
 
-    let mut ret = Vec::with_capacity(reviews.len());
-    for review in reviews {
-        let parts: Vec<_> = review
-            .iter()
-            .rev()
-            .take(3)
-            .map(|p| p.to_string_lossy())
-            .collect();
+    if parts.len() < 3 {
+        bail!("malformed review file path: {}", path.display());
+    }
 
-        if parts.len() != 3 {
-            bail!("malformed review file path: {}", review.display());
+    let (pr_file, rest) = parts.split_last().unwrap();
+    let (repo, mut owner) = rest.split_last().unwrap();
+    // GitHub owners are a single directory, so a longer owner starting with the
+    // directory of a forge is filed under that forge
+    let mut forge = ForgeKind::GitHub;
+    if owner.len() > 1 {
+        if let Some(kind) = [ForgeKind::GitLab, ForgeKind::Forgejo]
+            .into_iter()
+            .find(|k| forge_dir(*k) == Some(&*owner[0]))
+        {
+            forge = kind;
+            owner = &owner[1..];
         }
+    }
+    if forge == ForgeKind::GitHub && owner[0] == GERRIT_OWNER {
+        forge = ForgeKind::Gerrit;
+    }
+    let pr_num: u64 = pr_file
+        .strip_suffix(".prr")
+        .unwrap_or(pr_file)
+        .parse()
+        .with_context(|| format!("Failed to parse PR num: {}", path.display()))?;
+
+    Ok(Review::new_existing(
+        workdir,
+        &owner.join("/"),
+        repo,
+        pr_num,
+        forge,
+    ))
+}
 
-        let pr_num: u64 = parts[0]
-            .strip_suffix(".prr")
-            .unwrap_or(&parts[0])
-            .parse()
-            .with_context(|| format!("Failed to parse PR num: {}", review.display()))?;
+/// Returns the directory of the workdir the reviews of PRs on `forge` are
+/// filed under, if they are not filed directly under their owner
+///
+/// GitHub reviews are not, for backwards compatibility, and Gerrit's owners
+/// already start with `GERRIT_OWNER`.
+fn forge_dir(forge: ForgeKind) -> Option<&'static str> {
+    match forge {
+        ForgeKind::GitLab => Some("gitlab"),
+        ForgeKind::Forgejo => Some("forgejo"),
+        ForgeKind::GitHub | ForgeKind::Gerrit => None,
+    }
+}
 
-        // Note the vec has components reversed
-        let r = Review::new_existing(workdir, &parts[2], &parts[1], pr_num);
-        ret.push(r);
+/// Finds where the quoted lines of `contents` stop matching `original`
+///
+/// Returns the index of the offending line of `contents` along with what is
+/// wrong with it. Trailing whitespace is ignored, as editors tend to strip it.
+fn find_corruption(contents: &str, original: &str) -> Option<(usize, String)> {
+    let mut expected = original.lines().map(str::trim_end);
+    let mut last = 0;
+    for (idx, line) in contents.lines().enumerate() {
+        let quoted = match line.strip_prefix("> ") {
+            Some(stripped) => stripped.trim_end(),
+            None if line == ">" => "",
+            None => continue,
+        };
+        last = idx;
+        match expected.next() {
+            Some(e) if e == quoted => {}
+            Some(e) => return Some((idx, format!("found '{quoted}' expected '{e}'"))),
+            None => return Some((idx, "found trailing lines".to_string())),
+        }
     }
 
-    Ok(ret)
+    expected
+        .next()
+        .map(|_| (last, "found truncated lines".to_string()))
 }
 
 /// Recursive helper for `resolve_snips()`.
@@ -156,35 +452,87 @@ pub fn get_all_existing(workdir: &Path) -> Result<Vec<Review>> {
 /// This function will return Some(lines), where lines is a Vec of resolved
 /// lines. There should not be any trailing newlines in `lines`.
 ///
+/// Quoted lines must match the original text exactly. See `resolve_quotes()`
+/// for the details.
+fn resolve_snips_recurse<'a>(pattern: &[LineType<'a>], text: &[&'a str]) -> Option<Vec<String>> {
+    let resolved = trace_snips(pattern, text)?;
+    Some(resolved.into_iter().map(|(_, line)| line).collect())
+}
+
+/// Like `resolve_snips_recurse()`, but pairs each resolved line with the index
+/// of the `pattern` line it came from. Lines a snip stands in for come from the snip.
+fn trace_snips<'a>(pattern: &[LineType<'a>], text: &[&'a str]) -> Option<Vec<(usize, String)>> {
+    let quoted = |pattern: &[LineType], line: &str| match pattern.first() {
+        Some(LineType::Quoted(q)) if *q == line => Some(1),
+        _ => None,
+    };
+    resolve_quotes_memo(pattern, text, &quoted, &mut HashSet::new())
+}
+
+/// Matches the lines of `pattern` against the original text, replacing snips
+/// with the lines they stand in for
+///
+/// `quoted` decides whether the quoted lines at the start of a pattern match a
+/// line of original text. It returns how many pattern lines make up the text
+/// line, or `None` if they do not match. Matched lines are returned as the
+/// quoted original text.
+///
 /// The problem of resolving snips transposes pretty cleanly to the classic
 /// glob matching algorithm. We implement the glob matching fairly naively
 /// using recursion b/c it's cleaner to recurse when we want to eventually
-/// return a value.
+/// return a value. Suffixes that failed to match are remembered so a pattern
+/// with many snips that does not match fails in polynomial time.
 ///
 /// This would be in contrast to rsc's glob algorithm [0] where it's more
 /// efficient and has less pathological corner cases. We choose to trade off
 /// performance for simplicity here.
 ///
 /// [0]: https://research.swtch.com/glob
-fn resolve_snips_recurse<'a>(pattern: &[LineType<'a>], text: &[&'a str]) -> Option<Vec<String>> {
+pub fn resolve_quotes<'a>(
+    pattern: &[LineType<'a>],
+    text: &[&'a str],
+    quoted: &dyn Fn(&[LineType<'a>], &str) -> Option<usize>,
+) -> Option<Vec<String>> {
+    let resolved = resolve_quotes_memo(pattern, text, quoted, &mut HashSet::new())?;
+    Some(resolved.into_iter().map(|(_, line)| line).collect())
+}
+
+/// Memoizing helper for `resolve_quotes()`
+///
+/// `failed` holds the lengths of the `pattern` and `text` suffixes that are
+/// known not to match. Each resolved line is paired with the index of the
+/// `pattern` line it came from.
+fn resolve_quotes_memo<'a>(
+    pattern: &[LineType<'a>],
+    text: &[&'a str],
+    quoted: &dyn Fn(&[LineType<'a>], &str) -> Option<usize>,
+    failed: &mut HashSet<(usize, usize)>,
+) -> Option<Vec<(usize, String)>> {
+    let key = (pattern.len(), text.len());
+    if failed.contains(&key) {
+        return None;
+    }
+
     let mut resolved = Vec::new();
     let mut pattern_idx = 0;
     let mut text_idx = 0;
     while pattern_idx < pattern.len() || text_idx < text.len() {
         if pattern_idx < pattern.len() {
             match pattern[pattern_idx] {
-                LineType::Quoted(line) => {
-                    if text_idx < text.len() && text[text_idx] == line {
-                        resolved.push(format!("> {line}"));
-                        pattern_idx += 1;
-                        text_idx += 1;
-                        continue;
+                LineType::Quoted(_) => {
+                    if text_idx < text.len() {
+                        if let Some(n) = quoted(&pattern[pattern_idx..], text[text_idx]) {
+                            resolved.push((pattern_idx, format!("> {}", text[text_idx])));
+                            pattern_idx += n;
+                            text_idx += 1;
+                            continue;
+                        }
                     }
                 }
                 // Comments are semantically irrelevant to snip resolution. But we still
                 // need to account for them in returned output.
                 LineType::Comment(line) => {
-                    resolved.push(line.to_string());
+                    resolved.push((pattern_idx, line.to_string()));
                     pattern_idx += 1;
                     continue;
                 }
@@ -197,13 +545,18 @@ fn resolve_snips_recurse<'a>(pattern: &[LineType<'a>], text: &[&'a str]) -> Opti
                     for cand_text_idx in text_idx..=text.len() {
                         let cand_pattern = &pattern[pattern_idx + 1..];
                         let cand_text = &text[cand_text_idx..];
-                        if let Some(mut r) = resolve_snips_recurse(cand_pattern, cand_text) {
-                            let skipped: Vec<String> = text[text_idx..cand_text_idx]
+                        if let Some(r) =
+                            resolve_quotes_memo(cand_pattern, cand_text, quoted, failed)
+                        {
+                            let skipped = text[text_idx..cand_text_idx]
                                 .iter()
-                                .map(|&line| format!("> {line}"))
-                                .collect();
-                            resolved.extend_from_slice(&skipped);
-                            resolved.append(&mut r);
+                                .map(|&line| (pattern_idx, format!("> {line}")));
+                            resolved.extend(skipped);
+                            // `r` is indexed from the line after the snip
+                            resolved.extend(
+                                r.into_iter()
+                                    .map(|(idx, line)| (pattern_idx + 1 + idx, line)),
+                            );
                             return Some(resolved);
                         }
                     }
@@ -214,6 +567,7 @@ fn resolve_snips_recurse<'a>(pattern: &[LineType<'a>], text: &[&'a str]) -> Opti
         // If we reach here, we either have some `pattern` or `text` still left to
         // process. Meaning one ran out before the other. Which implies a resolution
         // failure.
+        failed.insert(key);
         return None;
     }
 
@@ -221,12 +575,324 @@ fn resolve_snips_recurse<'a>(pattern: &[LineType<'a>], text: &[&'a str]) -> Opti
     Some(resolved)
 }
 
+/// User-supplied lines to place into a newly rendered review file
+///
+/// Except for `top`, lines are keyed by the number of quoted lines before them.
+#[derive(Default, Debug)]
+struct Insertions {
+    /// Lines at the very top of the review file
+    top: Vec<String>,
+    /// Comments, placed before any review threads
+    comments: HashMap<usize, Vec<String>>,
+    /// Replies, placed after the review thread with the given id
+    replies: HashMap<(usize, String), Vec<String>>,
+    /// Whitespace starting a span, placed after any review threads
+    span_starts: HashMap<usize, Vec<String>>,
+    /// Number of comments that could not be placed and were moved to `top`
+    orphaned: usize,
+}
+
+/// An unsubmitted comment lifted out of an existing review file
+struct Draft<'a> {
+    /// `diff --git` line of the file the comment is in, if any
+    header: Option<&'a str>,
+    /// Whether the comment is a file-level comment
+    file_level: bool,
+    /// Quoted lines the comment is anchored to, as well as the comment itself.
+    /// For a spanned comment, this includes the whitespace starting the span.
+    pattern: Vec<LineType<'a>>,
+    /// Review thread the comment is a reply to, if any
+    thread: Option<String>,
+}
+
+impl Draft<'_> {
+    /// Renders the draft for the orphaned comments section
+    fn orphan_lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        let file = self
+            .header
+            .and_then(|h| h.rsplit_once(" b/"))
+            .map(|(_, f)| f);
+        let quoted = self.pattern.iter().rev().find_map(|l| match l {
+            LineType::Quoted(q) => Some(q),
+            _ => None,
+        });
+        match (file, quoted) {
+            (Some(f), Some(q)) if !self.file_level => lines.push(format!("On `{q}` in {f}:")),
+            (Some(f), _) => lines.push(format!("On {f}:")),
+            _ => {}
+        }
+
+        let mut comment: Vec<&str> = self
+            .pattern
+            .iter()
+            .rev()
+            .map_while(|l| match l {
+                LineType::Comment(c) => Some(*c),
+                _ => None,
+            })
+            .skip_while(|c| c.trim().is_empty())
+            .collect();
+        comment.reverse();
+        let start = comment
+            .iter()
+            .position(|c| !c.trim().is_empty())
+            .unwrap_or(comment.len());
+        lines.extend(comment[start..].iter().map(|c| c.to_string()));
+
+        lines
+    }
+}
+
+/// Classifies a line of a snip-resolved review file. Unlike `LineType::from()`,
+/// a bare `>` (ie. quoted empty line with trailing whitespace stripped) is quoted.
+fn classify_resolved(line: &str) -> LineType<'_> {
+    if line == ">" {
+        LineType::Quoted("")
+    } else {
+        LineType::from(line)
+    }
+}
+
+/// Lifts all unsubmitted comments out of snip-resolved review file contents
+fn extract_drafts(contents: &str) -> Vec<Draft<'_>> {
+    let lines: Vec<&str> = contents.lines().collect();
+    let mut drafts = Vec::new();
+    let mut header: Option<&str> = None;
+    let mut file_level = false;
+    // Quoted lines since the last file or hunk start
+    let mut context: Vec<&str> = Vec::new();
+    let mut thread: Option<String> = None;
+
+    let is_user_line =
+        |l: &str| !is_thread_line(l) && matches!(classify_resolved(l), LineType::Comment(_));
+    // Whether `l` is a quoted line inside a hunk
+    let is_diff_line = |l: &str, in_hunk: bool| match classify_resolved(l) {
+        LineType::Quoted(q) => in_hunk && !q.starts_with("diff --git ") && !q.starts_with("@@ "),
+        _ => false,
+    };
+
+    let mut idx = 0;
+    while idx < lines.len() {
+        let line = lines[idx];
+        if is_thread_line(line) {
+            if let Some(t) = parse_thread_start(line) {
+                thread = Some(t);
+            }
+            idx += 1;
+            continue;
+        }
+
+        if let LineType::Quoted(q) = classify_resolved(line) {
+            thread = None;
+            if q.starts_with("diff --git ") {
+                header = Some(q);
+                file_level = true;
+                context.clear();
+            } else if q.starts_with("@@ ") {
+                file_level = false;
+                context.clear();
+            } else if !file_level {
+                context.push(q);
+            }
+            idx += 1;
+            continue;
+        }
+
+        // Gather up the run of user-supplied lines
+        let run_end = (idx..lines.len())
+            .find(|&i| !is_user_line(lines[i]))
+            .unwrap_or(lines.len());
+        let run = &lines[idx..run_end];
+        let whitespace = run.iter().all(|l| l.trim().is_empty());
+        let anchor_start = context.len().saturating_sub(CARRY_FORWARD_CONTEXT);
+        let mut pattern: Vec<LineType> = if file_level {
+            Vec::new()
+        } else {
+            context[anchor_start..]
+                .iter()
+                .map(|q| LineType::Quoted(q))
+                .collect()
+        };
+        pattern.extend(run.iter().map(|l| LineType::Comment(l)));
+
+        let in_hunk = header.is_some() && !file_level;
+        let next_is_diff_line = lines
+            .get(run_end)
+            .map(|l| is_diff_line(l, in_hunk))
+            .unwrap_or(false);
+
+        if whitespace && next_is_diff_line {
+            // Start of a span. Include the spanned lines and the terminating comment.
+            let span_end = (run_end..lines.len())
+                .find(|&i| !is_diff_line(lines[i], in_hunk))
+                .unwrap_or(lines.len());
+            let comment_end = (span_end..lines.len())
+                .find(|&i| !is_user_line(lines[i]))
+                .unwrap_or(lines.len());
+
+            for l in &lines[run_end..span_end] {
+                if let LineType::Quoted(q) = classify_resolved(l) {
+                    pattern.push(LineType::Quoted(q));
+                    context.push(q);
+                }
+            }
+            pattern.extend(
+                lines[span_end..comment_end]
+                    .iter()
+                    .map(|l| LineType::Comment(l)),
+            );
+
+            // Unterminated spans are not comments
+            if comment_end > span_end {
+                drafts.push(Draft {
+                    header,
+                    file_level,
+                    pattern,
+                    thread: None,
+                });
+            }
+            thread = None;
+            idx = comment_end;
+            continue;
+        }
+
+        if !whitespace {
+            drafts.push(Draft {
+                header,
+                file_level,
+                pattern,
+                thread: thread.take(),
+            });
+        }
+        idx = run_end;
+    }
+
+    drafts
+}
+
+/// Finds where a draft belongs in `text` (unquoted original lines)
+///
+/// Returns the user-supplied lines of the draft keyed by the number of quoted
+/// lines before them, or `None` if the draft could not be placed.
+fn place_draft<'a>(draft: &Draft<'a>, text: &[&'a str]) -> Option<Vec<(usize, String)>> {
+    // We find the draft by resolving snips around it. This guarantees the
+    // comment ends up in the same position relative to the quoted lines as before.
+    let mut pattern = vec![LineType::Snip];
+    if let Some(h) = draft.header {
+        pattern.push(LineType::Quoted(h));
+        if !draft.file_level {
+            pattern.push(LineType::Snip);
+        }
+    }
+    pattern.extend(draft.pattern.iter().map(|l| match l {
+        LineType::Quoted(q) => LineType::Quoted(q.trim_end()),
+        LineType::Snip => LineType::Snip,
+        LineType::Comment(c) => LineType::Comment(c),
+    }));
+    pattern.push(LineType::Snip);
+
+    let resolved = resolve_snips_recurse(&pattern, text)?;
+
+    // Snips will happily skip over file boundaries. Comments must stay in the
+    // file they were in, though.
+    let mut placed = Vec::new();
+    let mut pos = 0;
+    let mut crossed = false;
+    for line in resolved {
+        if let Some(q) = line.strip_prefix("> ") {
+            if placed.is_empty() && q.starts_with("diff --git ") {
+                crossed = Some(q) != draft.header;
+            }
+            pos += 1;
+        } else {
+            placed.push((pos, line));
+        }
+    }
+
+    if crossed {
+        return None;
+    }
+
+    Some(placed)
+}
+
+/// Computes where each unsubmitted comment in `old` (snip-resolved review file
+/// contents) belongs in a review file for `original` with `threads`
+fn carry_forward(old: &str, original: &str, threads: &[ReviewThread]) -> Insertions {
+    let text: Vec<&str> = original.lines().map(str::trim_end).collect();
+    let locations = diff_locations(original);
+    let mut insertions = Insertions::default();
+    let mut orphans = Vec::new();
+
+    for draft in extract_drafts(old) {
+        let placed = match place_draft(&draft, &text) {
+            Some(p) => p,
+            None => {
+                orphans.push(draft);
+                continue;
+            }
+        };
+
+        // The last quoted line before the comment decides where replies go
+        let comment_pos = placed.last().map(|(pos, _)| *pos).unwrap_or(0);
+        if let Some(thread) = &draft.thread {
+            let location = comment_pos
+                .checked_sub(1)
+                .and_then(|idx| locations.get(idx).cloned().flatten());
+            let attached = threads
+                .iter()
+                .any(|t| &t.id == thread && Some((t.file.clone(), t.line.clone())) == location);
+            if !attached {
+                orphans.push(draft);
+                continue;
+            }
+        }
+
+        for (pos, line) in placed {
+            let lines = if pos == 0 {
+                &mut insertions.top
+            } else if pos != comment_pos {
+                insertions.span_starts.entry(pos).or_default()
+            } else if let Some(thread) = &draft.thread {
+                insertions.replies.entry((pos, thread.clone())).or_default()
+            } else {
+                insertions.comments.entry(pos).or_default()
+            };
+            lines.push(line);
+        }
+    }
+
+    if !orphans.is_empty() {
+        let mut top = vec![
+            "----- Orphaned comments: could not be placed in the new diff -----".to_string(),
+            String::new(),
+        ];
+        for orphan in &orphans {
+            top.extend(orphan.orphan_lines());
+            top.push(String::new());
+        }
+        top.push("----- End of orphaned comments -----".to_string());
+        top.push(String::new());
+        top.append(&mut insertions.top);
+        insertions.top = top;
+        insertions.orphaned = orphans.len();
+    }
+
+    insertions
+}
+
 impl Review {
     /// Creates a new `Review`
     ///
     /// `review_file` is the path where the user-facing review file should
     /// be created. Additional metadata files (dotfiles) may be created in the same
     /// directory.
+    ///
+    /// `threads` are existing review threads that get quoted under the lines
+    /// they are attached to. `unsubmitted` decides what happens to unsubmitted
+    /// changes in an existing review file.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         workdir: &Path,
         diff: String,
@@ -234,14 +900,17 @@ impl Review {
         repo: &str,
         pr_description: Option<String>,
         pr_num: u64,
+        forge: ForgeKind,
         commit_id: String,
-        force: bool,
+        threads: &[ReviewThread],
+        unsubmitted: Unsubmitted,
     ) -> Result<Review> {
         let review = Review {
             workdir: workdir.to_owned(),
             owner: owner.to_owned(),
             repo: repo.to_owned(),
             pr_num,
+            forge,
         };
 
         // First create directories leading up to review file if necessary
@@ -252,13 +921,35 @@ impl Review {
         fs::create_dir_all(review_dir).context("Failed to create workdir directories")?;
 
         // Check if there are unsubmitted changes
-        if !force && review.has_metadata() && review.status()? == ReviewStatus::Reviewed {
+        let mut old_contents = None;
+        // The pending review on the forge could not be finalized anymore
+        if review.has_metadata() && review.status()? == ReviewStatus::Pending {
             bail!(
-                "You have unsubmitted changes to the requested review. \
-                Either submit the existing changes, delete the existing review file, \
-                or re-run this command with --force."
+                "{} is pending. Finalize it with `prr submit --finalize` first.",
+                review.handle()
             );
         }
+        if review.has_metadata() && review.status()?.is_unsubmitted() {
+            match unsubmitted {
+                Unsubmitted::Keep => bail!(
+                    "You have unsubmitted changes to the requested review. \
+                    Either submit the existing changes, delete the existing review file, \
+                    re-run this command with --refresh to keep your comments, \
+                    or re-run this command with --force."
+                ),
+                Unsubmitted::Discard => (),
+                Unsubmitted::CarryForward => {
+                    let raw =
+                        fs::read_to_string(&review_path).context("Failed to read review file")?;
+                    old_contents = Some(review.resolve_snips(&raw)?);
+                }
+            }
+        }
+
+        // Keep the previous round around if it went anywhere
+        if review.has_metadata() && review.metadata()?.submitted.is_some() {
+            review.archive()?;
+        }
 
         // Now create review file
         let mut review_file = OpenOptions::new()
@@ -268,13 +959,30 @@ impl Review {
             .open(&review_path)
             .context("Failed to create review file")?;
 
-        let mut description = pr_description.unwrap_or_else(String::default);
+        let mut description = pr_description.unwrap_or_default();
         if !description.is_empty() {
             description += "\n";
         }
 
+        let pr_diff = if description.is_empty() {
+            None
+        } else {
+            Some(diff.clone())
+        };
         let original_contents = description + &diff;
-        let prefixed_contents = prefix_lines(&original_contents, ">");
+        let insertions = match &old_contents {
+            Some(old) => carry_forward(old, &original_contents, threads),
+            None => Insertions::default(),
+        };
+        if insertions.orphaned != 0 {
+            eprintln!(
+                "Warning: {} comment(s) could not be placed in the new diff. \
+                They were moved to the top of the review file.",
+                insertions.orphaned
+            );
+        }
+
+        let prefixed_contents = render_review_file(&original_contents, threads, &insertions);
         review_file
             .write_all(prefixed_contents.as_bytes())
             .context("Failed to write review file")?;
@@ -284,18 +992,13 @@ impl Review {
             original: original_contents,
             submitted: None,
             commit_id: Some(commit_id),
+            pr_diff,
+            per_commit: false,
+            series: None,
+            pending: None,
+            submission: None,
         };
-        let json = serde_json::to_string(&metadata)?;
-        let metadata_path = review.metadata_path();
-        let mut metadata_file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(metadata_path)
-            .context("Failed to create metadata file")?;
-        metadata_file
-            .write_all(json.as_bytes())
-            .context("Failed to write metadata file")?;
+        review.write_metadata(&metadata)?;
 
         Ok(review)
     }
@@ -304,28 +1007,42 @@ impl Review {
     ///
     /// Note we do not check that anything actually exists on disk because that is
     /// inherently racy. We'll handle ENOENT errors when we actually use any files.
-    pub fn new_existing(workdir: &Path, owner: &str, repo: &str, pr_num: u64) -> Review {
+    pub fn new_existing(
+        workdir: &Path,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        forge: ForgeKind,
+    ) -> Review {
         Review {
             workdir: workdir.to_owned(),
             owner: owner.to_owned(),
             repo: repo.to_owned(),
             pr_num,
+            forge,
         }
     }
 
     /// Parse the user-supplied comments on a review
-    ///
-    /// Returns (overall review action, overall review comment, inline comments, file comments)
-    pub fn comments(&self) -> Result<(ReviewAction, String, Vec<InlineComment>, Vec<FileComment>)> {
+    pub fn comments(&self) -> Result<ReviewComments> {
         let raw = fs::read_to_string(self.path()).context("Failed to read review file")?;
-        let contents = self.resolve_snips(&raw)?;
+        self.parse_comments(&raw)
+    }
+
+    /// Parse the user-supplied comments in `raw`, the contents of the review
+    /// file at some point, eg. when it was submitted
+    pub fn parse_comments(&self, raw: &str) -> Result<ReviewComments> {
+        let contents = self.resolve_snips(raw)?;
         self.validate_review_file(&contents)?;
 
-        let mut parser = ReviewParser::new();
+        let mut parser = ReviewParser::new(self.is_per_commit()?);
         let mut review_action = ReviewAction::Comment;
         let mut review_comment = String::new();
         let mut inline_comments = Vec::new();
         let mut file_comments = Vec::new();
+        let mut thread_replies = Vec::new();
+        let mut commit_comments = Vec::new();
+        let mut pending = false;
         for (idx, line) in contents.lines().enumerate() {
             let res = parser
                 .parse_line(line)
@@ -341,55 +1058,197 @@ impl Review {
                 }
                 Some(Comment::Inline(c)) => inline_comments.push(c),
                 Some(Comment::ReviewAction(a)) => review_action = a,
+                Some(Comment::Pending) => pending = true,
                 Some(Comment::File(fc)) => file_comments.push(fc),
+                Some(Comment::Reply(r)) => thread_replies.push(r),
+                Some(Comment::Commit(c)) => commit_comments.push(c),
                 None => {}
             }
         }
 
-        match parser.finish() {
+        match parser.finish()? {
             Some(Comment::Inline(c)) => inline_comments.push(c),
+            Some(Comment::Reply(r)) => thread_replies.push(r),
+            Some(Comment::Commit(c)) => commit_comments.push(c),
             // Original diff must have been short to begin with
             Some(Comment::Review(_)) => bail!("Unexpected review comment at parser finish"),
-            Some(Comment::ReviewAction(_)) => bail!("Unexpected review action at parser finish"),
+            Some(Comment::ReviewAction(_)) | Some(Comment::Pending) => {
+                bail!("Unexpected review action at parser finish")
+            }
             Some(Comment::File(_)) => bail!("Unexpected file-level comment at parser finish"),
             None => {}
         };
 
-        Ok((
+        Ok(ReviewComments {
             review_action,
             review_comment,
             inline_comments,
             file_comments,
-        ))
+            thread_replies,
+            commit_comments,
+            pending,
+        })
+    }
+
+    /// Checks `raw`, the contents of the review file as it is being edited,
+    /// for the first error `parse_comments()` would fail on
+    ///
+    /// Unlike `parse_comments()`, the error points at a line of `raw` rather
+    /// than of the snip-resolved contents.
+    pub fn lint(&self, raw: &str) -> Result<Option<LintError>> {
+        let original = self.original()?;
+        let last = raw.lines().count().saturating_sub(1);
+        let error = |line, message| Ok(Some(LintError { line, message }));
+        let resolved = match self.trace_snips(raw) {
+            Ok(r) => r,
+            Err(e) => {
+                let snip = raw
+                    .lines()
+                    .position(|l| matches!(LineType::from(l), LineType::Snip));
+                return error(snip.unwrap_or(0), format!("{e:#}"));
+            }
+        };
+
+        let contents: String = resolved.iter().map(|(_, l)| format!("{l}\n")).collect();
+        if let Some((idx, err)) = find_corruption(&contents, &original) {
+            return error(resolved[idx].0, format!("Quoted text was changed: {err}"));
+        }
+
+        let mut parser = ReviewParser::new(self.is_per_commit()?);
+        for (idx, line) in &resolved {
+            if let Err(e) = parser.parse_line(line) {
+                return error(*idx, format!("{e:#}"));
+            }
+        }
+        if let Err(e) = parser.finish() {
+            return error(last, format!("{e:#}"));
+        }
+
+        Ok(None)
+    }
+
+    /// Returns the file and line that each line of `raw`, the contents of the
+    /// review file as it is being edited, quotes
+    ///
+    /// Lines that do not quote a line of the diff, eg. comments and snips, map
+    /// to `None`. See `diff_locations()`.
+    pub fn locate(&self, raw: &str) -> Result<Vec<Option<(String, LineLocation)>>> {
+        let mut locations = diff_locations(&self.original()?).into_iter();
+        let lines: Vec<&str> = raw.lines().collect();
+        let mut located = vec![None; lines.len()];
+        for (idx, line) in self.trace_snips(raw)? {
+            if !line.starts_with("> ") && line != ">" {
+                continue;
+            }
+            let location = locations.next().flatten();
+            // Snips quote many lines, so there is no single place to point to
+            if !matches!(LineType::from(lines[idx]), LineType::Snip) {
+                located[idx] = location;
+            }
+        }
+
+        Ok(located)
     }
 
     /// Update the review file's submission time
     pub fn mark_submitted(&self) -> Result<()> {
-        let metadata_path = self.metadata_path();
         let mut metadata = self.metadata()?;
 
         let submission_time = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .expect("Time went backwards");
         metadata.submitted = Some(submission_time.as_secs());
+        metadata.pending = None;
 
-        let json = serde_json::to_string(&metadata)?;
-        let mut metadata_file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(metadata_path)
-            .context("Failed to create metadata file")?;
-        metadata_file
-            .write_all(json.as_bytes())
-            .context("Failed to write metadata file")?;
+        self.write_metadata(&metadata)
+    }
 
-        Ok(())
+    /// Records the ID of the pending review the review file was submitted as
+    pub fn set_pending(&self, id: String) -> Result<()> {
+        let mut metadata = self.metadata()?;
+        metadata.pending = Some(id);
+
+        self.write_metadata(&metadata)
+    }
+
+    /// Returns the ID of the pending review, if the review file was submitted
+    /// as pending and not finalized yet
+    pub fn pending(&self) -> Result<Option<String>> {
+        Ok(self.metadata()?.pending)
+    }
+
+    /// Returns the progress of the latest submission, if the review was submitted
+    pub fn submission(&self) -> Result<Option<Submission>> {
+        Ok(self.metadata()?.submission)
+    }
+
+    /// Records the progress of a submission
+    pub fn set_submission(&self, submission: &Submission) -> Result<()> {
+        let mut metadata = self.metadata()?;
+        metadata.submission = Some(submission.clone());
+
+        self.write_metadata(&metadata)
+    }
+
+    /// Returns the raw contents of the review file
+    pub fn contents(&self) -> Result<String> {
+        fs::read_to_string(self.path()).context("Failed to read review file")
+    }
+
+    /// Records the full PR diff for a review file that only contains part of it
+    pub fn set_pr_diff(&self, pr_diff: String) -> Result<()> {
+        let mut metadata = self.metadata()?;
+        metadata.pr_diff = Some(pr_diff);
+
+        self.write_metadata(&metadata)
+    }
+
+    /// Returns the forge the PR is hosted on
+    pub fn forge(&self) -> ForgeKind {
+        self.forge
+    }
+
+    /// Records the patch series an mbox review was read from
+    pub fn set_series(&self, series: Series) -> Result<()> {
+        let mut metadata = self.metadata()?;
+        metadata.series = Some(series);
+
+        self.write_metadata(&metadata)
+    }
+
+    /// Returns the patch series the review was read from, if it was read from an mbox
+    pub fn series(&self) -> Result<Option<Series>> {
+        Ok(self.metadata()?.series)
+    }
+
+    /// Records that the review is split into commits
+    pub fn set_per_commit(&self) -> Result<()> {
+        let mut metadata = self.metadata()?;
+        metadata.per_commit = true;
+
+        self.write_metadata(&metadata)
+    }
+
+    /// Returns whether the review is split into commits
+    pub fn is_per_commit(&self) -> Result<bool> {
+        let metadata = self.metadata()?;
+
+        Ok(metadata.per_commit || metadata.series.is_some())
     }
 
     /// Replaces all snips (`[...]`s) from `contents` with original, quoted text.
     /// Returns resolved contents as new string.
     fn resolve_snips(&self, contents: &str) -> Result<String> {
+        Ok(self
+            .trace_snips(contents)?
+            .iter()
+            .map(|(_, line)| format!("{line}\n"))
+            .collect())
+    }
+
+    /// Like `resolve_snips()`, but returns the resolved lines paired with the
+    /// index of the line of `contents` each came from
+    fn trace_snips(&self, contents: &str) -> Result<Vec<(usize, String)>> {
         // First, classify contents into line types. This is henceforth
         // known as the "pattern" we want to resolve against original text.
         let pattern: Vec<LineType> = contents.lines().map(LineType::from).collect();
@@ -400,7 +1259,7 @@ impl Review {
         // if they corrupted a quoted line. If we naively (and more efficiently) always
         // try to resolve snips, they might get the less informative error below.
         if !pattern.iter().any(|line| matches!(line, LineType::Snip)) {
-            return Ok(contents.to_string());
+            return Ok(contents.lines().map(str::to_string).enumerate().collect());
         }
 
         // Next, store original text as lines. It's easier to index into this way.
@@ -408,53 +1267,18 @@ impl Review {
         let original = self.metadata()?.original;
         let text: Vec<&str> = original.lines().collect();
 
-        Ok(resolve_snips_recurse(&pattern, &text)
-            .ok_or_else(|| anyhow!("Failed to resolve snips. Did you corrupt quoted text?"))?
-            .iter()
-            .map(|line| format!("{line}\n"))
-            .collect())
+        trace_snips(&pattern, &text)
+            .ok_or_else(|| anyhow!("Failed to resolve snips. Did you corrupt quoted text?"))
     }
 
     /// Validates whether the user corrupted the quoted contents
     fn validate_review_file(&self, contents: &str) -> Result<()> {
-        let mut reconstructed = String::with_capacity(contents.len());
-        for line in contents.lines() {
-            if let Some(stripped) = line.strip_prefix("> ") {
-                reconstructed += stripped.trim_end();
-                reconstructed += "\n";
-            }
-
-            if line == ">" {
-                reconstructed += "\n";
-            }
-        }
-
         let metadata = self.metadata()?;
-        let original: String = metadata
-            .original
-            .lines()
-            .map(|line| line.trim_end().to_owned() + "\n")
-            .collect();
-
-        if reconstructed != original {
-            // Be helpful and provide exact line number of mismatch.
-            //
-            // This loop on zip() will work as long as there isn't any truncation or trailing junk
-            // in the original text. To handle this case, there's the final bail!()
-            for (idx, (l, r)) in reconstructed.lines().zip(original.lines()).enumerate() {
-                if l != r {
-                    // Get number of user generated lines up until the mismatch
-                    let user_lines = contents
-                        .lines()
-                        .take(idx)
-                        .filter(|l| !l.starts_with('>'))
-                        .count();
-                    let err = format!("Line {}, found '{l}' expected '{r}'", idx + 1 + user_lines);
-                    bail!("Detected corruption in quoted part of review file: {err}");
-                }
-            }
-
-            bail!("Detected corruption in quoted part of review file: found trailing or truncated lines");
+        if let Some((idx, err)) = find_corruption(contents, &metadata.original) {
+            bail!(
+                "Detected corruption in quoted part of review file: Line {}, {err}",
+                idx + 1
+            );
         }
 
         Ok(())
@@ -462,16 +1286,28 @@ impl Review {
 
     /// Returns whether or not there exists review comments
     fn reviewed(&self) -> Result<bool> {
-        let (_, review_comment, comments, file_comments) = self
+        let comments = self
             .comments()
             .with_context(|| anyhow!("Failed to parse comments for {}", self.path().display()))?;
 
-        Ok(!review_comment.is_empty() || !comments.is_empty() || !file_comments.is_empty())
+        Ok(!comments.review_comment.is_empty()
+            || !comments.inline_comments.is_empty()
+            || !comments.file_comments.is_empty()
+            || !comments.thread_replies.is_empty()
+            || !comments.commit_comments.is_empty())
+    }
+
+    /// Returns the original text quoted by the review file
+    pub fn original(&self) -> Result<String> {
+        Ok(self.metadata()?.original)
     }
 
     /// Returns path to user-facing review file
     pub fn path(&self) -> PathBuf {
         let mut p = self.workdir.clone();
+        if let Some(dir) = forge_dir(self.forge) {
+            p.push(dir);
+        }
         p.push(&self.owner);
         p.push(&self.repo);
         p.push(format!("{}.prr", self.pr_num));
@@ -481,12 +1317,27 @@ impl Review {
 
     /// Loads and returns the parsed contents of the metadata file for the review file
     fn metadata(&self) -> Result<ReviewMetadata> {
-        let meta =
-            fs::read_to_string(self.metadata_path()).context("Failed to load metadata file")?;
-        serde_json::from_str::<ReviewMetadata>(&meta).context("Failed to parse metadata file")
+        read_metadata(&self.metadata_path())
     }
 
-    fn has_metadata(&self) -> bool {
+    /// Overwrites the metadata file for the review file
+    fn write_metadata(&self, metadata: &ReviewMetadata) -> Result<()> {
+        let json = serde_json::to_string(metadata)?;
+        let mut metadata_file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(self.metadata_path())
+            .context("Failed to create metadata file")?;
+        metadata_file
+            .write_all(json.as_bytes())
+            .context("Failed to write metadata file")?;
+
+        Ok(())
+    }
+
+    /// Returns whether the review exists on disk
+    pub fn has_metadata(&self) -> bool {
         fs::metadata(self.metadata_path()).is_ok()
     }
 
@@ -497,19 +1348,116 @@ impl Review {
         metadata_path
     }
 
+    /// Returns path to the review file of archived round `round`
+    fn round_path(&self, round: u64) -> PathBuf {
+        let mut p = self.path();
+        p.set_file_name(format!("{}.{}.prr", self.pr_num, round));
+
+        p
+    }
+
+    /// Returns path to the metadata file of archived round `round`
+    fn round_metadata_path(&self, round: u64) -> PathBuf {
+        let mut p = self.path();
+        p.set_file_name(format!(".{}.{}", self.pr_num, round));
+
+        p
+    }
+
+    /// Returns the number of archived rounds
+    fn archived_rounds(&self) -> u64 {
+        (1..)
+            .take_while(|r| self.round_metadata_path(*r).exists())
+            .count() as u64
+    }
+
+    /// Moves the review file and its metadata aside as the latest archived round
+    fn archive(&self) -> Result<()> {
+        let round = self.archived_rounds() + 1;
+        fs::rename(self.path(), self.round_path(round)).context("Failed to archive review file")?;
+        fs::rename(self.metadata_path(), self.round_metadata_path(round))
+            .context("Failed to archive metadata file")
+    }
+
+    /// Returns all rounds of review, oldest first. The last one is the review
+    /// file itself.
+    pub fn rounds(&self) -> Result<Vec<Round>> {
+        let archived = self.archived_rounds();
+        let mut rounds = Vec::new();
+        for number in 1..=archived {
+            let metadata = read_metadata(&self.round_metadata_path(number))?;
+            rounds.push(Round {
+                number,
+                commit_id: metadata.commit_id,
+                submitted: metadata.submitted,
+                path: self.round_path(number),
+            });
+        }
+        if self.has_metadata() {
+            let metadata = self.metadata()?;
+            rounds.push(Round {
+                number: archived + 1,
+                commit_id: metadata.commit_id,
+                submitted: metadata.submitted,
+                path: self.path(),
+            });
+        }
+
+        Ok(rounds)
+    }
+
     /// Returns the commit_id associated with the review
     pub fn commit_id(&self) -> Result<Option<String>> {
         Ok(self.metadata()?.commit_id.clone())
     }
 
     /// Returns the original review diff
+    ///
+    /// This is the full PR diff, even if the review file contains something else
     pub fn diff(&self) -> Result<String> {
-        Ok(self.metadata()?.original.clone())
+        let metadata = self.metadata()?;
+        Ok(metadata.pr_diff.unwrap_or(metadata.original))
+    }
+
+    /// Returns the full PR diff as of the last submitted review
+    pub fn submitted_diff(&self) -> Result<String> {
+        if !self.has_metadata() {
+            bail!("No existing review for {}", self.handle());
+        }
+
+        // Rounds downloaded since the last submission are archived once submitted,
+        // so the last submitted round is either the current one or archived
+        let archived = (1..=self.archived_rounds())
+            .rev()
+            .map(|round| read_metadata(&self.round_metadata_path(round)));
+        for metadata in std::iter::once(self.metadata()).chain(archived) {
+            let metadata = metadata?;
+            if metadata.submitted.is_some() {
+                return Ok(metadata.pr_diff.unwrap_or(metadata.original));
+            }
+        }
+
+        bail!("Review for {} has not been submitted yet", self.handle());
     }
 
     /// Returns a handle (eg "owner/repo/pr_num") to this review
     pub fn handle(&self) -> String {
-        format!("{}/{}/{}", self.owner, self.repo, self.pr_num)
+        match self.forge {
+            ForgeKind::GitHub | ForgeKind::Forgejo => {
+                format!("{}/{}/{}", self.owner, self.repo, self.pr_num)
+            }
+            ForgeKind::GitLab => format!("{}/{}/!{}", self.owner, self.repo, self.pr_num),
+            ForgeKind::Gerrit => format!(
+                "gerrit:{}~{}",
+                gerrit_project(&self.owner, &self.repo),
+                self.pr_num
+            ),
+        }
+    }
+
+    /// Returns the owner, repository and number of the PR under review
+    pub fn pr(&self) -> (&str, &str, u64) {
+        (&self.owner, &self.repo, self.pr_num)
     }
 
     /// Gets the status of a review
@@ -518,6 +1466,8 @@ impl Review {
         let reviewed = self.reviewed()?;
         let status = if metadata.submitted.is_some() {
             ReviewStatus::Submitted
+        } else if metadata.pending.is_some() {
+            ReviewStatus::Pending
         } else if reviewed {
             ReviewStatus::Reviewed
         } else {
@@ -527,15 +1477,58 @@ impl Review {
         Ok(status)
     }
 
+    /// Replaces the review file with `contents`, eg. a review imported from
+    /// elsewhere
+    pub fn import(&self, contents: &str, force: bool) -> Result<()> {
+        match self.status()? {
+            ReviewStatus::Submitted => bail!(
+                "{} has already been submitted. Run `prr get` to start a new review first.",
+                self.handle()
+            ),
+            ReviewStatus::Reviewed | ReviewStatus::Pending if !force => bail!(
+                "You have unsubmitted changes to the requested review. \
+                Re-run this command with --force to overwrite them."
+            ),
+            _ => (),
+        }
+
+        fs::write(self.path(), contents).context("Failed to write review file")
+    }
+
+    /// Adds `comments` to the review file, under the lines they are on
+    ///
+    /// Snips in the review file are expanded.
+    pub fn compose(&self, comments: &[ComposedComment]) -> Result<()> {
+        if self.status()? == ReviewStatus::Submitted {
+            bail!(
+                "{} has already been submitted. Run `prr get` to start a new review first.",
+                self.handle()
+            );
+        }
+
+        let raw = fs::read_to_string(self.path()).context("Failed to read review file")?;
+        let contents = self.resolve_snips(&raw)?;
+        self.validate_review_file(&contents)?;
+        let composed = insert_comments(&contents, comments, self.is_per_commit()?)?;
+
+        fs::write(self.path(), composed).context("Failed to write review file")
+    }
+
     /// Remove review from filesystem
     pub fn remove(self, force: bool) -> Result<()> {
-        if !force && self.status()? == ReviewStatus::Reviewed {
+        if !force && self.status()?.is_unsubmitted() {
             bail!(
                 "You have unsubmitted changes to the requested review. \
                 Re-run this command with --force to ignore this check."
             );
         }
 
+        for round in 1..=self.archived_rounds() {
+            fs::remove_file(self.round_path(round))
+                .context("Failed to remove archived review file")?;
+            fs::remove_file(self.round_metadata_path(round))
+                .context("Failed to remove archived metadata file")?;
+        }
         fs::remove_file(self.path()).context("Failed to remove review file")?;
         fs::remove_file(self.metadata_path()).context("Failed to remove metadata file")?;
 
@@ -574,7 +1567,7 @@ mod tests {
             .write_all(metadata.as_bytes())
             .expect("Failed to write metadata file");
 
-        let r = Review::new_existing(dir.path(), "some_owner", "some_repo", 3);
+        let r = Review::new_existing(dir.path(), "some_owner", "some_repo", 3, ForgeKind::GitHub);
 
         (r, dir)
     }
@@ -602,12 +1595,12 @@ mod tests {
         match r.status().expect("Failed to get review status") {
             ReviewStatus::New => (),
             ReviewStatus::Reviewed => panic!("Unexpected Reviewed state"),
+            ReviewStatus::Pending => panic!("Unexpected Pending state"),
             ReviewStatus::Submitted => panic!("Unpexected Submitted state"),
         };
 
         // Do a "review"
         let mut file = OpenOptions::new()
-            .write(true)
             .append(true)
             .open(r.path())
             .expect("Failed to open review file");
@@ -615,9 +1608,15 @@ mod tests {
             .expect("Failed to write review comment");
         assert_eq!(r.status().unwrap(), ReviewStatus::Reviewed);
 
+        // Submit it as pending
+        r.set_pending("1".to_string())
+            .expect("Failed to submit pending review");
+        assert_eq!(r.status().unwrap(), ReviewStatus::Pending);
+
         // "Submit" the review
         r.mark_submitted().expect("Failed to submit review");
         assert_eq!(r.status().unwrap(), ReviewStatus::Submitted);
+        assert_eq!(r.pending().unwrap(), None);
     }
 
     #[test]
@@ -640,6 +1639,44 @@ mod tests {
             .expect("Failed to validate review file with interleaving PR description");
     }
 
+    // The JSON printed by `prr parse --json` is documented in the book
+    #[test]
+    fn test_comments_json() {
+        let review = include_str!("../testdata/review/snip_comments/review");
+        let metadata = include_str!("../testdata/review/snip_comments/metadata");
+        let (r, _dir) = setup(review, metadata);
+
+        let comments = r.comments().expect("Failed to parse comments");
+        let json = serde_json::to_value(comments).unwrap();
+        let inline = |line, comment| {
+            serde_json::json!({
+                "file": "src/review.rs",
+                "line": { "side": "right", "line": line },
+                "start_line": null,
+                "comment": comment,
+                "commit": null,
+            })
+        };
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "review_action": "comment",
+                "review_comment": "",
+                "inline_comments": [
+                    inline(158, "woah!"),
+                    inline(356, "nice"),
+                    inline(357, "sheesh"),
+                ],
+                "file_comments": [
+                    { "file": "src/review.rs", "comment": "file comment!", "commit": null },
+                ],
+                "thread_replies": [],
+                "commit_comments": [],
+                "pending": false,
+            })
+        );
+    }
+
     // Tests creation of a new review
     #[test]
     fn test_new_review() {
@@ -654,8 +1691,10 @@ mod tests {
             "some_repo",
             Some("some_pr_desc".to_string()),
             3,
+            ForgeKind::GitHub,
             "111".to_string(),
-            false,
+            &[],
+            Unsubmitted::Discard,
         )
         .expect("Failed to create new non-existent review");
 
@@ -664,6 +1703,246 @@ mod tests {
         fs::metadata(review.metadata_path()).expect("Failed to read review file");
     }
 
+    // Tests existing review threads are quoted under the right lines
+    #[test]
+    fn test_new_review_with_threads() {
+        let workdir = tempdir().expect("Failed to create tempdir");
+        let diff = include_str!("../testdata/review/threads/diff");
+        let gold = include_str!("../testdata/review/threads/gold");
+        let comment = |author: &str, created_at: &str, body: &str| ThreadComment {
+            author: author.to_string(),
+            created_at: created_at.to_string(),
+            body: body.to_string(),
+        };
+        let threads = vec![
+            ReviewThread {
+                id: "PRRT_right".to_string(),
+                file: "ch1.txt".to_string(),
+                line: LineLocation::Right(3),
+                start_line: None,
+                resolved: true,
+                comments: vec![
+                    comment("bob", "2024-01-02T00:00:00Z", "Nit: too long"),
+                    comment("carol", "2024-01-03T00:00:00Z", "Agreed.\n"),
+                ],
+            },
+            ReviewThread {
+                id: "PRRT_left".to_string(),
+                file: "ch1.txt".to_string(),
+                line: LineLocation::Left(3),
+                start_line: None,
+                resolved: false,
+                comments: vec![comment("alice", "2024-01-01T00:00:00Z", "Why remove this?")],
+            },
+            // Outside of the diff, so should be dropped
+            ReviewThread {
+                id: "PRRT_gone".to_string(),
+                file: "ch1.txt".to_string(),
+                line: LineLocation::Right(100),
+                start_line: None,
+                resolved: false,
+                comments: vec![comment("dave", "2024-01-04T00:00:00Z", "Hmm")],
+            },
+        ];
+
+        let review = Review::new(
+            workdir.path(),
+            diff.to_string(),
+            "some_owner",
+            "some_repo",
+            None,
+            3,
+            ForgeKind::GitHub,
+            "111".to_string(),
+            &threads,
+            Unsubmitted::Discard,
+        )
+        .expect("Failed to create review with threads");
+
+        let contents = fs::read_to_string(review.path()).expect("Failed to read review file");
+        assert_eq_pretty!(contents, gold);
+        assert_eq!(review.status().unwrap(), ReviewStatus::New);
+    }
+
+    // Tests unsubmitted comments survive a refresh, and those that cannot be placed
+    // are moved to the top
+    #[test]
+    fn test_new_review_carry_forward() {
+        let workdir = tempdir().expect("Failed to create tempdir");
+        let old_diff = include_str!("../testdata/review/carry_forward/old_diff");
+        let new_diff = include_str!("../testdata/review/carry_forward/new_diff");
+        let review = include_str!("../testdata/review/carry_forward/review");
+        let gold = include_str!("../testdata/review/carry_forward/gold");
+        let threads = vec![ReviewThread {
+            id: "PRRT_right".to_string(),
+            file: "ch1.txt".to_string(),
+            line: LineLocation::Right(4),
+            start_line: None,
+            resolved: false,
+            comments: vec![ThreadComment {
+                author: "bob".to_string(),
+                created_at: "2024-01-02T00:00:00Z".to_string(),
+                body: "Nit: too long".to_string(),
+            }],
+        }];
+        let new = |diff: &str, unsubmitted| {
+            Review::new(
+                workdir.path(),
+                diff.to_string(),
+                "some_owner",
+                "some_repo",
+                None,
+                3,
+                ForgeKind::GitHub,
+                "111".to_string(),
+                &threads,
+                unsubmitted,
+            )
+        };
+
+        let r = new(old_diff, Unsubmitted::Discard).expect("Failed to create review");
+        fs::write(r.path(), review).expect("Failed to write review file");
+        assert_eq!(r.status().unwrap(), ReviewStatus::Reviewed);
+
+        assert!(new(new_diff, Unsubmitted::Keep).is_err());
+        let r = new(new_diff, Unsubmitted::CarryForward).expect("Failed to refresh review");
+        let contents = fs::read_to_string(r.path()).expect("Failed to read review file");
+        assert_eq_pretty!(contents, gold);
+        let comments = r.comments().expect("Failed to parse refreshed review");
+        assert_eq!(comments.file_comments.len(), 1);
+        assert_eq!(comments.inline_comments.len(), 2);
+        assert_eq!(comments.thread_replies.len(), 1);
+        assert_eq!(comments.review_action, ReviewAction::Approve);
+    }
+
+    #[test]
+    fn test_submitted_diff() {
+        let workdir = tempdir().expect("Failed to create tempdir");
+        let diff = include_str!("../testdata/review/threads/diff");
+        let review = Review::new(
+            workdir.path(),
+            diff.to_string(),
+            "some_owner",
+            "some_repo",
+            Some("some_pr_desc".to_string()),
+            3,
+            ForgeKind::GitHub,
+            "111".to_string(),
+            &[],
+            Unsubmitted::Discard,
+        )
+        .expect("Failed to create review");
+
+        assert!(review.submitted_diff().is_err());
+        review.mark_submitted().expect("Failed to submit review");
+        assert_eq_pretty!(review.submitted_diff().unwrap(), diff);
+
+        // If the review file only has part of the PR diff, the full diff is used
+        review
+            .set_pr_diff("full_diff".to_string())
+            .expect("Failed to set PR diff");
+        assert_eq!(review.submitted_diff().unwrap(), "full_diff");
+        assert_eq!(review.diff().unwrap(), "full_diff");
+
+        // Fetching twice without submitting in between still finds the last
+        // submitted round, which is archived by now
+        for commit_id in ["222", "333"] {
+            Review::new(
+                workdir.path(),
+                "newer_diff".to_string(),
+                "some_owner",
+                "some_repo",
+                None,
+                3,
+                ForgeKind::GitHub,
+                commit_id.to_string(),
+                &[],
+                Unsubmitted::Keep,
+            )
+            .expect("Failed to create review");
+            assert_eq!(review.submitted_diff().unwrap(), "full_diff");
+        }
+    }
+
+    #[test]
+    fn test_review_at() {
+        let workdir = tempdir().expect("Failed to create tempdir");
+        let review = |owner, pr_num, forge| {
+            let r = Review::new_existing(workdir.path(), owner, "project", pr_num, forge);
+            let found = review_at(workdir.path(), &r.path()).expect("Failed to find review");
+            assert_eq!(found.path(), r.path());
+            assert_eq!(found.forge(), forge);
+            assert_eq!(found.handle(), r.handle());
+
+            r.path()
+        };
+
+        // The same PR string on different forges must not share files
+        let github = review("group", 5, ForgeKind::GitHub);
+        let gitlab = review("group", 5, ForgeKind::GitLab);
+        let forgejo = review("group", 5, ForgeKind::Forgejo);
+        assert_ne!(github, gitlab);
+        assert_ne!(github, forgejo);
+        assert_ne!(gitlab, forgejo);
+
+        review("group/sub", 5, ForgeKind::GitLab);
+        review("gerrit", 5, ForgeKind::GitLab);
+        review("gerrit/platform", 5, ForgeKind::Gerrit);
+        // GitHub owners can be named after a forge
+        review("gitlab", 5, ForgeKind::GitHub);
+    }
+
+    #[test]
+    fn test_rounds() {
+        let workdir = tempdir().expect("Failed to create tempdir");
+        let new_review = |commit_id: &str| {
+            Review::new(
+                workdir.path(),
+                "some_review_contents".to_string(),
+                "some_owner",
+                "some_repo",
+                None,
+                3,
+                ForgeKind::GitHub,
+                commit_id.to_string(),
+                &[],
+                Unsubmitted::Discard,
+            )
+            .expect("Failed to create review")
+        };
+
+        // Unsubmitted rounds are not kept
+        new_review("111");
+        let review = new_review("222");
+        review.mark_submitted().expect("Failed to submit review");
+        let review = new_review("333");
+        review.mark_submitted().expect("Failed to submit review");
+        let review = new_review("444");
+
+        let rounds = review.rounds().unwrap();
+        let summary: Vec<_> = rounds
+            .iter()
+            .map(|r| (r.number, r.commit_id.as_deref(), r.submitted.is_some()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (1, Some("222"), true),
+                (2, Some("333"), true),
+                (3, Some("444"), false)
+            ]
+        );
+        assert!(rounds[0].path.ends_with("some_owner/some_repo/3.1.prr"));
+        assert!(rounds[0].path.exists());
+        assert_eq!(rounds[2].path, review.path());
+
+        // Archived rounds are not reviews of their own, and go with the review
+        assert_eq!(get_all_existing(workdir.path()).unwrap().len(), 1);
+        review.remove(true).expect("Failed to remove review");
+        assert!(!rounds[0].path.exists());
+        assert!(get_all_existing(workdir.path()).unwrap().is_empty());
+    }
+
     #[test]
     fn test_snip_single() {
         let review = include_str!("../testdata/review/snip_single/review");
@@ -694,6 +1973,68 @@ mod tests {
         assert_eq_pretty!(r.resolve_snips(review).unwrap(), gold);
     }
 
+    #[test]
+    fn test_lint() {
+        let review = include_str!("../testdata/review/snip_multiple/review");
+        let gold = include_str!("../testdata/review/snip_multiple/gold");
+        let metadata = include_str!("../testdata/review/snip_multiple/metadata");
+        let (r, _dir) = setup(review, metadata);
+        let lint = |raw: &str| r.lint(raw).unwrap().map(|e| (e.line, e.message));
+
+        assert_eq!(lint(review), None);
+        assert_eq!(lint(gold), None);
+        assert_eq!(
+            lint(&gold.replace("> +++ b/src/review.rs", "> +++ b/src/lint.rs")),
+            Some((
+                3,
+                "Quoted text was changed: found '+++ b/src/lint.rs' expected '+++ b/src/review.rs'"
+                    .to_string()
+            ))
+        );
+        assert_eq!(
+            lint(&format!("{gold}> trailing\n")),
+            Some((
+                gold.lines().count(),
+                "Quoted text was changed: found trailing lines".to_string()
+            ))
+        );
+        assert_eq!(
+            lint(&review.replace("> +++ b/src/review.rs", "> +++ b/src/lint.rs")),
+            Some((
+                0,
+                "Failed to resolve snips. Did you corrupt quoted text?".to_string()
+            ))
+        );
+        // Errors point at the review file as written, not as resolved. Here
+        // the hunk header the span runs into is behind a snip.
+        let mut spanned: Vec<&str> = review.lines().collect();
+        spanned.insert(9, "");
+        assert_eq!(
+            lint(&(spanned.join("\n") + "\n")),
+            Some((
+                12,
+                "Detected cross chunk span, file: src/review.rs".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_locate() {
+        let review = include_str!("../testdata/review/snip_multiple/review");
+        let metadata = include_str!("../testdata/review/snip_multiple/metadata");
+        let (r, _dir) = setup(review, metadata);
+        let located = r.locate(review).unwrap();
+
+        let right = |line| Some(("src/review.rs".to_string(), LineLocation::Right(line)));
+        assert_eq!(located.len(), review.lines().count());
+        // Snips and headers
+        assert_eq!(located[0], None);
+        assert_eq!(located[3], None);
+        assert_eq!(located[5], right(155));
+        assert_eq!(located[8], right(158));
+        assert_eq!(located[12], right(354));
+    }
+
     // Here we exhaustively check all possible single snips. It may be worth doing something
     // similar for multiple snips but it'll be a bit more complicated to implement.
     #[test]
@@ -0,0 +1,253 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use anyhow::{Context, Result};
+use serde_derive::Deserialize;
+
+use crate::compose::{ComposedComment, Side};
+use crate::parser::LineLocation;
+
+#[derive(Debug, Deserialize)]
+struct SarifLog {
+    runs: Vec<SarifRun>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SarifRun {
+    #[serde(default)]
+    results: Vec<SarifResult>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SarifResult {
+    rule_id: Option<String>,
+    /// One of `none`, `note`, `warning` or `error`. Defaults to `warning`
+    level: Option<String>,
+    message: SarifMessage,
+    #[serde(default)]
+    locations: Vec<SarifLocation>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SarifMessage {
+    text: Option<String>,
+    markdown: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SarifLocation {
+    physical_location: Option<SarifPhysicalLocation>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SarifPhysicalLocation {
+    artifact_location: SarifArtifactLocation,
+    region: Option<SarifRegion>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SarifArtifactLocation {
+    uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SarifRegion {
+    start_line: Option<u64>,
+    end_line: Option<u64>,
+}
+
+impl SarifResult {
+    /// Renders the result the way compilers print diagnostics, eg.
+    /// ``warning: unneeded `return` statement (clippy::needless_return)``
+    fn describe(&self) -> String {
+        let level = self.level.as_deref().unwrap_or("warning");
+        let message = self
+            .message
+            .markdown
+            .as_deref()
+            .or(self.message.text.as_deref())
+            .unwrap_or("")
+            .trim();
+        let mut text = format!("{level}: {message}");
+        if let Some(rule) = &self.rule_id {
+            // Appending to heap allocated string cannot fail
+            write!(text, " ({rule})").expect("Failed to write to string");
+        }
+
+        text
+    }
+}
+
+/// Static analysis results turned into review comments
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SarifComments {
+    pub comments: Vec<ComposedComment>,
+    /// Number of results that were not turned into comments
+    pub dropped: usize,
+}
+
+/// Finds the file of the diff that `uri` refers to
+///
+/// Tools report paths relative to wherever they ran, or as absolute `file://`
+/// URIs, so the longest file in the diff that `uri` ends with is picked.
+fn diff_file<'a>(uri: &str, files: &[&'a str]) -> Option<&'a str> {
+    let path = uri.strip_prefix("file://").unwrap_or(uri);
+    let path = path.strip_prefix("./").unwrap_or(path);
+    files
+        .iter()
+        .filter(|f| path == **f || path.ends_with(&format!("/{f}")))
+        .max_by_key(|f| f.len())
+        .copied()
+}
+
+/// Turns the results in `sarif`, a SARIF log, into comments on the lines in
+/// `locations`, as returned by `diff_locations()`
+///
+/// A result is attached to the first line of its region that is in the diff,
+/// on the new version of the file. Results that are not on any line in the
+/// diff are dropped, unless `outside` is set. Then they are listed in a
+/// file-level comment instead, as long as their file is part of the diff.
+pub fn sarif_comments(
+    sarif: &str,
+    locations: &[Option<(String, LineLocation)>],
+    outside: bool,
+) -> Result<SarifComments> {
+    let log: SarifLog = serde_json::from_str(sarif).context("Failed to parse SARIF log")?;
+    let mut files: Vec<&str> = locations
+        .iter()
+        .flatten()
+        .map(|(file, _)| file.as_str())
+        .collect();
+    files.dedup();
+    let in_diff = |file: &str, line: u64| {
+        locations
+            .iter()
+            .flatten()
+            .any(|(f, l)| f == file && *l == LineLocation::Right(line))
+    };
+
+    let mut sarif_comments = SarifComments::default();
+    // Results outside the diff, by file
+    let mut file_results: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+    for result in log.runs.iter().flat_map(|r| &r.results) {
+        // The first location is where the result is, and the rest are related
+        let location = result
+            .locations
+            .first()
+            .and_then(|l| l.physical_location.as_ref());
+        let Some(file) = location.and_then(|l| diff_file(&l.artifact_location.uri, &files)) else {
+            sarif_comments.dropped += 1;
+            continue;
+        };
+
+        let region = location.and_then(|l| l.region.as_ref());
+        let start = region.and_then(|r| r.start_line);
+        let end = region.and_then(|r| r.end_line).or(start);
+        let line = match (start, end) {
+            (Some(start), Some(end)) => (start..=end).find(|&l| in_diff(file, l)),
+            _ => None,
+        };
+
+        match (line, start) {
+            (Some(line), _) => sarif_comments.comments.push(ComposedComment {
+                file: file.to_string(),
+                line: Some(line),
+                side: Side::Right,
+                start_line: None,
+                body: result.describe(),
+            }),
+            (None, Some(start)) if outside => file_results
+                .entry(file)
+                .or_default()
+                .push(format!("- Line {start}: {}", result.describe())),
+            (None, None) if outside => file_results
+                .entry(file)
+                .or_default()
+                .push(format!("- {}", result.describe())),
+            _ => sarif_comments.dropped += 1,
+        }
+    }
+
+    for (file, results) in file_results {
+        sarif_comments.comments.push(ComposedComment {
+            file: file.to_string(),
+            line: None,
+            side: Side::Right,
+            start_line: None,
+            body: format!("Outside of the diff:\n\n{}", results.join("\n")),
+        });
+    }
+
+    Ok(sarif_comments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::diff_locations;
+    use pretty_assertions::assert_eq;
+
+    fn comment(line: Option<u64>, body: &str) -> ComposedComment {
+        ComposedComment {
+            file: "ch1.txt".to_string(),
+            line,
+            side: Side::Right,
+            start_line: None,
+            body: body.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_sarif_comments() {
+        let sarif = include_str!("../testdata/sarif/results.sarif");
+        let locations = diff_locations(include_str!("../testdata/review/threads/diff"));
+
+        assert_eq!(
+            sarif_comments(sarif, &locations, false).unwrap(),
+            SarifComments {
+                comments: vec![
+                    comment(Some(3), "warning: Line is too long (style::line_length)"),
+                    comment(Some(4), "error: Sentence does not end in a verb"),
+                ],
+                dropped: 3,
+            }
+        );
+        assert_eq!(
+            sarif_comments(sarif, &locations, true).unwrap(),
+            SarifComments {
+                comments: vec![
+                    comment(Some(3), "warning: Line is too long (style::line_length)"),
+                    comment(Some(4), "error: Sentence does not end in a verb"),
+                    comment(
+                        None,
+                        "Outside of the diff:\n\n\
+                         - Line 40: note: Chapter is too short (style::length)\n\
+                         - warning: File has no license header"
+                    ),
+                ],
+                // Not part of the diff at all
+                dropped: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_diff_file() {
+        let files = ["ch1.txt", "book/ch1.txt"];
+        assert_eq!(diff_file("ch1.txt", &files), Some("ch1.txt"));
+        assert_eq!(diff_file("./ch1.txt", &files), Some("ch1.txt"));
+        assert_eq!(
+            diff_file("file:///home/sun/book/ch1.txt", &files),
+            Some("book/ch1.txt")
+        );
+        assert_eq!(
+            diff_file("file:///home/sun/ch1.txt", &files),
+            Some("ch1.txt")
+        );
+        assert_eq!(diff_file("xch1.txt", &files), None);
+    }
+}
@@ -0,0 +1,232 @@
+use std::fmt::{Display, Formatter, Result as fmt_result};
+
+use anyhow::{bail, Result};
+
+use crate::parser::LineLocation;
+use crate::review::ReviewThread;
+
+/// Number of lines around a suggestion that must be unchanged for it to apply
+const CONTEXT: usize = 3;
+
+/// A change suggested in a review thread
+#[derive(Debug, PartialEq, Eq)]
+pub struct Suggestion {
+    /// File the suggestion is for
+    pub file: String,
+    /// First line replaced, on the right side of the diff
+    pub start: u64,
+    /// Last line replaced, on the right side of the diff
+    pub end: u64,
+    /// Login of whoever suggested the change
+    pub author: String,
+    /// Lines to replace `start..=end` with
+    pub lines: Vec<String>,
+}
+
+impl Display for Suggestion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt_result {
+        if self.start == self.end {
+            write!(f, "{}:{} by {}", self.file, self.start, self.author)
+        } else {
+            write!(
+                f,
+                "{}:{}-{} by {}",
+                self.file, self.start, self.end, self.author
+            )
+        }
+    }
+}
+
+impl Suggestion {
+    /// Applies the suggestion, which was made against `original`, to `current`,
+    /// a later version of the same file
+    ///
+    /// The replaced lines and the few lines around them must be unchanged in
+    /// `current`, though they may have moved.
+    pub fn apply(&self, original: &str, current: &str) -> Result<String> {
+        let original: Vec<&str> = original.split_inclusive('\n').collect();
+        let current: Vec<&str> = current.split_inclusive('\n').collect();
+        if self.start == 0 || self.end < self.start || self.end as usize > original.len() {
+            bail!("Lines are outside of the file");
+        }
+        let (start, end) = (self.start as usize - 1, self.end as usize);
+
+        let before = start.saturating_sub(CONTEXT);
+        let after = (end + CONTEXT).min(original.len());
+        let block = &original[before..after];
+        // Closest place the lines moved to
+        let Some(moved) = (0..=current.len().saturating_sub(block.len()))
+            .filter(|&i| current[i..].starts_with(block))
+            .min_by_key(|&i| i.abs_diff(before))
+        else {
+            bail!("Lines have changed since");
+        };
+
+        let mut applied = current[..moved + start - before].concat();
+        for line in &self.lines {
+            applied.push_str(line);
+            applied.push('\n');
+        }
+        applied.push_str(&current[moved + end - before..].concat());
+
+        Ok(applied)
+    }
+}
+
+/// Extracts the contents of the first ```` ```suggestion ```` block in a comment
+fn parse_suggestion(body: &str) -> Option<Vec<String>> {
+    let mut lines = body.lines();
+    let fence = lines.find_map(|l| {
+        let l = l.trim();
+        let fence = l.chars().take_while(|c| *c == '`').count();
+        let info = &l[fence..];
+        (fence >= 3 && info.trim() == "suggestion").then(|| "`".repeat(fence))
+    })?;
+
+    let mut suggestion = Vec::new();
+    for line in lines {
+        if line.trim() == fence {
+            return Some(suggestion);
+        }
+        suggestion.push(line.trim_end_matches('\r').to_string());
+    }
+
+    // Unterminated block, which the forge would not render as a suggestion either
+    None
+}
+
+/// Collects the suggested changes in unresolved review threads, oldest first
+/// within each thread
+pub fn find_suggestions(threads: &[ReviewThread]) -> Vec<Suggestion> {
+    let mut suggestions = Vec::new();
+    for thread in threads.iter().filter(|t| !t.resolved) {
+        // Suggestions replace lines of the new version of the file
+        let LineLocation::Right(end) = thread.line else {
+            continue;
+        };
+        let start = match thread.start_line {
+            Some(LineLocation::Right(start)) => start,
+            Some(LineLocation::Left(_)) => continue,
+            None => end,
+        };
+
+        for comment in &thread.comments {
+            if let Some(lines) = parse_suggestion(&comment.body) {
+                suggestions.push(Suggestion {
+                    file: thread.file.clone(),
+                    start,
+                    end,
+                    author: comment.author.clone(),
+                    lines,
+                });
+            }
+        }
+    }
+
+    suggestions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::review::ThreadComment;
+    use pretty_assertions::assert_eq;
+
+    fn thread(
+        line: LineLocation,
+        start_line: Option<LineLocation>,
+        resolved: bool,
+        bodies: &[&str],
+    ) -> ReviewThread {
+        ReviewThread {
+            id: "PRRT".to_string(),
+            file: "ch1.txt".to_string(),
+            line,
+            start_line,
+            resolved,
+            comments: bodies
+                .iter()
+                .map(|b| ThreadComment {
+                    author: "bob".to_string(),
+                    created_at: "2024-01-02T00:00:00Z".to_string(),
+                    body: b.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_find_suggestions() {
+        let threads = vec![
+            thread(
+                LineLocation::Right(3),
+                Some(LineLocation::Right(2)),
+                false,
+                &[
+                    "Shorter:\r\n```suggestion\r\nOne line\r\n```\r\n",
+                    "No suggestion here",
+                    "````suggestion\n```\nfenced\n```\n````",
+                ],
+            ),
+            thread(LineLocation::Right(5), None, false, &["```suggestion\n```"]),
+            // Skipped: resolved, on the left side and unterminated
+            thread(
+                LineLocation::Right(7),
+                None,
+                true,
+                &["```suggestion\nx\n```"],
+            ),
+            thread(
+                LineLocation::Left(7),
+                None,
+                false,
+                &["```suggestion\nx\n```"],
+            ),
+            thread(LineLocation::Right(9), None, false, &["```suggestion\nx"]),
+        ];
+
+        let suggestion = |start, end, lines: &[&str]| Suggestion {
+            file: "ch1.txt".to_string(),
+            start,
+            end,
+            author: "bob".to_string(),
+            lines: lines.iter().map(|l| l.to_string()).collect(),
+        };
+        assert_eq!(
+            find_suggestions(&threads),
+            vec![
+                suggestion(2, 3, &["One line"]),
+                suggestion(2, 3, &["```", "fenced", "```"]),
+                suggestion(5, 5, &[]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_apply() {
+        let suggestion = Suggestion {
+            file: "ch1.txt".to_string(),
+            start: 2,
+            end: 3,
+            author: "bob".to_string(),
+            lines: vec!["two and three".to_string()],
+        };
+        let original = "one\ntwo\nthree\nfour\nfive\nsix\nseven\n";
+
+        assert_eq!(
+            suggestion.apply(original, original).unwrap(),
+            "one\ntwo and three\nfour\nfive\nsix\nseven\n"
+        );
+        assert_eq!(
+            suggestion
+                .apply(original, &format!("zero\n{original}"))
+                .unwrap(),
+            "zero\none\ntwo and three\nfour\nfive\nsix\nseven\n"
+        );
+        // Context changed
+        assert!(suggestion
+            .apply(original, &original.replace("five", "5"))
+            .is_err());
+        assert!(suggestion.apply("one\ntwo\n", "one\ntwo\n").is_err());
+    }
+}
@@ -19,10 +19,12 @@ syn match prrHeader "^> diff --git .*"
 syn match prrIndex "^> index \w*\.\.\w*\( \w*\)\?"
 syn match prrChunkH "^> @@ .* @@"
 
+syn match prrThread "^>>.*"
+
 syn match prrTag "^@prr .*" contains=prrTagName,prrResult transparent
 
 syn match prrTagName contained "@prr" nextgroup=prrResult
-syn keyword prrResult contained approve reject comment
+syn keyword prrResult contained approve reject comment resolve
 
 " Define the default highlighting.
 " Only used when an item doesn't have highlighting yet
@@ -34,5 +36,6 @@ hi def link prrResult String
 hi def link prrHeader Include
 hi def link prrIndex Comment
 hi def link prrChunkH Function
+hi def link prrThread Comment
 
 let b:current_syntax = "prr"